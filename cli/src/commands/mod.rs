@@ -14,7 +14,7 @@ mod push;
 pub enum Command {
 	/// Log in to Replicate's Docker registry
 	Login {
-		/// Pass login token on stdin instead of opening a browser. You can find your Replicate login token at https://replicate.com/auth/token
+		/// Pass login token on stdin instead of opening a browser. You can find your Replicate login token at <https://replicate.com/auth/token>
 		#[clap(long)]
 		token_stdin: bool,
 		/// Registry host
@@ -59,7 +59,7 @@ pub async fn exec(command: Command, ctx: Context) {
 	match command {
 		Command::Debug => debug::handle(ctx),
 		Command::Build { tag } => build::handle(ctx, tag),
-		Command::Push { image } => push::handle(ctx, &image),
+		Command::Push { image } => push::handle(ctx, image.as_ref()),
 		Command::Login {
 			registry,
 			token_stdin,
@@ -69,5 +69,5 @@ pub async fn exec(command: Command, ctx: Context) {
 			output,
 			input,
 		} => predict::handle(ctx, image, input, output).await,
-	};
+	}
 }
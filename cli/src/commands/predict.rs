@@ -161,7 +161,7 @@ fn parse_inputs(inputs: Vec<String>, schema: &SchemaObject) -> HashMap<String, S
 				|| value.clone(),
 				|path_str| {
 					let bytes = fs::read(PathBuf::from(path_str))
-						.expect("Couldn't find {path_str} file (for {name})");
+						.unwrap_or_else(|_| panic!("Couldn't find {path_str} file (for {name})"));
 
 					let mime = tree_magic_mini::from_u8(bytes.as_slice());
 
@@ -1,14 +1,14 @@
 use crate::Context;
 
-pub fn handle(ctx: Context, image: &Option<String>) {
+pub fn handle(ctx: Context, image: Option<&String>) {
 	let builder = ctx.into_builder();
 
-	if builder.config.image.as_ref().or(image.as_ref()).is_none() {
+	if builder.config.image.as_ref().or(image).is_none() {
 		eprintln!("To push images, you must either set the 'image' option in the packages.metadata.cog of your Cargo.toml or pass an image name as an argument. For example, 'cargo cog push hotdog-detector'");
 		std::process::exit(1);
 	}
 
-	let image_name = builder.build(image.clone());
+	let image_name = builder.build(image.cloned());
 	builder.push(image);
 
 	println!("Image '{image_name}' pushed");
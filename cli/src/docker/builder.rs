@@ -174,10 +174,9 @@ impl Builder {
 		image_name
 	}
 
-	pub fn push(&self, image: &Option<String>) {
+	pub fn push(&self, image: Option<&String>) {
 		Docker::push(
 			image
-				.as_ref()
 				.or(self.config.image.as_ref())
 				.expect("Image name not specified"),
 		)
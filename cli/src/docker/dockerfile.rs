@@ -1,4 +1,4 @@
-use std::process::Command;
+use std::{fmt::Write, process::Command};
 
 #[derive(Debug)]
 pub struct Dockerfile(String);
@@ -9,8 +9,9 @@ impl Dockerfile {
 	}
 
 	pub fn run_multiple(mut self, command: &[&Command]) -> Self {
-		self.0.push_str(&format!(
-			"RUN {}\n",
+		writeln!(
+			self.0,
+			"RUN {}",
 			command
 				.iter()
 				.map(|cmd| format!(
@@ -23,19 +24,20 @@ impl Dockerfile {
 				))
 				.collect::<Vec<String>>()
 				.join(" && ")
-		));
+		)
+		.unwrap();
 
 		self
 	}
 
 	pub fn env(mut self, key: &str, value: &str) -> Self {
-		self.0.push_str(&format!("ENV {key}={value}\n"));
+		writeln!(self.0, "ENV {key}={value}").unwrap();
 
 		self
 	}
 
 	pub fn copy(mut self, src: &str, dest: &str) -> Self {
-		self.0.push_str(&format!("COPY {src} {dest}\n"));
+		writeln!(self.0, "COPY {src} {dest}").unwrap();
 
 		self
 	}
@@ -49,6 +51,7 @@ pub trait DockerfileExt {
 	fn handler(self, slot: &str, value: impl FnOnce() -> Option<Dockerfile>) -> Self;
 }
 
+#[allow(clippy::literal_string_with_formatting_args)]
 impl DockerfileExt for String {
 	fn for_bin(self, bin: &str) -> Self {
 		self.replace("{:bin_name}", bin)
@@ -298,7 +298,7 @@ impl Docker {
 		stdout
 			.trim()
 			.split(':')
-			.last()
+			.next_back()
 			.ok_or_else(|| Error::Parse("docker port".to_string()))?
 			.parse()
 			.map_err(|_| Error::Parse("docker port".to_string()))
@@ -1,8 +1,7 @@
 use anyhow::{bail, Context};
-use cog_core::http::{HTTPValidationError, Response};
+use cog_client::{Client, Error as ClientError, Health, Response};
 use indoc::formatdoc;
 use map_macro::hash_map;
-use reqwest::StatusCode;
 use schemars::schema::SchemaObject;
 use serde_json::{json, Value};
 use std::{
@@ -17,7 +16,7 @@ use crate::docker::Docker;
 #[derive(Debug)]
 pub struct Predictor {
 	image: String,
-	port: Option<u16>,
+	client: Option<Client>,
 	container_id: Option<String>,
 }
 
@@ -25,7 +24,7 @@ impl Predictor {
 	pub const fn new(image: String) -> Self {
 		Self {
 			image,
-			port: None,
+			client: None,
 			container_id: None,
 		}
 	}
@@ -61,34 +60,25 @@ impl Predictor {
 		.unwrap();
 
 		self.container_id = Some(container_id.clone());
-		self.port = Some(Docker::find_port(&container_id, 5000).unwrap());
+		let port = Docker::find_port(&container_id, 5000).unwrap();
+		self.client = Some(Client::new(
+			format!("http://localhost:{port}").parse().unwrap(),
+		));
 
+		// The log tail exits on its own once the container is stopped.
+		#[allow(clippy::zombie_processes)]
 		Docker::tail_logs(&container_id).unwrap();
 		self.wait_for_server().await.unwrap();
 	}
 
 	pub async fn predict(&self, inputs: HashMap<String, String>) -> anyhow::Result<Response> {
-		let port = self
-			.port
+		let client = self
+			.client
 			.as_ref()
 			.context("Trying to predict with non-running container.")?;
 
-		let client = reqwest::Client::new();
-
-		let res = client
-			.post(format!("http://localhost:{port}/predictions"))
-			.json(&json!({ "input": inputs }))
-			.send()
-			.await
-			.context("Failed to send request")?;
-
-		if matches!(res.status(), StatusCode::UNPROCESSABLE_ENTITY) {
-			let text = res.text().await?;
-			let errors = serde_json::from_str::<HTTPValidationError>(&text).context(
-                format!("/predictions call returned status 422, and the response body failed to decode: {text}")
-            )?;
-
-			bail!(formatdoc! {"
+		match client.predict(json!(inputs)).await {
+			Err(ClientError::Validation(errors)) => bail!(formatdoc! {"
                 The inputs you passed to cog predict could not be validated:
 
                 {}
@@ -100,16 +90,9 @@ impl Predictor {
                 If your input is a local file, you need to prefix the path with @ to tell Cog to read the file contents. For example:
 
                     cog predict -i path=@image.jpg
-            ", errors.detail.iter().map(|e| e.msg.clone()).collect::<Vec<_>>().join("\n")});
-		}
-
-		if !matches!(res.status(), StatusCode::OK) {
-			bail!("/predictions call returned status {}", res.status());
+            ", errors.detail.iter().map(|e| e.msg.clone()).collect::<Vec<_>>().join("\n")}),
+			result => result.context("/predictions call failed"),
 		}
-
-		let text = res.text().await?;
-		serde_json::from_str::<Response>(&text)
-			.context("Failed to decode prediction response: {text}")
 	}
 
 	pub fn stop(&self) -> anyhow::Result<()> {
@@ -129,7 +112,10 @@ impl Predictor {
 			.as_ref()
 			.context("Waiting for non-running container.")?;
 
-		let client = reqwest::Client::new();
+		let client = self
+			.client
+			.as_ref()
+			.context("Waiting for non-running container.")?;
 
 		loop {
 			if start.elapsed().as_secs() > 300 {
@@ -155,32 +141,19 @@ impl Predictor {
 				bail!("Container exited unexpectedly");
 			}
 
-			let res = client
-				.get(format!(
-					"http://localhost:{}/health-check",
-					self.port.unwrap()
-				))
-				.send()
-				.await
-				.and_then(reqwest::Response::error_for_status);
-
-			let Ok(res) = res else {
-				continue;
+			let health = match client.health().await {
+				Ok(health) => health,
+				Err(ClientError::Request(_) | ClientError::Status { .. }) => continue,
+				Err(error) => {
+					return Err(error).context("Container healthcheck returned invalid response")
+				},
 			};
 
-			let status = res
-				.json::<Value>()
-				.await
-				.ok()
-				.and_then(|v| v.get("status").cloned())
-				.and_then(|s| s.as_str().map(str::to_string))
-				.context("Container healthcheck returned invalid response")?;
-
-			match status.as_str() {
-				"STARTING" => continue,
-				"READY" => return Ok(()),
-				"SETUP_FAILED" => bail!("Model setup failed"),
-				_ => bail!("Container healthcheck returned unexpected status: {status}"),
+			match health.status {
+				Health::Starting => {},
+				Health::Ready => return Ok(()),
+				Health::SetupFailed => bail!("Model setup failed"),
+				status => bail!("Container healthcheck returned unexpected status: {status:?}"),
 			}
 		}
 	}
@@ -0,0 +1,540 @@
+#![warn(clippy::all, clippy::pedantic, clippy::nursery)]
+//! A typed async client for Cog prediction servers.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), cog_client::Error> {
+//! use cog_client::Client;
+//! use std::time::Duration;
+//!
+//! #[derive(serde::Serialize, serde::Deserialize)]
+//! struct Input {
+//!     text: String,
+//! }
+//!
+//! let client = Client::new("http://localhost:5000".parse()?);
+//! client.wait_until_ready(Duration::from_secs(300)).await?;
+//!
+//! let prediction = client
+//!     .predict::<_, String>(Input { text: "hello".to_string() })
+//!     .await?;
+//! println!("{:?}", prediction.output);
+//! # Ok(())
+//! # }
+//! ```
+
+use reqwest::{Method, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+use url::Url;
+
+pub use cog_core::{
+	http::{HTTPValidationError, Request, Response, Status, ValidationError, WebhookEvent},
+	HealthReport, HealthStatus, ModelError,
+};
+
+/// How long we wait between checks when polling the server.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("The input could not be validated: {}", describe_validation_errors(.0))]
+	Validation(HTTPValidationError),
+
+	#[error("The server is already running a prediction")]
+	Conflict,
+
+	#[error("The requested prediction does not exist")]
+	NotFound,
+
+	#[error("The model's setup failed")]
+	SetupFailed,
+
+	#[error("The model is defunct")]
+	Defunct,
+
+	#[error("Timed out waiting for the server")]
+	Timeout,
+
+	#[error("The server responded with {status}: {body}")]
+	Status { status: StatusCode, body: String },
+
+	#[error("Failed to decode the server's response: {0}")]
+	Decode(#[from] serde_json::Error),
+
+	#[error("Failed to send request: {0}")]
+	Request(#[from] reqwest::Error),
+
+	#[error("Invalid URL: {0}")]
+	Url(#[from] url::ParseError),
+}
+
+/// The state of the server, as reported by `/health-check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Health {
+	Unknown,
+	Starting,
+	Ready,
+	Busy,
+	SetupFailed,
+	Unhealthy,
+	Defunct,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthCheck {
+	pub status: Health,
+	/// Information about the model's setup (like its logs), if the server reports it.
+	pub setup: Option<Value>,
+	/// The model's own health check, if the server reports it.
+	pub model: Option<HealthReport>,
+}
+
+/// A client for a single Cog server.
+///
+/// Predictions are typed by their input (`Req`) and output (`Res`), which should match the model's schema.
+#[derive(Debug, Clone)]
+pub struct Client {
+	base_url: Url,
+	http: reqwest::Client,
+}
+
+impl Client {
+	/// A client for the server at `base_url`.
+	#[must_use]
+	pub fn new(base_url: Url) -> Self {
+		Self::with_http_client(base_url, reqwest::Client::new())
+	}
+
+	/// A client for the server at `base_url`, sending requests with the given `reqwest` client (to set default headers for authentication, or timeouts).
+	#[must_use]
+	pub fn with_http_client(mut base_url: Url, http: reqwest::Client) -> Self {
+		// Make sure paths are joined onto the base url, instead of replacing its last segment.
+		if !base_url.path().ends_with('/') {
+			base_url.set_path(&format!("{}/", base_url.path()));
+		}
+
+		Self { base_url, http }
+	}
+
+	/// Run a prediction with the given input, waiting for its result.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the input is invalid, the server is busy, or the request fails.
+	pub async fn predict<Req, Res>(&self, input: Req) -> Result<Response<Req, Res>, Error>
+	where
+		Req: Serialize + DeserializeOwned + Send + Sync,
+		Res: DeserializeOwned + Send,
+	{
+		self.create(&Request::new(input)).await
+	}
+
+	/// Create a prediction, waiting for its result.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the input is invalid, the server is busy, or the request fails.
+	pub async fn create<Req, Res>(
+		&self,
+		request: &Request<Req>,
+	) -> Result<Response<Req, Res>, Error>
+	where
+		Req: Serialize + DeserializeOwned + Send + Sync,
+		Res: DeserializeOwned + Send,
+	{
+		self.send_prediction(Method::POST, &["predictions"], request, false)
+			.await
+	}
+
+	/// Start a prediction without waiting for it to complete, returning its initial state.
+	///
+	/// Since it has no id, the result can only be received through the request's webhook. Use [`Self::put_async`] to be able to wait for it instead.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the input is invalid, the server is busy, or the request fails.
+	pub async fn create_async<Req, Res>(
+		&self,
+		request: &Request<Req>,
+	) -> Result<Response<Req, Res>, Error>
+	where
+		Req: Serialize + DeserializeOwned + Send + Sync,
+		Res: DeserializeOwned + Send,
+	{
+		self.send_prediction(Method::POST, &["predictions"], request, true)
+			.await
+	}
+
+	/// Create the prediction with the given id (or attach to it, if it's already running), waiting for its result.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the input is invalid, another prediction is running, or the request fails.
+	pub async fn put<Req, Res>(
+		&self,
+		id: &str,
+		request: &Request<Req>,
+	) -> Result<Response<Req, Res>, Error>
+	where
+		Req: Serialize + DeserializeOwned + Send + Sync,
+		Res: DeserializeOwned + Send,
+	{
+		self.send_prediction(Method::PUT, &["predictions", id], request, false)
+			.await
+	}
+
+	/// Start the prediction with the given id without waiting for it to complete, returning its current state.
+	///
+	/// While the prediction is running, sending the same request again returns it instead of starting a new one. Once it has completed, the server forgets about it, so the request would run it again: use [`Self::wait_for`] (or a webhook) to get its result instead.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the input is invalid, another prediction is running, or the request fails.
+	pub async fn put_async<Req, Res>(
+		&self,
+		id: &str,
+		request: &Request<Req>,
+	) -> Result<Response<Req, Res>, Error>
+	where
+		Req: Serialize + DeserializeOwned + Send + Sync,
+		Res: DeserializeOwned + Send,
+	{
+		self.send_prediction(Method::PUT, &["predictions", id], request, true)
+			.await
+	}
+
+	/// The current state of the running prediction with the given id.
+	///
+	/// # Errors
+	///
+	/// Returns [`Error::NotFound`] if no prediction with that id is running (servers don't keep finished predictions around), or an error if the request fails.
+	pub async fn get<Req, Res>(&self, id: &str) -> Result<Response<Req, Res>, Error>
+	where
+		Req: DeserializeOwned,
+		Res: DeserializeOwned,
+	{
+		self.send(Method::GET, &["predictions", id], None, true)
+			.await
+	}
+
+	/// Wait for the result of a prediction started with [`Self::put_async`], reconnecting if the connection drops before the prediction completes.
+	///
+	/// This only ever waits on the running prediction, and never starts it again.
+	///
+	/// # Errors
+	///
+	/// Returns [`Error::NotFound`] if the prediction isn't running (because it already completed, and the server forgot its result), or an error if it doesn't complete before the timeout or the request fails.
+	pub async fn wait_for<Req, Res>(
+		&self,
+		id: &str,
+		timeout: Duration,
+	) -> Result<Response<Req, Res>, Error>
+	where
+		Req: DeserializeOwned,
+		Res: DeserializeOwned,
+	{
+		let (path, deadline) = (["predictions", id], Instant::now() + timeout);
+
+		loop {
+			let remaining = deadline.saturating_duration_since(Instant::now());
+			let attempt = self.send(Method::GET, &path, None, false);
+			match tokio::time::timeout(remaining, attempt).await {
+				Err(_) => return Err(Error::Timeout),
+				// Waiting has no side effects, so it's safe to try again.
+				Ok(Err(Error::Request(error)))
+					if error.is_connect()
+						|| error.is_request()
+						|| error.is_body() || error.is_timeout() =>
+				{
+					tokio::time::sleep(POLL_INTERVAL.min(remaining)).await;
+				},
+				Ok(result) => return result,
+			}
+		}
+	}
+
+	/// Cancel the running prediction with the given id.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the prediction doesn't exist (or already completed), or the request fails.
+	pub async fn cancel(&self, id: &str) -> Result<(), Error> {
+		self.send::<Value>(Method::POST, &["predictions", id, "cancel"], None, false)
+			.await?;
+
+		Ok(())
+	}
+
+	/// The server's current health.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the request fails.
+	pub async fn health(&self) -> Result<HealthCheck, Error> {
+		self.send(Method::GET, &["health-check"], None, false).await
+	}
+
+	/// Wait for the model's setup to complete, retrying while the server isn't accepting connections yet.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the setup fails or doesn't complete before the timeout.
+	pub async fn wait_until_ready(&self, timeout: Duration) -> Result<(), Error> {
+		let start = Instant::now();
+
+		loop {
+			match self.health().await {
+				Ok(health) if matches!(health.status, Health::Ready | Health::Busy) => {
+					return Ok(())
+				},
+				Ok(health) if health.status == Health::SetupFailed => {
+					return Err(Error::SetupFailed)
+				},
+				Ok(health) if health.status == Health::Defunct => return Err(Error::Defunct),
+				Ok(_) => {},
+				Err(Error::Request(error)) if error.is_connect() => {},
+				Err(error) => return Err(error),
+			}
+
+			if start.elapsed() > timeout {
+				return Err(Error::Timeout);
+			}
+			tokio::time::sleep(POLL_INTERVAL).await;
+		}
+	}
+
+	/// The server's `OpenAPI` schema, describing the model's input and output.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the request fails.
+	pub async fn schema(&self) -> Result<Value, Error> {
+		self.send(Method::GET, &["openapi.json"], None, false).await
+	}
+
+	async fn send_prediction<Req, Res>(
+		&self,
+		method: Method,
+		path: &[&str],
+		request: &Request<Req>,
+		respond_async: bool,
+	) -> Result<Response<Req, Res>, Error>
+	where
+		Req: Serialize + DeserializeOwned + Send + Sync,
+		Res: DeserializeOwned + Send,
+	{
+		self.send(
+			method,
+			path,
+			Some(serde_json::to_value(request)?),
+			respond_async,
+		)
+		.await
+	}
+
+	async fn send<T: DeserializeOwned>(
+		&self,
+		method: Method,
+		path: &[&str],
+		body: Option<Value>,
+		respond_async: bool,
+	) -> Result<T, Error> {
+		let mut request = self.http.request(method, self.url(path)?);
+		if respond_async {
+			request = request.header("Prefer", "respond-async");
+		}
+		if let Some(body) = body {
+			request = request.json(&body);
+		}
+
+		let response = request.send().await?;
+		let status = response.status();
+		let body = response.text().await?;
+
+		match status {
+			StatusCode::OK | StatusCode::ACCEPTED => Ok(serde_json::from_str(&body)?),
+			StatusCode::CONFLICT => Err(Error::Conflict),
+			StatusCode::NOT_FOUND => Err(Error::NotFound),
+			StatusCode::UNPROCESSABLE_ENTITY => Err(serde_json::from_str(&body)
+				.map_or(Error::Status { status, body }, Error::Validation)),
+			_ => Err(Error::Status { status, body }),
+		}
+	}
+
+	/// The URL of the given path on the server, with each segment (like a prediction id) percent-encoded.
+	fn url(&self, path: &[&str]) -> Result<Url, Error> {
+		let mut url = self.base_url.clone();
+		url.path_segments_mut()
+			.map_err(|()| url::ParseError::RelativeUrlWithCannotBeABaseBase)?
+			.pop_if_empty()
+			.extend(path);
+
+		Ok(url)
+	}
+}
+
+fn describe_validation_errors(errors: &HTTPValidationError) -> String {
+	errors
+		.detail
+		.iter()
+		.map(|error| format!("{} ({})", error.msg, error.loc.join(".")))
+		.collect::<Vec<_>>()
+		.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use cog_rust::{Cog, ServerBuilder, SetupContext};
+	use serde_json::json;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+	struct Input {
+		text: String,
+	}
+
+	struct Echo;
+
+	impl Cog for Echo {
+		type Request = Input;
+		type Response = String;
+
+		async fn setup(_: SetupContext) -> anyhow::Result<Self> {
+			Ok(Self)
+		}
+
+		fn predict(&self, input: Self::Request) -> anyhow::Result<Self::Response> {
+			Ok(input.text)
+		}
+	}
+
+	/// How many times `Counted::predict` ran.
+	static PREDICTIONS: AtomicUsize = AtomicUsize::new(0);
+
+	struct Counted;
+
+	impl Cog for Counted {
+		type Request = Input;
+		type Response = String;
+
+		async fn setup(_: SetupContext) -> anyhow::Result<Self> {
+			Ok(Self)
+		}
+
+		fn predict(&self, input: Self::Request) -> anyhow::Result<Self::Response> {
+			PREDICTIONS.fetch_add(1, Ordering::SeqCst);
+			std::thread::sleep(Duration::from_millis(200));
+
+			Ok(input.text)
+		}
+	}
+
+	async fn serve<T: Cog + 'static>() -> Client {
+		let router = ServerBuilder::<T>::new().into_router().unwrap();
+		let server =
+			axum::Server::bind(&([127, 0, 0, 1], 0).into()).serve(router.into_make_service());
+		let client = Client::new(format!("http://{}", server.local_addr()).parse().unwrap());
+		tokio::spawn(server);
+
+		client
+			.wait_until_ready(Duration::from_secs(10))
+			.await
+			.unwrap();
+		client
+	}
+
+	#[tokio::test]
+	async fn predictions_are_typed() {
+		let client = serve::<Echo>().await;
+
+		let prediction = client
+			.predict::<_, String>(Input {
+				text: "hello".to_string(),
+			})
+			.await
+			.unwrap();
+		assert!(matches!(prediction.status, Status::Succeeded));
+		assert_eq!(prediction.output.as_deref(), Some("hello"));
+		assert_eq!(prediction.input.unwrap().text, "hello");
+
+		assert!(client.schema().await.unwrap()["paths"]["/predictions"].is_object());
+	}
+
+	#[tokio::test]
+	async fn errors_are_structured() {
+		let client = serve::<Echo>().await;
+
+		let Err(Error::Validation(errors)) =
+			client.predict::<_, String>(json!({ "text": 1 })).await
+		else {
+			panic!("expected a validation error");
+		};
+		assert_eq!(errors.detail[0].loc, ["body", "input", "text"]);
+
+		assert!(matches!(
+			client.cancel("unknown").await,
+			Err(Error::NotFound)
+		));
+	}
+
+	#[tokio::test]
+	async fn waiting_never_runs_predictions_again() {
+		let client = serve::<Counted>().await;
+		let request = Request::new(Input {
+			text: "hello".to_string(),
+		});
+
+		client
+			.put_async::<_, String>("abc", &request)
+			.await
+			.unwrap();
+		assert_eq!(
+			client
+				.get::<Input, String>("abc")
+				.await
+				.unwrap()
+				.id
+				.as_deref(),
+			Some("abc")
+		);
+		let prediction = client
+			.wait_for::<Input, String>("abc", Duration::from_secs(10))
+			.await
+			.unwrap();
+		assert_eq!(prediction.output.as_deref(), Some("hello"));
+
+		// Once the server forgets the prediction, waiting on it fails instead of running it again.
+		loop {
+			match client
+				.wait_for::<Input, String>("abc", Duration::from_secs(10))
+				.await
+			{
+				Ok(_) => tokio::task::yield_now().await,
+				Err(Error::NotFound) => break,
+				Err(error) => panic!("{error}"),
+			}
+		}
+		assert_eq!(PREDICTIONS.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn ids_are_encoded() {
+		let client = serve::<Echo>().await;
+
+		let result = client
+			.put_async::<_, String>(
+				"abc/../../health-check",
+				&Request::new(Input {
+					text: "hello".to_string(),
+				}),
+			)
+			.await;
+		assert!(matches!(
+			result,
+			Err(Error::Status { status, .. }) if status == StatusCode::UNPROCESSABLE_ENTITY
+		));
+	}
+}
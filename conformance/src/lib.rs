@@ -0,0 +1,555 @@
+#![warn(clippy::all, clippy::pedantic, clippy::nursery)]
+//! Checks that a prediction server follows Cog's HTTP protocol: sync and async predictions, idempotent `PUT`s, the `Prefer: respond-async` header, cancellation, error shapes, webhook ordering and `/health-check` transitions.
+//!
+//! The server under test must be running the conformance [`Model`] (or an equivalent one, with the same input schema), and be able to send webhooks to the suite.
+
+use anyhow::{ensure, Context, Result};
+use cog_core::http::{Response, Status};
+use cog_rust::testing::WebhookReceiver;
+use futures::{Stream, StreamExt};
+use reqwest::{Client, Method, StatusCode};
+use serde_json::{json, Value};
+use std::{
+	fmt::{self, Display},
+	future::Future,
+	net::SocketAddr,
+	pin::Pin,
+	time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use url::Url;
+use uuid::Uuid;
+
+pub use model::{Input, Model};
+
+mod model;
+
+/// How long we wait for the server to become ready, or for a prediction to complete.
+const TIMEOUT: Duration = Duration::from_mins(1);
+
+/// How long (in seconds) predictions we need to inspect while they're running take.
+const SLOW: f64 = 1.0;
+
+type Check<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// The outcome of running the suite.
+#[derive(Debug, Default)]
+pub struct Report {
+	pub results: Vec<(&'static str, Result<()>)>,
+}
+
+impl Report {
+	/// Whether every check passed.
+	#[must_use]
+	pub fn passed(&self) -> bool {
+		self.results.iter().all(|(_, result)| result.is_ok())
+	}
+}
+
+impl Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for (name, result) in &self.results {
+			match result {
+				Ok(()) => writeln!(f, "✓ {name}")?,
+				Err(error) => writeln!(f, "✗ {name}: {error:#}")?,
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// The conformance suite, targeting a single server.
+pub struct Suite {
+	base_url: Url,
+	client: Client,
+	webhook_url: Url,
+	/// Keeps listening for webhooks while the suite is alive.
+	_webhooks: WebhookReceiver,
+	events: Mutex<Pin<Box<dyn Stream<Item = Response> + Send>>>,
+}
+
+impl Suite {
+	/// Target the server at `base_url`, receiving webhooks on a random local port.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the webhook receiver cannot be started.
+	pub fn new(base_url: Url) -> Result<Self> {
+		Self::with_webhooks(base_url, SocketAddr::from(([127, 0, 0, 1], 0)), None)
+	}
+
+	/// Target the server at `base_url`, receiving webhooks on the given address.
+	///
+	/// Servers that can't reach that address directly (like ones running in a container) can be given a different `webhook_url` that forwards to it.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the webhook receiver cannot be started.
+	pub fn with_webhooks(
+		mut base_url: Url,
+		addr: SocketAddr,
+		webhook_url: Option<Url>,
+	) -> Result<Self> {
+		// Make sure paths are joined onto the base url, instead of replacing its last segment.
+		if !base_url.path().ends_with('/') {
+			base_url.set_path(&format!("{}/", base_url.path()));
+		}
+
+		let webhooks = WebhookReceiver::new().bind(addr)?;
+
+		Ok(Self {
+			base_url,
+			client: Client::new(),
+			webhook_url: webhook_url
+				.or_else(|| webhooks.url())
+				.context("The webhook receiver isn't listening")?,
+			events: Mutex::new(Box::pin(webhooks.events())),
+			_webhooks: webhooks,
+		})
+	}
+
+	/// Run every check in order, waiting for the server to be idle before each one.
+	pub async fn run(&self) -> Report {
+		let checks: Vec<(&'static str, Check<'_>)> = vec![
+			(
+				"health-check becomes ready after setup",
+				Box::pin(self.setup_completes()),
+			),
+			(
+				"sync predictions return the result",
+				Box::pin(self.sync_prediction()),
+			),
+			(
+				"failed predictions return a failed response",
+				Box::pin(self.failed_prediction()),
+			),
+			(
+				"invalid input is rejected with a 422",
+				Box::pin(self.invalid_input()),
+			),
+			(
+				"async predictions send ordered webhooks",
+				Box::pin(self.async_prediction()),
+			),
+			("PUT is idempotent", Box::pin(self.idempotent_put())),
+			(
+				"concurrent predictions are rejected with a 409",
+				Box::pin(self.concurrent_predictions()),
+			),
+			(
+				"health-check is busy while predicting",
+				Box::pin(self.busy_health_check()),
+			),
+			(
+				"webhook event filters are respected",
+				Box::pin(self.webhook_event_filters()),
+			),
+			("predictions can be canceled", Box::pin(self.cancel())),
+		];
+
+		let mut report = Report::default();
+		for (name, check) in checks {
+			let result = match self.wait_until_ready().await {
+				Ok(()) => check.await,
+				Err(error) => Err(error),
+			};
+
+			report.results.push((name, result));
+		}
+
+		report
+	}
+
+	async fn setup_completes(&self) -> Result<()> {
+		let started = Instant::now();
+
+		loop {
+			let (status, body) = self
+				.request(Method::GET, "health-check", None, false)
+				.await?;
+			ensure!(status == StatusCode::OK, "Expected a 200, got {status}");
+
+			match body["status"].as_str() {
+				Some("READY") => {
+					ensure!(
+						body["setup"]["status"] == "succeeded",
+						"Expected setup to have succeeded, got {}",
+						body["setup"]
+					);
+					return Ok(());
+				},
+				Some("STARTING") if started.elapsed() < TIMEOUT => {
+					tokio::time::sleep(Duration::from_millis(100)).await;
+				},
+				_ => anyhow::bail!("Unexpected health-check response: {body}"),
+			}
+		}
+	}
+
+	async fn sync_prediction(&self) -> Result<()> {
+		let response = self
+			.predict(
+				Method::POST,
+				"predictions",
+				json!({ "input": { "text": "hello" } }),
+				false,
+			)
+			.await?;
+
+		ensure!(
+			matches!(response.status, Status::Succeeded),
+			"Expected the prediction to succeed, got {:?}",
+			response.status
+		);
+		ensure!(
+			response.output == Some(json!("hello")),
+			"Unexpected output: {:?}",
+			response.output
+		);
+		ensure!(
+			response.input == Some(json!({ "text": "hello" })),
+			"Unexpected input: {:?}",
+			response.input
+		);
+
+		Ok(())
+	}
+
+	async fn failed_prediction(&self) -> Result<()> {
+		let response = self
+			.predict(
+				Method::POST,
+				"predictions",
+				json!({ "input": { "text": "hello", "fail": true } }),
+				false,
+			)
+			.await?;
+
+		ensure!(
+			matches!(response.status, Status::Failed),
+			"Expected the prediction to fail, got {:?}",
+			response.status
+		);
+		ensure!(response.error.is_some(), "Expected an error message");
+
+		Ok(())
+	}
+
+	async fn invalid_input(&self) -> Result<()> {
+		for input in [json!({ "text": 1 }), json!({})] {
+			let (status, body) = self
+				.request(
+					Method::POST,
+					"predictions",
+					Some(json!({ "input": input })),
+					false,
+				)
+				.await?;
+
+			ensure!(
+				status == StatusCode::UNPROCESSABLE_ENTITY,
+				"Expected a 422 for {input}, got {status}"
+			);
+
+			let detail = body["detail"]
+				.as_array()
+				.filter(|detail| !detail.is_empty())
+				.with_context(|| format!("Expected a list of validation errors, got {body}"))?;
+			for error in detail {
+				ensure!(error["msg"].is_string(), "Missing error message in {error}");
+				ensure!(
+					error["loc"]
+						.as_array()
+						.is_some_and(|loc| loc.len() >= 2 && loc[0] == "body" && loc[1] == "input"),
+					"Expected the error location to start with body.input, got {}",
+					error["loc"]
+				);
+			}
+		}
+
+		Ok(())
+	}
+
+	async fn async_prediction(&self) -> Result<()> {
+		let id = Uuid::new_v4().to_string();
+		let response = self
+			.predict(
+				Method::PUT,
+				&format!("predictions/{id}"),
+				json!({ "input": { "text": "hello" }, "webhook": self.webhook_url }),
+				true,
+			)
+			.await?;
+
+		ensure!(
+			response.id.as_deref() == Some(&id),
+			"Expected the response to have id {id}, got {:?}",
+			response.id
+		);
+		ensure!(
+			matches!(response.status, Status::Starting | Status::Processing),
+			"Expected the prediction to be starting, got {:?}",
+			response.status
+		);
+
+		let webhooks = self.webhooks_for(&id).await?;
+		ensure!(
+			matches!(webhooks[0].status, Status::Processing),
+			"Expected a start webhook first, got {:?}",
+			webhooks[0].status
+		);
+
+		let last = webhooks.last().unwrap();
+		ensure!(
+			matches!(last.status, Status::Succeeded) && last.output == Some(json!("hello")),
+			"Expected the last webhook to have the result, got {last:?}"
+		);
+
+		Ok(())
+	}
+
+	async fn idempotent_put(&self) -> Result<()> {
+		let id = Uuid::new_v4().to_string();
+		let path = format!("predictions/{id}");
+		let body = json!({ "input": { "text": "hello", "sleep": SLOW } });
+
+		self.predict(Method::PUT, &path, body.clone(), true).await?;
+
+		let response = self.predict(Method::PUT, &path, body.clone(), true).await?;
+		ensure!(
+			response.id.as_deref() == Some(&id),
+			"Expected the running prediction to be returned, got {:?}",
+			response.id
+		);
+
+		let response = self.predict(Method::PUT, &path, body, false).await?;
+		ensure!(
+			matches!(response.status, Status::Succeeded) && response.output == Some(json!("hello")),
+			"Expected a sync PUT to wait for the running prediction, got {response:?}"
+		);
+
+		Ok(())
+	}
+
+	async fn concurrent_predictions(&self) -> Result<()> {
+		let id = self.start_slow_prediction(SLOW, None).await?;
+		let body = json!({ "input": { "text": "hello" } });
+
+		for (method, path, respond_async) in [
+			(Method::PUT, format!("predictions/{}", Uuid::new_v4()), true),
+			(Method::POST, "predictions".to_string(), true),
+			(Method::POST, "predictions".to_string(), false),
+		] {
+			let (status, response) = self
+				.request(method.clone(), &path, Some(body.clone()), respond_async)
+				.await?;
+
+			ensure!(
+				status == StatusCode::CONFLICT,
+				"Expected a 409 for {method} /{path}, got {status}"
+			);
+			ensure!(
+				response["detail"].is_string(),
+				"Expected an error message, got {response}"
+			);
+		}
+
+		self.webhooks_for(&id).await?;
+
+		Ok(())
+	}
+
+	async fn busy_health_check(&self) -> Result<()> {
+		let id = self.start_slow_prediction(SLOW, None).await?;
+
+		let status = self.health().await?;
+		ensure!(
+			status == "BUSY",
+			"Expected BUSY while predicting, got {status}"
+		);
+
+		self.webhooks_for(&id).await?;
+		self.wait_until_ready().await
+	}
+
+	async fn webhook_event_filters(&self) -> Result<()> {
+		let id = self
+			.start_slow_prediction(0.0, Some(&["Completed"]))
+			.await?;
+
+		let webhooks = self.webhooks_for(&id).await?;
+		ensure!(
+			webhooks.len() == 1,
+			"Expected only the completed webhook, got {:?}",
+			webhooks
+				.iter()
+				.map(|webhook| webhook.status)
+				.collect::<Vec<_>>()
+		);
+
+		Ok(())
+	}
+
+	async fn cancel(&self) -> Result<()> {
+		let id = self.start_slow_prediction(SLOW * 5.0, None).await?;
+
+		let (status, body) = self
+			.request(
+				Method::POST,
+				&format!("predictions/{id}/cancel"),
+				None,
+				false,
+			)
+			.await?;
+		ensure!(
+			status == StatusCode::OK,
+			"Expected a 200, got {status}: {body}"
+		);
+
+		let webhooks = self.webhooks_for(&id).await?;
+		let last = webhooks.last().unwrap();
+		ensure!(
+			matches!(last.status, Status::Canceled),
+			"Expected the prediction to be canceled, got {:?}",
+			last.status
+		);
+
+		let (status, body) = self
+			.request(
+				Method::POST,
+				&format!("predictions/{}/cancel", Uuid::new_v4()),
+				None,
+				false,
+			)
+			.await?;
+		ensure!(
+			status == StatusCode::NOT_FOUND,
+			"Expected a 404 when canceling an unknown prediction, got {status}"
+		);
+		ensure!(
+			body["detail"].is_string(),
+			"Expected an error message, got {body}"
+		);
+
+		Ok(())
+	}
+
+	/// Start an async prediction that takes `sleep` seconds, sending webhooks to the suite.
+	async fn start_slow_prediction(&self, sleep: f64, filters: Option<&[&str]>) -> Result<String> {
+		let id = Uuid::new_v4().to_string();
+		let mut body = json!({
+			"input": { "text": "hello", "sleep": sleep },
+			"webhook": self.webhook_url,
+		});
+		if let Some(filters) = filters {
+			body["webhook_event_filters"] = json!(filters);
+		}
+
+		self.predict(Method::PUT, &format!("predictions/{id}"), body, true)
+			.await?;
+
+		Ok(id)
+	}
+
+	/// Collect the webhooks sent for the given prediction, until the one for its completion.
+	async fn webhooks_for(&self, id: &str) -> Result<Vec<Response>> {
+		let (mut webhooks, mut events) = (Vec::new(), self.events.lock().await);
+
+		loop {
+			let webhook = tokio::time::timeout(TIMEOUT, events.next())
+				.await
+				.with_context(|| format!("Timed out waiting for webhooks for prediction {id}"))?
+				.context("Webhook receiver stopped")?;
+
+			if webhook.id.as_deref() != Some(id) {
+				continue;
+			}
+
+			let completed = matches!(
+				webhook.status,
+				Status::Succeeded | Status::Failed | Status::Canceled
+			);
+			webhooks.push(webhook);
+
+			if completed {
+				return Ok(webhooks);
+			}
+		}
+	}
+
+	async fn wait_until_ready(&self) -> Result<()> {
+		let started = Instant::now();
+
+		while self.health().await? != "READY" {
+			ensure!(
+				started.elapsed() < TIMEOUT,
+				"Timed out waiting for the server to be ready"
+			);
+			tokio::time::sleep(Duration::from_millis(100)).await;
+		}
+
+		Ok(())
+	}
+
+	async fn health(&self) -> Result<String> {
+		let (_, body) = self
+			.request(Method::GET, "health-check", None, false)
+			.await?;
+
+		body["status"]
+			.as_str()
+			.map(ToString::to_string)
+			.with_context(|| format!("Unexpected health-check response: {body}"))
+	}
+
+	/// Create a prediction, expecting a 200 (or a 202 when responding asynchronously).
+	async fn predict(
+		&self,
+		method: Method,
+		path: &str,
+		body: Value,
+		respond_async: bool,
+	) -> Result<Response> {
+		let expected = if respond_async {
+			StatusCode::ACCEPTED
+		} else {
+			StatusCode::OK
+		};
+
+		let (status, body) = self
+			.request(method.clone(), path, Some(body), respond_async)
+			.await?;
+		ensure!(
+			status == expected,
+			"Expected a {expected} for {method} /{path}, got {status}: {body}"
+		);
+
+		serde_json::from_value(body).context("Invalid prediction response")
+	}
+
+	async fn request(
+		&self,
+		method: Method,
+		path: &str,
+		body: Option<Value>,
+		respond_async: bool,
+	) -> Result<(StatusCode, Value)> {
+		let mut request = self.client.request(method, self.base_url.join(path)?);
+		if respond_async {
+			request = request.header("Prefer", "respond-async");
+		}
+		if let Some(body) = body {
+			request = request.json(&body);
+		}
+
+		let response = request.send().await?;
+		let status = response.status();
+		let body = response
+			.json::<Value>()
+			.await
+			.with_context(|| format!("Expected a JSON response to /{path}"))?;
+
+		Ok((status, body))
+	}
+}
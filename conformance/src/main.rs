@@ -0,0 +1,39 @@
+#![warn(clippy::all, clippy::pedantic, clippy::nursery)]
+
+use anyhow::Result;
+use clap::Parser;
+use cog_conformance::Suite;
+use std::{net::SocketAddr, process::ExitCode};
+use url::Url;
+
+/// Check that a server running the conformance model follows Cog's HTTP protocol.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+	/// Base URL of the server under test
+	base_url: Url,
+
+	/// Address to receive webhooks on
+	#[clap(long, default_value = "127.0.0.1:0")]
+	webhook_listen: SocketAddr,
+
+	/// URL the server should send webhooks to, if it can't reach --webhook-listen directly
+	#[clap(long)]
+	webhook_url: Option<Url>,
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode> {
+	let args = Cli::parse();
+
+	let report = Suite::with_webhooks(args.base_url, args.webhook_listen, args.webhook_url)?
+		.run()
+		.await;
+	print!("{report}");
+
+	Ok(if report.passed() {
+		ExitCode::SUCCESS
+	} else {
+		ExitCode::FAILURE
+	})
+}
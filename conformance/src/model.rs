@@ -0,0 +1,41 @@
+use anyhow::Result;
+use cog_rust::{Cog, SetupContext};
+use schemars::JsonSchema;
+use std::time::Duration;
+
+/// Input of the conformance model.
+///
+/// Servers under test should run a model with the same input schema: echo `text` back as the output after sleeping for `sleep` seconds, or fail if `fail` is set.
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+pub struct Input {
+	/// Text to echo back
+	pub text: String,
+	/// Seconds to wait before responding
+	pub sleep: Option<f64>,
+	/// Fail the prediction instead of responding
+	pub fail: Option<bool>,
+}
+
+/// A trivial model driving the conformance suite.
+pub struct Model;
+
+impl Cog for Model {
+	type Request = Input;
+	type Response = String;
+
+	async fn setup(_: SetupContext) -> Result<Self> {
+		Ok(Self)
+	}
+
+	fn predict(&self, input: Self::Request) -> Result<Self::Response> {
+		if let Some(sleep) = input.sleep {
+			std::thread::sleep(Duration::from_secs_f64(sleep));
+		}
+
+		if input.fail.unwrap_or_default() {
+			anyhow::bail!("Prediction failed as requested");
+		}
+
+		Ok(input.text)
+	}
+}
@@ -0,0 +1,18 @@
+use cog_conformance::{Model, Suite};
+use cog_rust::{ServerBuilder, Signals};
+
+#[tokio::test]
+async fn cog_rust_conforms() {
+	let router = ServerBuilder::<Model>::new()
+		.signals(Signals::None)
+		.allow_private_egress(true)
+		.into_router()
+		.unwrap();
+
+	let server = axum::Server::bind(&([127, 0, 0, 1], 0).into()).serve(router.into_make_service());
+	let base_url = format!("http://{}", server.local_addr()).parse().unwrap();
+	tokio::spawn(server);
+
+	let report = Suite::new(base_url).unwrap().run().await;
+	assert!(report.passed(), "\n{report}");
+}
@@ -0,0 +1,57 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// An error a model can return from `predict()` (or `train()`) to tell clients what went wrong, like a prompt it can't handle.
+///
+/// Unlike other errors, its code, message and details end up in the response as-is, so the message should be safe to show to users.
+///
+/// ```
+/// # use cog_core::ModelError;
+/// # fn predict(prompt: &str) -> anyhow::Result<String> {
+/// if prompt.is_empty() {
+///     return Err(ModelError::new("empty_prompt", "The prompt can't be empty").into());
+/// }
+/// # Ok(prompt.to_string())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema, thiserror::Error)]
+#[error("{message}")]
+pub struct ModelError {
+	/// A machine-readable code for the error, like `invalid_prompt`
+	pub code: String,
+	/// A description of the error that's safe to show to users
+	pub message: String,
+	/// Whether running the same prediction again may succeed
+	pub retryable: bool,
+	/// More information about the error, like which part of the input caused it
+	#[serde(default, skip_serializing_if = "Value::is_null")]
+	pub details: Value,
+}
+
+impl ModelError {
+	/// A (non-retryable) error with the given code and message.
+	#[must_use]
+	pub fn new(code: impl Into<String>, message: impl Into<String>) -> Self {
+		Self {
+			code: code.into(),
+			message: message.into(),
+			retryable: false,
+			details: Value::Null,
+		}
+	}
+
+	/// Mark the error as temporary, so clients know they can try again.
+	#[must_use]
+	pub const fn retryable(mut self) -> Self {
+		self.retryable = true;
+		self
+	}
+
+	/// Attach more information about the error.
+	#[must_use]
+	pub fn with_details(mut self, details: impl Into<Value>) -> Self {
+		self.details = details.into();
+		self
+	}
+}
@@ -0,0 +1,62 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// How a model is doing after its setup, as reported by [`crate::Cog::health_check`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum HealthStatus {
+	/// The model can run predictions.
+	#[default]
+	Healthy,
+	/// The model can't run predictions right now, but may recover (it'll keep being checked).
+	Unhealthy,
+	/// The model can't recover, and the server should be restarted.
+	Defunct,
+}
+
+/// The result of a model's health check, with any details that help diagnose it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct HealthReport {
+	/// Whether the model can run predictions
+	pub status: HealthStatus,
+	/// Model-specific diagnostics, like GPU memory usage or the error that made the model unhealthy
+	#[serde(default, skip_serializing_if = "Value::is_null")]
+	pub details: Value,
+}
+
+impl HealthReport {
+	/// The model can run predictions.
+	#[must_use]
+	pub const fn healthy() -> Self {
+		Self {
+			status: HealthStatus::Healthy,
+			details: Value::Null,
+		}
+	}
+
+	/// The model can't run predictions right now, but may recover.
+	#[must_use]
+	pub const fn unhealthy() -> Self {
+		Self {
+			status: HealthStatus::Unhealthy,
+			details: Value::Null,
+		}
+	}
+
+	/// The model can't recover, and the server should be restarted.
+	#[must_use]
+	pub const fn defunct() -> Self {
+		Self {
+			status: HealthStatus::Defunct,
+			details: Value::Null,
+		}
+	}
+
+	/// Attach diagnostic details to the report.
+	#[must_use]
+	pub fn with_details(mut self, details: impl Into<Value>) -> Self {
+		self.details = details.into();
+		self
+	}
+}
@@ -5,7 +5,9 @@ use serde_json::Value;
 use std::collections::HashMap;
 use url::Url;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+use crate::ModelError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum Status {
 	#[serde(skip)]
@@ -18,7 +20,7 @@ pub enum Status {
 	Processing,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub enum WebhookEvent {
 	Start,
 	Output,
@@ -26,14 +28,32 @@ pub enum WebhookEvent {
 	Completed,
 }
 
-#[derive(Debug, Clone, serde::Deserialize, JsonSchema)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Request<T = Value> {
+	#[serde(skip_serializing_if = "Option::is_none")]
 	pub webhook: Option<Url>,
+	#[serde(skip_serializing_if = "Option::is_none")]
 	pub webhook_event_filters: Option<Vec<WebhookEvent>>,
 
+	/// A URL prefix that output files are PUT to (the file name is appended to it), overriding the server's upload URL.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub output_file_prefix: Option<Url>,
+
 	pub input: T,
 }
 
+impl<T> Request<T> {
+	/// A request for the given input, without webhooks.
+	pub const fn new(input: T) -> Self {
+		Self {
+			input,
+			webhook: None,
+			webhook_event_filters: None,
+			output_file_prefix: None,
+		}
+	}
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Response<Req = Value, Res = Value> {
 	pub input: Option<Req>,
@@ -49,6 +69,8 @@ pub struct Response<Req = Value, Res = Value> {
 	pub logs: String,
 	pub status: Status,
 	pub error: Option<String>,
+	/// A machine-readable description of why the prediction failed
+	pub failure: Option<ModelError>,
 
 	pub metrics: Option<HashMap<String, Value>>,
 }
@@ -58,6 +80,7 @@ impl Default for Response {
 		Self {
 			id: None,
 			error: None,
+			failure: None,
 			input: None,
 			output: None,
 			metrics: None,
@@ -1,6 +1,14 @@
 #![warn(clippy::all, clippy::pedantic, clippy::nursery)]
 
+mod error;
+mod health;
 pub mod http;
+mod setup;
 mod spec;
+#[cfg(feature = "webhooks")]
+pub mod webhooks;
 
-pub use spec::{Cog, CogResponse};
+pub use error::ModelError;
+pub use health::{HealthReport, HealthStatus};
+pub use setup::{Progress, Reporter, SetupContext, WeightsLocation};
+pub use spec::{Cog, CogResponse, Train};
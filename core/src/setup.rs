@@ -0,0 +1,223 @@
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::{
+	collections::HashMap,
+	fmt::Display,
+	path::{Path, PathBuf},
+	str::FromStr,
+	sync::{Arc, Mutex},
+};
+use url::Url;
+
+/// What a model gets to set itself up with: where its weights are, its configuration and a place to put files.
+#[derive(Debug, Clone)]
+pub struct SetupContext {
+	weights: Option<WeightsLocation>,
+	env: HashMap<String, String>,
+	scratch_dir: PathBuf,
+	reporter: Reporter,
+}
+
+impl SetupContext {
+	/// A context with no weights or configuration, using the given scratch directory.
+	#[must_use]
+	pub fn new(scratch_dir: impl Into<PathBuf>) -> Self {
+		Self {
+			weights: None,
+			env: HashMap::new(),
+			scratch_dir: scratch_dir.into(),
+			reporter: Reporter::default(),
+		}
+	}
+
+	/// Load the model's weights from this location.
+	#[must_use]
+	pub fn with_weights(mut self, weights: WeightsLocation) -> Self {
+		self.weights = Some(weights);
+		self
+	}
+
+	/// Configure the model with these variables (usually, the process' environment).
+	#[must_use]
+	pub fn with_env(mut self, env: impl IntoIterator<Item = (String, String)>) -> Self {
+		self.env.extend(env);
+		self
+	}
+
+	/// Report the setup's progress and logs through this reporter.
+	#[must_use]
+	pub fn with_reporter(mut self, reporter: Reporter) -> Self {
+		self.reporter = reporter;
+		self
+	}
+
+	/// Where the model's weights should be loaded from, as chosen at deploy time (with `COG_WEIGHTS`) or bundled into the image (in `weights/`).
+	#[must_use]
+	pub const fn weights(&self) -> Option<&WeightsLocation> {
+		self.weights.as_ref()
+	}
+
+	/// A configuration variable, if it was set.
+	#[must_use]
+	pub fn var(&self, name: &str) -> Option<&str> {
+		self.env.get(name).map(String::as_str)
+	}
+
+	/// Parse a configuration variable, if it was set.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the variable is set but can't be parsed.
+	pub fn parse_var<T>(&self, name: &str) -> Result<Option<T>>
+	where
+		T: FromStr,
+		T::Err: Display,
+	{
+		self.var(name)
+			.map(|value| {
+				value
+					.parse()
+					.map_err(|error| anyhow::anyhow!("{error}"))
+					.with_context(|| format!("Invalid value for {name}: {value:?}"))
+			})
+			.transpose()
+	}
+
+	/// A directory the model can write temporary files to, which already exists.
+	#[must_use]
+	pub fn scratch_dir(&self) -> &Path {
+		&self.scratch_dir
+	}
+
+	/// Reports the setup's progress and logs to the server's health check.
+	#[must_use]
+	pub const fn reporter(&self) -> &Reporter {
+		&self.reporter
+	}
+}
+
+/// Where a model's weights are: a local file or directory, or a URL to download them from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WeightsLocation {
+	Path(PathBuf),
+	Url(Url),
+}
+
+impl FromStr for WeightsLocation {
+	type Err = std::convert::Infallible;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		// Single-letter schemes are Windows drive letters, not URLs.
+		match Url::parse(s) {
+			Ok(url) if url.scheme() == "file" => Ok(url
+				.to_file_path()
+				.map_or_else(|()| Self::Url(url), Self::Path)),
+			Ok(url) if url.scheme().len() > 1 => Ok(Self::Url(url)),
+			_ => Ok(Self::Path(PathBuf::from(s))),
+		}
+	}
+}
+
+/// How far along a step of the setup (like downloading weights) is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct Progress {
+	/// Units (like bytes) completed so far
+	pub current: u64,
+	/// Total units, if known
+	pub total: Option<u64>,
+}
+
+/// Reports a model's setup logs and progress, which the server shows in its health check.
+#[derive(Debug, Clone, Default)]
+pub struct Reporter {
+	state: Arc<Mutex<ReporterState>>,
+}
+
+#[derive(Debug, Default)]
+struct ReporterState {
+	logs: String,
+	progress: Option<Progress>,
+}
+
+impl Reporter {
+	/// Add a line to the setup logs.
+	///
+	/// # Panics
+	///
+	/// Panics if another thread panicked while reporting.
+	pub fn log(&self, message: impl AsRef<str>) {
+		let message = message.as_ref();
+		tracing::info!("{message}");
+
+		let mut state = self.state.lock().unwrap();
+		state.logs.push_str(message);
+		state.logs.push('\n');
+	}
+
+	/// Record how far along the current step of the setup is.
+	///
+	/// # Panics
+	///
+	/// Panics if another thread panicked while reporting.
+	pub fn progress(&self, current: u64, total: Option<u64>) {
+		self.state.lock().unwrap().progress = Some(Progress { current, total });
+	}
+
+	/// Everything logged so far.
+	///
+	/// # Panics
+	///
+	/// Panics if another thread panicked while reporting.
+	#[must_use]
+	pub fn logs(&self) -> String {
+		self.state.lock().unwrap().logs.clone()
+	}
+
+	/// The last progress reported, if any.
+	///
+	/// # Panics
+	///
+	/// Panics if another thread panicked while reporting.
+	#[must_use]
+	pub fn last_progress(&self) -> Option<Progress> {
+		self.state.lock().unwrap().progress
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn weights_locations_are_parsed() {
+		assert_eq!(
+			"weights/model.safetensors"
+				.parse::<WeightsLocation>()
+				.unwrap(),
+			WeightsLocation::Path(PathBuf::from("weights/model.safetensors"))
+		);
+		assert_eq!(
+			"file:///weights".parse::<WeightsLocation>().unwrap(),
+			WeightsLocation::Path(PathBuf::from("/weights"))
+		);
+		assert_eq!(
+			"https://example.com/weights.tar"
+				.parse::<WeightsLocation>()
+				.unwrap(),
+			WeightsLocation::Url("https://example.com/weights.tar".parse().unwrap())
+		);
+	}
+
+	#[test]
+	fn variables_are_parsed() {
+		let ctx = SetupContext::new("/tmp").with_env([
+			("BATCH_SIZE".to_string(), "8".to_string()),
+			("DEVICE".to_string(), "cuda".to_string()),
+		]);
+
+		assert_eq!(ctx.parse_var::<u32>("BATCH_SIZE").unwrap(), Some(8));
+		assert_eq!(ctx.parse_var::<u32>("MISSING").unwrap(), None);
+		assert!(ctx.parse_var::<u32>("DEVICE").is_err());
+	}
+}
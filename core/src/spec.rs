@@ -5,19 +5,19 @@ use serde::{de::DeserializeOwned, Serialize};
 use serde_json::Value;
 use std::future::Future;
 
-use crate::http::Request;
+use crate::{http::Request, HealthReport, SetupContext};
 
 /// A Cog model
 pub trait Cog: Sized + Send {
 	type Request: DeserializeOwned + JsonSchema + Send;
-	type Response: CogResponse + Debug + JsonSchema;
+	type Response: CogResponse + Debug + JsonSchema + 'static;
 
-	/// Setup the model
+	/// Setup the model, loading its weights and configuration from the given context
 	///
 	/// # Errors
 	///
 	/// Returns an error if setup fails.
-	fn setup() -> impl Future<Output = Result<Self>> + Send;
+	fn setup(ctx: SetupContext) -> impl Future<Output = Result<Self>> + Send;
 
 	/// Run a prediction on the model
 	///
@@ -25,12 +25,71 @@ pub trait Cog: Sized + Send {
 	///
 	/// Returns an error if the prediction fails.
 	fn predict(&self, input: Self::Request) -> Result<Self::Response>;
+
+	/// Check whether the model can still run predictions (for example, that its GPU context is usable), with any details that help diagnose it
+	///
+	/// Called periodically and when `/health-check` is requested, but never while a prediction is running. Unhealthy models don't get predictions until they report being healthy again, and defunct models never do.
+	fn health_check(&mut self) -> impl Future<Output = HealthReport> + Send {
+		async { HealthReport::healthy() }
+	}
+
+	/// Release the model's resources (like GPU contexts or connections) when the server shuts down, once the running prediction (if any) stops
+	///
+	/// # Errors
+	///
+	/// Returns an error if teardown fails.
+	fn teardown(&mut self) -> impl Future<Output = Result<()>> + Send {
+		async { Ok(()) }
+	}
+}
+
+/// A trainer for a Cog model, served from the `/trainings` endpoints alongside the model's predictions.
+pub trait Train: Sized + Send {
+	type Request: DeserializeOwned + JsonSchema + Send;
+	type Response: CogResponse + Debug + JsonSchema + 'static;
+
+	/// Setup the trainer, loading its weights and configuration from the given context
+	///
+	/// # Errors
+	///
+	/// Returns an error if setup fails.
+	fn setup(ctx: SetupContext) -> impl Future<Output = Result<Self>> + Send;
+
+	/// Run a training job
+	///
+	/// # Errors
+	///
+	/// Returns an error if training fails.
+	fn train(&self, input: Self::Request) -> Result<Self::Response>;
+
+	/// Release the trainer's resources when the server shuts down, once the running training job (if any) stops
+	///
+	/// # Errors
+	///
+	/// Returns an error if teardown fails.
+	fn teardown(&mut self) -> impl Future<Output = Result<()>> + Send {
+		async { Ok(()) }
+	}
 }
 
 /// A response from a Cog model
 pub trait CogResponse: Send {
 	/// Convert the response into a JSON value
 	fn into_response(self, request: Request) -> impl Future<Output = Result<Value>> + Send;
+
+	/// Convert the response into a JSON value on the current thread, which must allow blocking
+	///
+	/// Runners call this from a blocking thread, so per-prediction state set up on that thread (like where output files go) is visible while serializing. Defaults to blocking on `into_response`.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the response cannot be converted.
+	fn into_response_blocking(self, request: Request) -> Result<Value>
+	where
+		Self: Sized,
+	{
+		tokio::runtime::Handle::current().block_on(self.into_response(request))
+	}
 }
 
 impl<T: Serialize + Send + 'static> CogResponse for T {
@@ -38,4 +97,8 @@ impl<T: Serialize + Send + 'static> CogResponse for T {
 		// We use spawn_blocking here to allow blocking code in serde Serialize impls (used in `Path`, for example).
 		Ok(tokio::task::spawn_blocking(move || serde_json::to_value(self)).await??)
 	}
+
+	fn into_response_blocking(self, _: Request) -> Result<Value> {
+		Ok(serde_json::to_value(self)?)
+	}
 }
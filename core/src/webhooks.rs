@@ -0,0 +1,531 @@
+use chrono::Utc;
+use futures_core::Stream;
+use hmac::{Hmac, Mac};
+use http::{header::AUTHORIZATION, Method, Request, StatusCode};
+use http_body::{LengthLimitError, Limited};
+use hyper::{
+	server::conn::AddrIncoming,
+	service::{make_service_fn, service_fn},
+};
+use sha2::Sha256;
+use std::{
+	collections::{HashSet, VecDeque},
+	convert::Infallible,
+	future::Future,
+	net::SocketAddr,
+	pin::Pin,
+	sync::{Arc, Mutex, Weak},
+	task::{Context, Poll},
+};
+use tokio::task::JoinHandle;
+use tower::Service;
+use url::Url;
+
+use crate::http::{Response, Status};
+
+/// Header with the Unix timestamp a webhook was signed at.
+pub const TIMESTAMP_HEADER: &str = "X-Cog-Timestamp";
+
+/// Header with the hex-encoded signature of a webhook.
+pub const SIGNATURE_HEADER: &str = "X-Cog-Signature";
+
+/// How far (in seconds) the timestamp of a signed webhook (or request) may be from the current time.
+pub const SIGNATURE_TOLERANCE: i64 = 300;
+
+/// How many completed webhooks we remember to detect duplicates.
+const MAX_SEEN: usize = 10_000;
+
+/// The largest webhook body accepted by default. Outputs are sent inline when the server doesn't upload them, so webhooks can be large.
+const DEFAULT_MAX_BODY_SIZE: usize = 100 * 1024 * 1024;
+
+/// Sign a webhook body: a hex-encoded HMAC-SHA256 of `{timestamp}.{body}`.
+#[must_use]
+pub fn sign(secret: &[u8], timestamp: i64, body: &[u8]) -> String {
+	hex::encode(signing_mac(secret, timestamp, body).finalize().into_bytes())
+}
+
+/// An HMAC-SHA256 keyed with `key`, ready to be updated with the signed data.
+///
+/// # Panics
+///
+/// Never, since HMAC accepts keys of any size.
+#[must_use]
+pub fn hmac_sha256(key: &[u8]) -> Hmac<Sha256> {
+	Hmac::new_from_slice(key).expect("HMAC can take a key of any size")
+}
+
+/// Compare two secrets (like bearer tokens) in constant time, so they can't be guessed from how long the comparison takes.
+#[must_use]
+pub fn secrets_match(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn signing_mac(secret: &[u8], timestamp: i64, body: &[u8]) -> Hmac<Sha256> {
+	let mut mac = hmac_sha256(secret);
+	mac.update(format!("{timestamp}.").as_bytes());
+	mac.update(body);
+
+	mac
+}
+
+/// A tower service receiving prediction webhooks, to mount in any server (like an axum router, with `route_service`), or to serve on its own (see [`WebhookReceiver::start`]).
+///
+/// Webhooks are authenticated with a bearer token and/or a signature (see [`sign`]) when configured, and redelivered completion webhooks (with the same prediction id and status) are only passed on once. Predictions send several `processing` webhooks (as their logs and output change), so those are always passed on.
+#[derive(Clone)]
+pub struct WebhookReceiver {
+	inner: Arc<Inner>,
+}
+
+struct Inner {
+	token: Option<String>,
+	secret: Option<Vec<u8>>,
+	max_body_size: usize,
+	seen: Mutex<Seen>,
+	subscribers: Mutex<Vec<Subscriber>>,
+	url: Option<Url>,
+	server: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// A stream returned by [`WebhookReceiver::events`] or [`WebhookReceiver::completed`].
+struct Subscriber {
+	completed_only: bool,
+	sender: flume::Sender<Response>,
+}
+
+/// The completion webhooks we've already passed on, by prediction id and status (forgetting the oldest ones first).
+#[derive(Default)]
+struct Seen {
+	set: HashSet<(String, Status)>,
+	order: VecDeque<(String, Status)>,
+}
+
+impl Default for WebhookReceiver {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl WebhookReceiver {
+	/// A receiver accepting unauthenticated webhooks.
+	#[must_use]
+	pub fn new() -> Self {
+		Self {
+			inner: Arc::new(Inner {
+				token: None,
+				secret: None,
+				max_body_size: DEFAULT_MAX_BODY_SIZE,
+				seen: Mutex::default(),
+				subscribers: Mutex::default(),
+				url: None,
+				server: Mutex::default(),
+			}),
+		}
+	}
+
+	/// Only accept webhooks sent with this bearer token (like the server's `WEBHOOK_AUTH_TOKEN`).
+	#[must_use]
+	pub fn with_token(self, token: impl Into<String>) -> Self {
+		self.configure(|inner| inner.token = Some(token.into()))
+	}
+
+	/// Only accept webhooks signed with this secret (like the server's `WEBHOOK_SIGNING_SECRET`).
+	#[must_use]
+	pub fn with_signing_secret(self, secret: impl Into<Vec<u8>>) -> Self {
+		self.configure(|inner| inner.secret = Some(secret.into()))
+	}
+
+	/// Reject webhooks with bodies larger than this many bytes (100 MiB by default) with a 413, instead of buffering them.
+	#[must_use]
+	pub fn with_max_body_size(self, max_body_size: usize) -> Self {
+		self.configure(|inner| inner.max_body_size = max_body_size)
+	}
+
+	/// Start listening for webhooks on a random local port, until the receiver (and all its clones) are dropped. Must be called from within a Tokio runtime.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the port cannot be bound.
+	pub fn start(self) -> hyper::Result<Self> {
+		self.bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+	}
+
+	/// Start listening for webhooks on the given address, until the receiver (and all its clones) are dropped. Must be called from within a Tokio runtime.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the address cannot be bound.
+	///
+	/// # Panics
+	///
+	/// If the receiver has already been cloned.
+	pub fn bind(self, addr: SocketAddr) -> hyper::Result<Self> {
+		let incoming = AddrIncoming::bind(&addr)?;
+		let url = format!("http://{}/", incoming.local_addr())
+			.parse()
+			.expect("A socket address is a valid host");
+		let receiver = self.configure(|inner| inner.url = Some(url));
+
+		// The server only holds a weak reference, so dropping the receiver stops it.
+		let inner = Arc::downgrade(&receiver.inner);
+		let server = hyper::Server::builder(incoming).serve(make_service_fn(move |_| {
+			let inner = inner.clone();
+			async move {
+				Ok::<_, Infallible>(service_fn(move |req| {
+					Self::respond(Weak::upgrade(&inner), req)
+				}))
+			}
+		}));
+		*receiver.inner.server.lock().unwrap() = Some(tokio::spawn(async move {
+			server.await.ok();
+		}));
+
+		Ok(receiver)
+	}
+
+	/// The URL to send webhooks to, if the receiver was started with [`Self::start`] or [`Self::bind`].
+	#[must_use]
+	pub fn url(&self) -> Option<Url> {
+		self.inner.url.clone()
+	}
+
+	/// Every (deduplicated) webhook received from now on, in order.
+	///
+	/// Every stream returned by this method gets every webhook. Webhooks are only kept for streams that haven't been dropped.
+	pub fn events(&self) -> impl Stream<Item = Response> + Send + Unpin {
+		self.subscribe(false)
+	}
+
+	/// The predictions that complete (succeed, fail or are canceled) from now on, as reported by their last webhook.
+	///
+	/// Every stream returned by this method gets every prediction. Webhooks are only kept for streams that haven't been dropped.
+	pub fn completed(&self) -> impl Stream<Item = Response> + Send + Unpin {
+		self.subscribe(true)
+	}
+
+	fn subscribe(&self, completed_only: bool) -> impl Stream<Item = Response> + Send + Unpin {
+		let (sender, receiver) = flume::unbounded();
+		self.inner.subscribers.lock().unwrap().push(Subscriber {
+			completed_only,
+			sender,
+		});
+
+		receiver.into_stream()
+	}
+
+	async fn respond<B>(
+		inner: Option<Arc<Inner>>,
+		req: Request<B>,
+	) -> Result<http::Response<String>, Infallible>
+	where
+		B: hyper::body::HttpBody + Send + 'static,
+		B::Data: Send,
+		B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+	{
+		let status = match inner {
+			None => StatusCode::SERVICE_UNAVAILABLE,
+			Some(inner) => {
+				let (parts, body) = req.into_parts();
+
+				match hyper::body::to_bytes(Limited::new(body, inner.max_body_size)).await {
+					Ok(body) => inner.handle(&parts, &body),
+					Err(error) if error.is::<LengthLimitError>() => StatusCode::PAYLOAD_TOO_LARGE,
+					Err(_) => StatusCode::BAD_REQUEST,
+				}
+			},
+		};
+
+		let mut response = http::Response::new(String::new());
+		*response.status_mut() = status;
+		Ok(response)
+	}
+
+	fn configure(self, f: impl FnOnce(&mut Inner)) -> Self {
+		let mut inner = Arc::try_unwrap(self.inner)
+			.unwrap_or_else(|_| panic!("WebhookReceiver must be configured before it's cloned"));
+		f(&mut inner);
+
+		Self {
+			inner: Arc::new(inner),
+		}
+	}
+}
+
+impl Inner {
+	fn handle(&self, parts: &http::request::Parts, body: &[u8]) -> StatusCode {
+		if parts.method != Method::POST {
+			return StatusCode::METHOD_NOT_ALLOWED;
+		}
+
+		if !self.is_authorized(&parts.headers, body) {
+			return StatusCode::UNAUTHORIZED;
+		}
+
+		let Ok(response) = serde_json::from_slice::<Response>(body) else {
+			return StatusCode::BAD_REQUEST;
+		};
+
+		let completed = matches!(
+			response.status,
+			Status::Succeeded | Status::Failed | Status::Canceled
+		);
+
+		if let (true, Some(id)) = (completed, &response.id) {
+			let mut seen = self.seen.lock().unwrap();
+			if !seen.set.insert((id.clone(), response.status)) {
+				return StatusCode::OK;
+			}
+
+			seen.order.push_back((id.clone(), response.status));
+			if seen.order.len() > MAX_SEEN {
+				let oldest = seen.order.pop_front().unwrap();
+				seen.set.remove(&oldest);
+			}
+		}
+
+		// Streams that were dropped are forgotten, so their webhooks don't pile up.
+		self.subscribers.lock().unwrap().retain(|subscriber| {
+			if subscriber.completed_only && !completed {
+				return !subscriber.sender.is_disconnected();
+			}
+
+			subscriber.sender.send(response.clone()).is_ok()
+		});
+
+		StatusCode::OK
+	}
+
+	fn is_authorized(&self, headers: &http::HeaderMap, body: &[u8]) -> bool {
+		let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+		if let Some(token) = &self.token {
+			let bearer =
+				header(AUTHORIZATION.as_str()).and_then(|value| value.strip_prefix("Bearer "));
+			if !bearer.is_some_and(|bearer| secrets_match(bearer.as_bytes(), token.as_bytes())) {
+				return false;
+			}
+		}
+
+		if let Some(secret) = &self.secret {
+			let (Some(timestamp), Some(signature)) =
+				(header(TIMESTAMP_HEADER), header(SIGNATURE_HEADER))
+			else {
+				return false;
+			};
+
+			let (Ok(timestamp), Ok(expected)) = (timestamp.parse::<i64>(), hex::decode(signature))
+			else {
+				return false;
+			};
+			if (Utc::now().timestamp() - timestamp).abs() > SIGNATURE_TOLERANCE {
+				return false;
+			}
+
+			if signing_mac(secret, timestamp, body)
+				.verify_slice(&expected)
+				.is_err()
+			{
+				return false;
+			}
+		}
+
+		true
+	}
+}
+
+impl<B> Service<Request<B>> for WebhookReceiver
+where
+	B: hyper::body::HttpBody + Send + 'static,
+	B::Data: Send,
+	B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+	type Error = Infallible;
+	type Response = http::Response<String>;
+	type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+	fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn call(&mut self, req: Request<B>) -> Self::Future {
+		Box::pin(Self::respond(Some(self.inner.clone()), req))
+	}
+}
+
+impl Drop for Inner {
+	fn drop(&mut self) {
+		if let Some(server) = self.server.get_mut().unwrap().take() {
+			server.abort();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::future::poll_fn;
+
+	async fn deliver(
+		receiver: &mut WebhookReceiver,
+		response: &Response,
+		headers: &[(&str, String)],
+	) -> StatusCode {
+		let body = serde_json::to_vec(response).unwrap();
+		let mut request = Request::post("/webhook");
+		for (name, value) in headers {
+			request = request.header(*name, value);
+		}
+
+		receiver
+			.call(request.body(hyper::Body::from(body)).unwrap())
+			.await
+			.unwrap()
+			.status()
+	}
+
+	async fn next(stream: &mut (impl Stream<Item = Response> + Unpin)) -> Option<Response> {
+		poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+	}
+
+	#[tokio::test]
+	async fn webhooks_are_verified_and_deduplicated() {
+		let mut receiver = WebhookReceiver::new()
+			.with_token("token")
+			.with_signing_secret("secret");
+		let mut completed = receiver.completed();
+
+		let response = Response {
+			id: Some("abc".to_string()),
+			status: Status::Succeeded,
+			..Response::default()
+		};
+		let timestamp = Utc::now().timestamp();
+		let headers = [
+			("Authorization", "Bearer token".to_string()),
+			(TIMESTAMP_HEADER, timestamp.to_string()),
+			(
+				SIGNATURE_HEADER,
+				sign(
+					b"secret",
+					timestamp,
+					&serde_json::to_vec(&response).unwrap(),
+				),
+			),
+		];
+
+		assert_eq!(
+			deliver(&mut receiver, &response, &headers[..2]).await,
+			StatusCode::UNAUTHORIZED
+		);
+		for forged in ["00".repeat(32), "not hex".to_string()] {
+			let mut headers = headers.clone();
+			headers[2].1 = forged;
+			assert_eq!(
+				deliver(&mut receiver, &response, &headers).await,
+				StatusCode::UNAUTHORIZED
+			);
+		}
+		assert_eq!(
+			deliver(&mut receiver, &response, &headers).await,
+			StatusCode::OK
+		);
+		assert_eq!(
+			deliver(&mut receiver, &response, &headers).await,
+			StatusCode::OK
+		);
+
+		assert_eq!(
+			next(&mut completed).await.unwrap().id.as_deref(),
+			Some("abc")
+		);
+		drop(receiver);
+		assert!(next(&mut completed).await.is_none());
+	}
+
+	#[tokio::test]
+	async fn only_completion_webhooks_are_deduplicated() {
+		let mut receiver = WebhookReceiver::new();
+		let (mut events, mut also_events) = (receiver.events(), receiver.events());
+
+		let processing = Response {
+			id: Some("abc".to_string()),
+			status: Status::Processing,
+			..Response::default()
+		};
+		let succeeded = Response {
+			status: Status::Succeeded,
+			..processing.clone()
+		};
+		for response in [&processing, &processing, &succeeded, &succeeded] {
+			assert_eq!(deliver(&mut receiver, response, &[]).await, StatusCode::OK);
+		}
+		drop(receiver);
+
+		for stream in [&mut events, &mut also_events] {
+			let mut statuses = Vec::new();
+			while let Some(response) = next(stream).await {
+				statuses.push(response.status);
+			}
+
+			assert_eq!(
+				statuses,
+				[Status::Processing, Status::Processing, Status::Succeeded]
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn oversized_webhooks_are_rejected() {
+		let mut receiver = WebhookReceiver::new().with_max_body_size(1024);
+		let response = Response {
+			id: Some("abc".to_string()),
+			status: Status::Succeeded,
+			..Response::default()
+		};
+		assert_eq!(deliver(&mut receiver, &response, &[]).await, StatusCode::OK);
+
+		let response = Response {
+			output: Some(serde_json::Value::String("a".repeat(1024))),
+			..response
+		};
+		assert_eq!(
+			deliver(&mut receiver, &response, &[]).await,
+			StatusCode::PAYLOAD_TOO_LARGE
+		);
+	}
+
+	#[tokio::test]
+	async fn receivers_can_listen_on_their_own() {
+		let receiver = WebhookReceiver::new().start().unwrap();
+		let mut events = receiver.events();
+
+		let response = Response {
+			id: Some("abc".to_string()),
+			..Response::default()
+		};
+		let status = hyper::Client::new()
+			.request(
+				Request::post(receiver.url().unwrap().as_str())
+					.body(hyper::Body::from(serde_json::to_vec(&response).unwrap()))
+					.unwrap(),
+			)
+			.await
+			.unwrap()
+			.status();
+
+		assert_eq!(status, StatusCode::OK);
+		assert_eq!(next(&mut events).await.unwrap().id.as_deref(), Some("abc"));
+	}
+
+	#[tokio::test]
+	async fn dropped_streams_are_forgotten() {
+		let mut receiver = WebhookReceiver::new();
+		drop((receiver.events(), receiver.completed()));
+
+		let response = Response {
+			id: Some("abc".to_string()),
+			..Response::default()
+		};
+		assert_eq!(deliver(&mut receiver, &response, &[]).await, StatusCode::OK);
+		assert!(receiver.inner.subscribers.lock().unwrap().is_empty());
+	}
+}
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use cog_rust::{Cog, Path};
+use cog_rust::{Cog, Path, SetupContext};
 use schemars::JsonSchema;
 
 #[derive(serde::Deserialize, JsonSchema)]
@@ -16,7 +16,7 @@ impl Cog for BlurModel {
 	type Request = ModelRequest;
 	type Response = Path;
 
-	async fn setup() -> Result<Self> {
+	async fn setup(_: SetupContext) -> Result<Self> {
 		Ok(Self {})
 	}
 
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use cog_rust::Cog;
+use cog_rust::{Cog, SetupContext};
 use schemars::JsonSchema;
 
 #[derive(serde::Deserialize, JsonSchema)]
@@ -16,7 +16,7 @@ impl Cog for ExampleModel {
 	type Request = ModelRequest;
 	type Response = String;
 
-	async fn setup() -> Result<Self> {
+	async fn setup(_: SetupContext) -> Result<Self> {
 		Ok(Self {
 			prefix: "hello".to_string(),
 		})
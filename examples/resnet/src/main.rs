@@ -1,5 +1,5 @@
 use anyhow::Result;
-use cog_rust::Cog;
+use cog_rust::{Cog, SetupContext, WeightsLocation};
 use schemars::JsonSchema;
 use std::collections::HashMap;
 use tch::{
@@ -22,9 +22,13 @@ impl Cog for ResnetModel {
 	type Request = ModelRequest;
 	type Response = HashMap<String, f64>;
 
-	async fn setup() -> Result<Self> {
+	async fn setup(ctx: SetupContext) -> Result<Self> {
+		let Some(WeightsLocation::Path(weights)) = ctx.weights() else {
+			anyhow::bail!("Expected a local weights directory");
+		};
+
 		let mut vs = VarStore::new(Device::Cpu);
-		vs.load("weights/model.safetensors")?;
+		vs.load(weights.join("model.safetensors"))?;
 		let model = Box::new(resnet50(&vs.root(), imagenet::CLASS_COUNT));
 
 		Ok(Self { model })
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use cog_rust::{Cog, Path};
+use cog_rust::{Cog, Path, SetupContext, WeightsLocation};
 use diffusers::{
 	models::{unet_2d::UNet2DConditionModel, vae::AutoEncoderKL},
 	pipelines::stable_diffusion::{self, StableDiffusionConfig},
@@ -44,17 +44,26 @@ impl Cog for StableDiffusion {
 	type Request = ModelRequest;
 	type Response = Vec<Path>;
 
-	async fn setup() -> Result<Self> {
+	async fn setup(ctx: SetupContext) -> Result<Self> {
+		let Some(WeightsLocation::Path(weights)) = ctx.weights() else {
+			anyhow::bail!("Expected a local weights directory");
+		};
+
+		let weight = |name: &str| weights.join(name).to_string_lossy().into_owned();
+
 		tch::maybe_init_cuda();
 		let sd_config = stable_diffusion::StableDiffusionConfig::v2_1(None, None, None);
 		let device_setup = diffusers::utils::DeviceSetup::new(vec![]);
 		let tokenizer =
-			clip::Tokenizer::create("weights/bpe_simple_vocab_16e6.txt", &sd_config.clip)?;
+			clip::Tokenizer::create(weight("bpe_simple_vocab_16e6.txt"), &sd_config.clip)?;
 		let text_model = sd_config
-			.build_clip_transformer("weights/clip_v2.1.safetensors", device_setup.get("clip"))?;
-		let vae = sd_config.build_vae("weights/vae_v2.1.safetensors", device_setup.get("vae"))?;
-		let unet =
-			sd_config.build_unet("weights/unet_v2.1.safetensors", device_setup.get("unet"), 4)?;
+			.build_clip_transformer(&weight("clip_v2.1.safetensors"), device_setup.get("clip"))?;
+		let vae = sd_config.build_vae(&weight("vae_v2.1.safetensors"), device_setup.get("vae"))?;
+		let unet = sd_config.build_unet(
+			&weight("unet_v2.1.safetensors"),
+			device_setup.get("unet"),
+			4,
+		)?;
 
 		Ok(Self {
 			vae,
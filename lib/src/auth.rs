@@ -0,0 +1,366 @@
+use axum::{
+	body::Body,
+	extract::State,
+	http::{HeaderMap, Method, Request, StatusCode},
+	middleware::Next,
+	response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use cog_core::webhooks::{hmac_sha256, secrets_match, SIGNATURE_TOLERANCE};
+use hmac::Mac;
+use http_body::{LengthLimitError, Limited};
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+};
+
+use crate::errors::HTTPError;
+
+/// The largest body we buffer to verify a request's signature, in bytes.
+const MAX_SIGNED_BODY_SIZE: usize = 100 * 1024 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("Missing or invalid credentials")]
+	Unauthorized,
+
+	#[error("This endpoint requires the admin credential")]
+	Forbidden,
+}
+
+/// What a request needs to be let through, after checking its headers.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Access {
+	Granted,
+	/// The request is signed, and may only go through if [`Auth::verify`] accepts the signature over its body.
+	Signed,
+}
+
+/// Inbound authentication for the server's routes.
+///
+/// Requests are authenticated with one of the static bearer tokens, or by signing them with the shared HMAC secret (each signature is only accepted once).
+/// When an admin token is set, `/shutdown` and prediction (or training) cancellation require it instead.
+#[derive(Debug, Default)]
+pub struct Auth {
+	tokens: Vec<String>,
+	hmac_secret: Option<String>,
+	admin_token: Option<String>,
+	exempt: Vec<String>,
+	/// The signatures accepted within the tolerance window (with their timestamps), so signed requests can't be replayed.
+	seen: Mutex<HashMap<Vec<u8>, i64>>,
+}
+
+impl Auth {
+	pub fn new(
+		tokens: Vec<String>,
+		hmac_secret: Option<String>,
+		admin_token: Option<String>,
+		exempt: Vec<String>,
+	) -> Option<Self> {
+		if tokens.is_empty() && hmac_secret.is_none() && admin_token.is_none() {
+			return None;
+		}
+
+		Some(Self {
+			tokens,
+			hmac_secret,
+			admin_token,
+			exempt,
+			seen: Mutex::default(),
+		})
+	}
+
+	/// Check whether a request may access the given route, using only its headers (so unauthenticated requests are rejected before their body is read).
+	///
+	/// # Errors
+	///
+	/// Returns an error if the request doesn't carry valid credentials (or a signature) for the route.
+	pub fn authorize(&self, path_and_query: &str, headers: &HeaderMap) -> Result<Access, Error> {
+		let path = path_and_query.split('?').next().unwrap_or_default();
+		let bearer = headers
+			.get("Authorization")
+			.and_then(|value| value.to_str().ok())
+			.and_then(|value| value.strip_prefix("Bearer "));
+
+		if let Some(admin_token) = &self.admin_token {
+			let is_admin = bearer
+				.is_some_and(|bearer| secrets_match(bearer.as_bytes(), admin_token.as_bytes()));
+
+			if is_admin_route(path) {
+				return if is_admin {
+					Ok(Access::Granted)
+				} else if bearer.is_some() {
+					Err(Error::Forbidden)
+				} else {
+					Err(Error::Unauthorized)
+				};
+			}
+
+			if is_admin {
+				return Ok(Access::Granted);
+			}
+		}
+
+		if self.is_exempt(path) || (self.tokens.is_empty() && self.hmac_secret.is_none()) {
+			return Ok(Access::Granted);
+		}
+
+		if bearer.is_some_and(|bearer| {
+			self.tokens
+				.iter()
+				.any(|token| secrets_match(bearer.as_bytes(), token.as_bytes()))
+		}) {
+			return Ok(Access::Granted);
+		}
+
+		if self.hmac_secret.is_some() && headers.contains_key("X-Cog-Signature") {
+			return Ok(Access::Signed);
+		}
+
+		Err(Error::Unauthorized)
+	}
+
+	/// Verify the signature of a request that [`Self::authorize`] found to be signed.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the signature is invalid, expired or was already used.
+	pub fn verify(
+		&self,
+		method: &Method,
+		path_and_query: &str,
+		headers: &HeaderMap,
+		body: &[u8],
+	) -> Result<(), Error> {
+		let (timestamp, signature) = self
+			.hmac_secret
+			.as_ref()
+			.and_then(|secret| verify_signature(secret, method, path_and_query, headers, body))
+			.ok_or(Error::Unauthorized)?;
+
+		let mut seen = self.seen.lock().unwrap();
+		seen.retain(|_, seen| (Utc::now().timestamp() - *seen).abs() <= SIGNATURE_TOLERANCE);
+		let replayed = seen.insert(signature, timestamp).is_some();
+		drop(seen);
+
+		if replayed {
+			return Err(Error::Unauthorized);
+		}
+
+		Ok(())
+	}
+
+	fn is_exempt(&self, path: &str) -> bool {
+		self.exempt.iter().any(|exempt| {
+			exempt
+				.strip_suffix('*')
+				.map_or_else(|| path == exempt, |prefix| path.starts_with(prefix))
+		})
+	}
+}
+
+/// Reject requests that aren't authorized by the configured [`Auth`].
+pub async fn middleware(
+	State(auth): State<Arc<Auth>>,
+	req: Request<Body>,
+	next: Next<Body>,
+) -> Response {
+	let path_and_query = req
+		.uri()
+		.path_and_query()
+		.map_or_else(|| req.uri().path(), |path| path.as_str())
+		.to_string();
+
+	match auth.authorize(&path_and_query, req.headers()) {
+		Ok(Access::Granted) => return next.run(req).await,
+		Ok(Access::Signed) => {},
+		Err(error) => {
+			tracing::debug!("Rejected request to {}: {error}", req.uri());
+			return HTTPError::from(error).into_response();
+		},
+	}
+
+	// Signatures cover the request body, so we need to buffer it (up to a limit) before passing it on.
+	let (parts, body) = req.into_parts();
+	let body = match hyper::body::to_bytes(Limited::new(body, MAX_SIGNED_BODY_SIZE)).await {
+		Ok(body) => body,
+		Err(error) if error.is::<LengthLimitError>() => {
+			return HTTPError::new("The request body is too large to verify its signature")
+				.with_status(StatusCode::PAYLOAD_TOO_LARGE)
+				.into_response()
+		},
+		Err(error) => {
+			return HTTPError::new(&error.to_string())
+				.with_status(StatusCode::BAD_REQUEST)
+				.into_response()
+		},
+	};
+
+	if let Err(error) = auth.verify(&parts.method, &path_and_query, &parts.headers, &body) {
+		tracing::debug!("Rejected request to {}: {error}", parts.uri);
+
+		return HTTPError::from(error).into_response();
+	}
+
+	next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+fn is_admin_route(path: &str) -> bool {
+	path == "/shutdown"
+		|| ((path.starts_with("/predictions/") || path.starts_with("/trainings/"))
+			&& path.ends_with("/cancel"))
+}
+
+/// Verify the `X-Cog-Signature` of a request: a hex-encoded HMAC-SHA256 of `{timestamp}.{method}.{path_and_query}.{body}`, where the timestamp comes from `X-Cog-Timestamp`.
+///
+/// Returns the timestamp and (decoded) signature of validly signed requests.
+fn verify_signature(
+	secret: &str,
+	method: &Method,
+	path_and_query: &str,
+	headers: &HeaderMap,
+	body: &[u8],
+) -> Option<(i64, Vec<u8>)> {
+	let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+	let signature = hex::decode(header("X-Cog-Signature")?).ok()?;
+	let timestamp = header("X-Cog-Timestamp")?
+		.parse::<i64>()
+		.ok()
+		.filter(|timestamp| (Utc::now().timestamp() - timestamp).abs() <= SIGNATURE_TOLERANCE)?;
+
+	let mut mac = hmac_sha256(secret.as_bytes());
+	mac.update(format!("{timestamp}.{method}.{path_and_query}.").as_bytes());
+	mac.update(body);
+
+	mac.verify_slice(&signature).ok()?;
+	Some((timestamp, signature))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use axum::http::{HeaderName, HeaderValue};
+
+	fn headers(pairs: &[(&'static str, String)]) -> HeaderMap {
+		pairs
+			.iter()
+			.map(|(name, value)| {
+				(
+					HeaderName::from_static(name),
+					HeaderValue::from_str(value).unwrap(),
+				)
+			})
+			.collect()
+	}
+
+	#[test]
+	fn tokens_and_admin_routes_are_enforced() {
+		let auth = Auth::new(
+			vec!["token".to_string()],
+			None,
+			Some("admin".to_string()),
+			vec!["/health-check".to_string()],
+		)
+		.unwrap();
+		let authorize = |path: &str, token: Option<&str>| {
+			let headers = token.map_or_else(HeaderMap::new, |token| {
+				headers(&[("authorization", format!("Bearer {token}"))])
+			});
+
+			auth.authorize(path, &headers)
+		};
+
+		assert_eq!(authorize("/health-check", None).unwrap(), Access::Granted);
+		assert_eq!(
+			authorize("/predictions", Some("token")).unwrap(),
+			Access::Granted
+		);
+		assert_eq!(
+			authorize("/predictions", Some("admin")).unwrap(),
+			Access::Granted
+		);
+		assert!(matches!(
+			authorize("/predictions", Some("wrong")),
+			Err(Error::Unauthorized)
+		));
+		assert!(matches!(
+			authorize("/predictions/abc/cancel", Some("token")),
+			Err(Error::Forbidden)
+		));
+		assert!(matches!(
+			authorize("/trainings/abc/cancel", Some("token")),
+			Err(Error::Forbidden)
+		));
+		assert_eq!(
+			authorize("/shutdown", Some("admin")).unwrap(),
+			Access::Granted
+		);
+	}
+
+	#[test]
+	fn signed_requests_are_verified() {
+		let auth = Auth::new(vec![], Some("secret".to_string()), None, vec![]).unwrap();
+		let timestamp = Utc::now().timestamp().to_string();
+		let body = br#"{"input":{}}"#;
+
+		let mut mac = hmac_sha256(b"secret");
+		mac.update(format!("{timestamp}.POST./predictions.").as_bytes());
+		mac.update(body);
+		let signature = hex::encode(mac.finalize().into_bytes());
+
+		let signed = headers(&[
+			("x-cog-timestamp", timestamp),
+			("x-cog-signature", signature),
+		]);
+
+		assert_eq!(
+			auth.authorize("/predictions", &signed).unwrap(),
+			Access::Signed
+		);
+		assert!(auth
+			.verify(&Method::POST, "/predictions", &signed, b"{}")
+			.is_err());
+		assert!(auth
+			.verify(&Method::POST, "/predictions", &signed, body)
+			.is_ok());
+
+		// The same signed request can't be replayed.
+		assert!(auth
+			.verify(&Method::POST, "/predictions", &signed, body)
+			.is_err());
+		assert!(auth.authorize("/predictions", &HeaderMap::new()).is_err());
+	}
+
+	#[tokio::test]
+	async fn bodies_are_only_buffered_for_signed_requests() {
+		use axum::{middleware::from_fn_with_state, routing::post, Router};
+		use tower::ServiceExt;
+
+		let auth = Arc::new(Auth::new(vec![], Some("secret".to_string()), None, vec![]).unwrap());
+		let router = Router::new()
+			.route("/predictions", post(|| async { "ok" }))
+			.layer(from_fn_with_state(auth, middleware));
+
+		// A body that never ends: unauthenticated requests must be rejected without reading it.
+		let (_sender, body) = Body::channel();
+		let response = router
+			.clone()
+			.oneshot(Request::post("/predictions").body(body).unwrap())
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+		let response = router
+			.oneshot(
+				Request::post("/predictions")
+					.header("X-Cog-Timestamp", Utc::now().timestamp().to_string())
+					.header("X-Cog-Signature", "00")
+					.body(Body::from(vec![b'a'; MAX_SIGNED_BODY_SIZE + 1]))
+					.unwrap(),
+			)
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+	}
+}
@@ -0,0 +1,428 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use reqwest::{
+	blocking::RequestBuilder,
+	header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+	StatusCode,
+};
+use sha2::{Digest, Sha256};
+use std::{
+	collections::HashMap,
+	fs::File,
+	io::Write,
+	path::{Path, PathBuf},
+	sync::Mutex,
+	time::SystemTime,
+};
+use uuid::Uuid;
+
+/// An on-disk cache for downloaded inputs.
+///
+/// Files are stored once per unique content (named by their SHA-256) and indexed by the exact URL they were downloaded from, query string included.
+/// So URLs that change with every request (like presigned URLs, whose signature and expiry are part of the query) never hit the cache, though identical content is still only stored once.
+/// Cached entries are revalidated with the server using their `ETag`/`Last-Modified` headers, and the least recently used ones are evicted once the cache grows past its maximum size.
+#[derive(Debug)]
+pub struct DownloadCache {
+	root: PathBuf,
+	max_size: u64,
+	index: Mutex<HashMap<String, Entry>>,
+	/// How many fetches are using each blob, which keeps it from being evicted.
+	pinned: Mutex<HashMap<String, usize>>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct Entry {
+	sha256: String,
+	size: u64,
+	etag: Option<String>,
+	last_modified: Option<String>,
+	/// Modification time of the blob when it was cached, used to detect files modified in place.
+	blob_modified: SystemTime,
+	last_used: DateTime<Utc>,
+}
+
+impl DownloadCache {
+	/// Open (or create) a cache in the given directory, holding at most `max_size` bytes.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the cache directory cannot be created.
+	pub fn new(root: PathBuf, max_size: u64) -> Result<Self> {
+		std::fs::create_dir_all(root.join("blobs"))?;
+
+		let index = std::fs::read(root.join("index.json"))
+			.ok()
+			.and_then(|index| serde_json::from_slice::<HashMap<String, Entry>>(&index).ok())
+			.unwrap_or_default();
+
+		let cache = Self {
+			root,
+			max_size,
+			index: Mutex::new(index),
+			pinned: Mutex::default(),
+		};
+		cache
+			.index
+			.lock()
+			.unwrap()
+			.retain(|_, entry| cache.is_intact(entry));
+
+		Ok(cache)
+	}
+
+	/// Download `url` into `destination`, reusing the cached copy if the server confirms it is still current.
+	///
+	/// The destination always gets its own copy of the cached file, so the model can modify or remove it without affecting the cache.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the request fails or the file cannot be written.
+	pub fn fetch(&self, url: &str, request: RequestBuilder, destination: &Path) -> Result<()> {
+		// The cached blob is pinned before the index is unlocked, so it can't be evicted before it's copied.
+		let index = self.index.lock().unwrap();
+		let cached = index
+			.get(url)
+			.cloned()
+			.filter(|entry| self.is_intact(entry));
+		let _pinned = cached.as_ref().map(|entry| self.pin(&entry.sha256));
+		drop(index);
+
+		let mut request = request;
+		if let Some(entry) = &cached {
+			if let Some(etag) = &entry.etag {
+				request = request.header(IF_NONE_MATCH, etag);
+			}
+			if let Some(last_modified) = &entry.last_modified {
+				request = request.header(IF_MODIFIED_SINCE, last_modified);
+			}
+		}
+
+		let mut response = request.send()?;
+		if let Some(entry) = cached.filter(|_| response.status() == StatusCode::NOT_MODIFIED) {
+			tracing::debug!("Using cached download for {url}");
+			std::fs::copy(self.blob_path(&entry.sha256), destination)?;
+			self.insert(url.to_string(), entry);
+
+			return Ok(());
+		}
+		response = response.error_for_status()?;
+
+		let header = |name| {
+			response
+				.headers()
+				.get(name)
+				.and_then(|value| value.to_str().ok())
+				.map(ToString::to_string)
+		};
+		let (etag, last_modified) = (header(ETAG), header(LAST_MODIFIED));
+
+		let staging = self.root.join(format!("download-{}", Uuid::new_v4()));
+		let mut writer = HashingWriter {
+			file: File::create(&staging)?,
+			hasher: Sha256::new(),
+		};
+		let size = response.copy_to(&mut writer)?;
+		let sha256 = hex::encode(writer.hasher.finalize());
+
+		// Identical content downloaded from a different url (or re-downloaded without validators) is only stored once.
+		let _pinned = self.pin(&sha256);
+		let blob = self.blob_path(&sha256);
+		if blob.exists() {
+			std::fs::remove_file(&staging)?;
+		} else {
+			std::fs::rename(&staging, &blob)?;
+		}
+		std::fs::copy(&blob, destination)?;
+
+		self.insert(
+			url.to_string(),
+			Entry {
+				size,
+				etag,
+				sha256,
+				last_modified,
+				last_used: Utc::now(),
+				blob_modified: blob.metadata()?.modified()?,
+			},
+		);
+
+		Ok(())
+	}
+
+	/// Record an entry as the most recently used one, then evict old entries until the cache fits within its maximum size.
+	fn insert(&self, url: String, mut entry: Entry) {
+		let mut index = self.index.lock().unwrap();
+		entry.last_used = Utc::now();
+		index.insert(url, entry);
+
+		let mut blobs = HashMap::<String, (u64, DateTime<Utc>)>::new();
+		for entry in index.values() {
+			let blob = blobs
+				.entry(entry.sha256.clone())
+				.or_insert((entry.size, entry.last_used));
+			blob.1 = blob.1.max(entry.last_used);
+		}
+
+		let mut total = blobs.values().map(|(size, _)| size).sum::<u64>();
+		let mut blobs = blobs.into_iter().collect::<Vec<_>>();
+		blobs.sort_by_key(|(_, (_, last_used))| *last_used);
+
+		let pinned = self.pinned.lock().unwrap();
+		for (sha256, (size, _)) in blobs {
+			if total <= self.max_size {
+				break;
+			}
+
+			// Blobs being copied are left for a later eviction.
+			if pinned.contains_key(&sha256) {
+				continue;
+			}
+
+			tracing::debug!("Evicting cached download {sha256}");
+			index.retain(|_, entry| entry.sha256 != sha256);
+			if let Err(error) = std::fs::remove_file(self.blob_path(&sha256)) {
+				tracing::error!("Failed to remove cached download: {error}");
+			}
+			total -= size;
+		}
+		drop(pinned);
+
+		// Keep the lock while persisting, so the index file is never replaced by an older version.
+		let persisted = self.persist(&index);
+		drop(index);
+
+		if let Err(error) = persisted {
+			tracing::error!("Failed to persist download cache index: {error}");
+		}
+	}
+
+	/// Write the index to a temporary file and move it into place, so other servers sharing the cache never read a partially written index.
+	fn persist(&self, index: &HashMap<String, Entry>) -> Result<()> {
+		let staging = self.root.join(format!("index-{}.json", Uuid::new_v4()));
+		std::fs::write(&staging, serde_json::to_vec(index)?)?;
+
+		if let Err(error) = std::fs::rename(&staging, self.root.join("index.json")) {
+			let _ = std::fs::remove_file(&staging);
+			return Err(error.into());
+		}
+
+		Ok(())
+	}
+
+	/// Whether the cached file still exists and hasn't been modified since it was downloaded.
+	fn is_intact(&self, entry: &Entry) -> bool {
+		self.blob_path(&entry.sha256)
+			.metadata()
+			.is_ok_and(|metadata| {
+				metadata.len() == entry.size
+					&& metadata.modified().ok() == Some(entry.blob_modified)
+			})
+	}
+
+	fn blob_path(&self, sha256: &str) -> PathBuf {
+		self.root.join("blobs").join(sha256)
+	}
+
+	/// Keep the blob from being evicted until the returned guard is dropped.
+	fn pin(&self, sha256: &str) -> Pinned<'_> {
+		*self
+			.pinned
+			.lock()
+			.unwrap()
+			.entry(sha256.to_string())
+			.or_default() += 1;
+
+		Pinned {
+			cache: self,
+			sha256: sha256.to_string(),
+		}
+	}
+}
+
+/// Keeps a blob from being evicted, until it's dropped.
+struct Pinned<'a> {
+	cache: &'a DownloadCache,
+	sha256: String,
+}
+
+impl Drop for Pinned<'_> {
+	fn drop(&mut self) {
+		let mut pinned = self.cache.pinned.lock().unwrap();
+
+		if let Some(count) = pinned.get_mut(&self.sha256) {
+			*count -= 1;
+			if *count == 0 {
+				pinned.remove(&self.sha256);
+			}
+		}
+	}
+}
+
+/// Writes to a file while computing the SHA-256 of everything written.
+struct HashingWriter {
+	file: File,
+	hasher: Sha256,
+}
+
+impl Write for HashingWriter {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		let written = self.file.write(buf)?;
+		self.hasher.update(&buf[..written]);
+
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.file.flush()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::egress::EgressPolicy;
+	use std::{
+		env::temp_dir,
+		io::{BufRead, BufReader},
+		net::TcpListener,
+		sync::{
+			atomic::{AtomicUsize, Ordering},
+			Arc,
+		},
+	};
+
+	/// A client like the input handlers', allowing the loopback addresses the test servers listen on.
+	fn client() -> reqwest::blocking::Client {
+		let egress = Arc::new(EgressPolicy::new(vec![], vec![], true, None));
+
+		reqwest::blocking::ClientBuilder::from(egress.client_builder())
+			.build()
+			.unwrap()
+	}
+
+	/// Serve `body` with an `ETag`, answering conditional requests with a 304. Returns the url and a counter of full responses.
+	fn serve(body: &'static str) -> (String, Arc<AtomicUsize>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!(
+			"http://{}/model.safetensors",
+			listener.local_addr().unwrap()
+		);
+		let downloads = Arc::new(AtomicUsize::new(0));
+
+		let counter = downloads.clone();
+		std::thread::spawn(move || {
+			for mut stream in listener.incoming().flatten() {
+				let mut reader = BufReader::new(stream.try_clone().unwrap());
+				let mut not_modified = false;
+				loop {
+					let mut line = String::new();
+					reader.read_line(&mut line).unwrap();
+					if line.trim().is_empty() {
+						break;
+					}
+					not_modified |= line.to_lowercase().starts_with("if-none-match: \"v1\"");
+				}
+
+				let response = if not_modified {
+					"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n"
+						.to_string()
+				} else {
+					counter.fetch_add(1, Ordering::SeqCst);
+					format!(
+						"HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+						body.len()
+					)
+				};
+				stream.write_all(response.as_bytes()).unwrap();
+			}
+		});
+
+		(url, downloads)
+	}
+
+	#[test]
+	fn cached_downloads_are_revalidated_and_shared() {
+		let root = temp_dir().join(format!("cog-cache-{}", Uuid::new_v4()));
+		let cache = DownloadCache::new(root.clone(), 1024).unwrap();
+		let (url, downloads) = serve("weights");
+		let client = client();
+
+		let first = temp_dir().join(Uuid::new_v4().to_string());
+		let second = temp_dir().join(Uuid::new_v4().to_string());
+		cache.fetch(&url, client.get(&url), &first).unwrap();
+		std::fs::remove_file(&first).unwrap();
+		cache.fetch(&url, client.get(&url), &second).unwrap();
+
+		assert_eq!(downloads.load(Ordering::SeqCst), 1);
+		assert_eq!(std::fs::read_to_string(&second).unwrap(), "weights");
+
+		// Writing to an input (even without changing its size) leaves the cached file untouched.
+		std::fs::write(&second, "WEIGHTS").unwrap();
+		cache.fetch(&url, client.get(&url), &first).unwrap();
+		assert_eq!(downloads.load(Ordering::SeqCst), 1);
+		assert_eq!(std::fs::read_to_string(&first).unwrap(), "weights");
+		std::fs::remove_file(&first).unwrap();
+
+		// The index survives restarts.
+		let cache = DownloadCache::new(root.clone(), 1024).unwrap();
+		assert!(cache.index.lock().unwrap().contains_key(&url));
+
+		std::fs::remove_file(second).unwrap();
+		std::fs::remove_dir_all(root).unwrap();
+	}
+
+	#[test]
+	fn least_recently_used_entries_are_evicted() {
+		let root = temp_dir().join(format!("cog-cache-{}", Uuid::new_v4()));
+		let cache = DownloadCache::new(root.clone(), 10).unwrap();
+		let client = client();
+
+		let (first, _) = serve("123456");
+		let (second, _) = serve("abcdef");
+		for url in [&first, &second] {
+			let destination = temp_dir().join(Uuid::new_v4().to_string());
+			cache.fetch(url, client.get(url), &destination).unwrap();
+			std::fs::remove_file(destination).unwrap();
+		}
+
+		let index = cache.index.lock().unwrap().clone();
+		assert!(!index.contains_key(&first));
+		assert!(index.contains_key(&second));
+		assert_eq!(std::fs::read_dir(root.join("blobs")).unwrap().count(), 1);
+
+		std::fs::remove_dir_all(root).unwrap();
+	}
+
+	#[test]
+	fn blobs_in_use_are_not_evicted() {
+		let root = temp_dir().join(format!("cog-cache-{}", Uuid::new_v4()));
+		let cache = DownloadCache::new(root.clone(), 10).unwrap();
+		let client = client();
+
+		let (first, _) = serve("123456");
+		let (second, _) = serve("abcdef");
+		let destination = temp_dir().join(Uuid::new_v4().to_string());
+		cache
+			.fetch(&first, client.get(&first), &destination)
+			.unwrap();
+		std::fs::remove_file(&destination).unwrap();
+
+		let sha256 = cache.index.lock().unwrap()[&first].sha256.clone();
+		let pinned = cache.pin(&sha256);
+		cache
+			.fetch(&second, client.get(&second), &destination)
+			.unwrap();
+		std::fs::remove_file(&destination).unwrap();
+		assert!(cache.blob_path(&sha256).exists());
+
+		// Once the blob isn't used anymore, the next insert evicts it.
+		drop(pinned);
+		cache
+			.fetch(&second, client.get(&second), &destination)
+			.unwrap();
+		std::fs::remove_file(destination).unwrap();
+		assert!(!cache.blob_path(&sha256).exists());
+
+		std::fs::remove_dir_all(root).unwrap();
+	}
+}
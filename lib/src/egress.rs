@@ -0,0 +1,443 @@
+use hyper::client::connect::dns::Name;
+use ipnet::IpNet;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use std::{
+	future::Future,
+	net::{IpAddr, Ipv4Addr},
+	str::FromStr,
+	sync::Arc,
+};
+use url::{Host, Url};
+
+tokio::task_local! {
+	/// The policy of the server whose model is being set up, for requests made during `setup()`.
+	static SETUP_POLICY: Arc<EgressPolicy>;
+}
+
+/// How many redirects we follow before giving up.
+const MAX_REDIRECTS: usize = 10;
+
+/// The schemes allowed when no list is configured: the ones with a built-in input handler.
+const DEFAULT_SCHEMES: &[&str] = &["http", "https", "data", "file", "s3", "gs"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("URLs with the {0} scheme are not allowed")]
+	Scheme(String),
+
+	#[error("Requests to {0} are not allowed")]
+	Host(String),
+
+	#[error("Requests to {0} are not allowed")]
+	Address(IpAddr),
+}
+
+/// A host name (optionally a `*.` wildcard), IP address or CIDR range.
+#[derive(Debug, Clone)]
+pub enum Rule {
+	Host(String),
+	Network(IpNet),
+}
+
+impl Rule {
+	fn matches_host(&self, host: &str) -> bool {
+		match self {
+			Self::Host(rule) => rule.strip_prefix("*.").map_or_else(
+				|| host.eq_ignore_ascii_case(rule),
+				|domain| {
+					host.to_lowercase()
+						.strip_suffix(domain)
+						.is_some_and(|subdomain| subdomain.ends_with('.'))
+				},
+			),
+			Self::Network(_) => false,
+		}
+	}
+
+	fn matches_addr(&self, addr: IpAddr) -> bool {
+		match self {
+			Self::Network(network) => network.contains(&addr),
+			Self::Host(_) => false,
+		}
+	}
+}
+
+impl FromStr for Rule {
+	type Err = String;
+
+	fn from_str(rule: &str) -> Result<Self, Self::Err> {
+		let rule = rule.trim();
+		if rule.is_empty() {
+			return Err("Empty egress rule".to_string());
+		}
+
+		if let Ok(network) = rule.parse::<IpNet>() {
+			return Ok(Self::Network(network));
+		}
+
+		if let Ok(addr) = rule.parse::<IpAddr>() {
+			return Ok(Self::Network(addr.into()));
+		}
+
+		Ok(Self::Host(rule.to_lowercase()))
+	}
+}
+
+/// Restricts which URLs can be fetched as inputs or sent webhooks, to prevent requests to internal services.
+///
+/// By default only the built-in schemes are allowed, and HTTP requests to private, loopback, link-local and other reserved addresses are blocked.
+#[derive(Debug, Default)]
+pub struct EgressPolicy {
+	allow: Vec<Rule>,
+	deny: Vec<Rule>,
+	allow_private: bool,
+	schemes: Option<Vec<String>>,
+}
+
+impl EgressPolicy {
+	pub const fn new(
+		allow: Vec<Rule>,
+		deny: Vec<Rule>,
+		allow_private: bool,
+		schemes: Option<Vec<String>>,
+	) -> Self {
+		Self {
+			allow,
+			deny,
+			allow_private,
+			schemes,
+		}
+	}
+
+	/// Check whether the given URL may be requested.
+	///
+	/// Host names that resolve to a blocked address are only caught by [`Self::check_resolved`], or when connecting with the clients returned from [`Self::client_builder`].
+	///
+	/// # Errors
+	///
+	/// Returns an error if the scheme, host or address of the URL is not allowed.
+	pub fn check(&self, url: &Url) -> Result<(), Error> {
+		let allowed = self.schemes.as_ref().map_or_else(
+			|| DEFAULT_SCHEMES.contains(&url.scheme()),
+			|schemes| {
+				schemes
+					.iter()
+					.any(|scheme| scheme.eq_ignore_ascii_case(url.scheme()))
+			},
+		);
+		if !allowed {
+			return Err(Error::Scheme(url.scheme().to_string()));
+		}
+
+		if !matches!(url.scheme(), "http" | "https") {
+			return Ok(());
+		}
+
+		match url.host() {
+			None => Ok(()),
+			Some(Host::Ipv4(addr)) => self.check_addr(addr.into(), false),
+			Some(Host::Ipv6(addr)) => self.check_addr(addr.into(), false),
+			Some(Host::Domain(host)) => {
+				if self.deny.iter().any(|rule| rule.matches_host(host)) {
+					return Err(Error::Host(host.to_string()));
+				}
+
+				if self.allow.iter().any(|rule| rule.matches_host(host)) {
+					return Ok(());
+				}
+
+				// If only host names are allowed, we don't need to resolve the host to know it's not one of them.
+				let has_networks = self
+					.allow
+					.iter()
+					.any(|rule| matches!(rule, Rule::Network(_)));
+				let is_local = host.eq_ignore_ascii_case("localhost")
+					|| host.to_lowercase().ends_with(".localhost");
+				if (!self.allow.is_empty() && !has_networks) || (is_local && !self.allow_private) {
+					return Err(Error::Host(host.to_string()));
+				}
+
+				Ok(())
+			},
+		}
+	}
+
+	/// Check whether the given URL may be requested, also resolving its host to make sure it points at an allowed address.
+	///
+	/// Hosts that don't resolve at all are left for the request itself to fail.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the URL is not allowed, or its host only resolves to addresses that aren't.
+	pub async fn check_resolved(&self, url: &Url) -> Result<(), Error> {
+		self.check(url)?;
+
+		let Some(Host::Domain(host)) = url
+			.host()
+			.filter(|_| matches!(url.scheme(), "http" | "https"))
+		else {
+			return Ok(());
+		};
+
+		let Ok(addrs) = tokio::net::lookup_host((host, 0)).await else {
+			return Ok(());
+		};
+
+		let host_allowed = self.allow.iter().any(|rule| rule.matches_host(host));
+		let mut results = addrs
+			.map(|addr| self.check_addr(addr.ip(), host_allowed))
+			.peekable();
+		if results.peek().is_some() && !results.any(|result| result.is_ok()) {
+			return Err(Error::Host(host.to_string()));
+		}
+
+		Ok(())
+	}
+
+	/// Run a model's `setup()` with this policy in scope, so the requests it makes (like downloading weights) follow it too.
+	pub async fn scope<F: Future>(self: Arc<Self>, setup: F) -> F::Output {
+		SETUP_POLICY.scope(self, setup).await
+	}
+
+	/// The policy in scope (see [`Self::scope`]), or the default one.
+	pub fn current() -> Arc<Self> {
+		SETUP_POLICY.try_with(Clone::clone).unwrap_or_default()
+	}
+
+	/// A client builder that enforces this policy on every redirect and resolved address.
+	///
+	/// Proxies configured through the environment are ignored, since they would resolve (and connect to) hosts on our behalf.
+	pub fn client_builder(self: &Arc<Self>) -> reqwest::ClientBuilder {
+		let policy = self.clone();
+
+		reqwest::Client::builder()
+			.no_proxy()
+			.dns_resolver(Arc::new(Resolver(self.clone())))
+			.redirect(reqwest::redirect::Policy::custom(move |attempt| {
+				if attempt.previous().len() >= MAX_REDIRECTS {
+					return attempt.error("Too many redirects");
+				}
+
+				match policy.check(attempt.url()) {
+					Ok(()) => attempt.follow(),
+					Err(error) => attempt.error(error),
+				}
+			}))
+	}
+
+	fn check_addr(&self, addr: IpAddr, host_allowed: bool) -> Result<(), Error> {
+		let addr = match addr {
+			IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+			IpAddr::V4(_) => addr,
+		};
+
+		if self.deny.iter().any(|rule| rule.matches_addr(addr)) {
+			return Err(Error::Address(addr));
+		}
+
+		if host_allowed {
+			return Ok(());
+		}
+
+		let allowed = self.allow.iter().any(|rule| rule.matches_addr(addr));
+		if (!self.allow.is_empty() && !allowed)
+			|| (is_private(addr) && !allowed && !self.allow_private)
+		{
+			return Err(Error::Address(addr));
+		}
+
+		Ok(())
+	}
+}
+
+/// Resolves host names, dropping any address the policy doesn't allow (so a host can't be pointed at an internal address after it was checked).
+struct Resolver(Arc<EgressPolicy>);
+
+impl Resolve for Resolver {
+	fn resolve(&self, name: Name) -> Resolving {
+		let policy = self.0.clone();
+
+		Box::pin(async move {
+			let host = name.as_str();
+			let host_allowed = policy.allow.iter().any(|rule| rule.matches_host(host));
+
+			let addrs = tokio::net::lookup_host((host, 0))
+				.await?
+				.filter(|addr| policy.check_addr(addr.ip(), host_allowed).is_ok())
+				.collect::<Vec<_>>();
+
+			if addrs.is_empty() {
+				return Err(Error::Host(host.to_string()).into());
+			}
+
+			Ok(Box::new(addrs.into_iter()) as Addrs)
+		})
+	}
+}
+
+fn is_private(addr: IpAddr) -> bool {
+	match addr {
+		IpAddr::V4(addr) => is_private_v4(addr),
+		IpAddr::V6(addr) => {
+			let segments = addr.segments();
+			let embedded = |high: u16, low: u16| {
+				is_private_v4(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low)))
+			};
+
+			addr.is_loopback()
+				|| addr.is_unspecified()
+				|| addr.is_multicast()
+				// Unique local (fc00::/7) and link-local (fe80::/10) addresses.
+				|| (segments[0] & 0xfe00) == 0xfc00
+				|| (segments[0] & 0xffc0) == 0xfe80
+				// Documentation (2001:db8::/32).
+				|| (segments[0] == 0x2001 && segments[1] == 0xdb8)
+				// Teredo (2001::/32), which tunnels to an (obfuscated) IPv4 address.
+				|| (segments[0] == 0x2001 && segments[1] == 0)
+				// IPv4-mapped (::ffff:0:0/96) and IPv4-compatible (::/96) addresses.
+				|| addr.to_ipv4().is_some_and(is_private_v4)
+				// NAT64 (64:ff9b::/96), which reaches the embedded IPv4 address.
+				|| (segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] && embedded(segments[6], segments[7]))
+				// 6to4 (2002::/16), which reaches the IPv4 address in the following 32 bits.
+				|| (segments[0] == 0x2002 && embedded(segments[1], segments[2]))
+		},
+	}
+}
+
+/// Whether the address isn't globally reachable (like `Ipv4Addr::is_global`, which isn't stable yet).
+const fn is_private_v4(addr: Ipv4Addr) -> bool {
+	let [a, b, c, _] = addr.octets();
+
+	addr.is_private()
+		|| addr.is_loopback()
+		|| addr.is_link_local()
+		|| addr.is_multicast()
+		|| addr.is_documentation()
+		// "This network" (0.0.0.0/8).
+		|| a == 0
+		// Shared address space (100.64.0.0/10), used for carrier-grade NAT.
+		|| (a == 100 && (b & 0xc0) == 64)
+		// IETF protocol assignments (192.0.0.0/24).
+		|| (a == 192 && b == 0 && c == 0)
+		// Benchmarking (198.18.0.0/15).
+		|| (a == 198 && (b & 0xfe) == 18)
+		// Reserved (240.0.0.0/4), including the broadcast address.
+		|| a >= 240
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn check(policy: &EgressPolicy, url: &str) -> bool {
+		policy.check(&url.parse().unwrap()).is_ok()
+	}
+
+	#[test]
+	fn private_addresses_are_blocked_by_default() {
+		let policy = EgressPolicy::default();
+
+		assert!(check(&policy, "https://example.com/image.png"));
+		assert!(check(&policy, "https://93.184.216.34/image.png"));
+		assert!(!check(&policy, "http://169.254.169.254/latest/meta-data"));
+		assert!(!check(&policy, "http://10.0.0.1:8080"));
+		assert!(!check(&policy, "http://[::ffff:127.0.0.1]"));
+		assert!(!check(&policy, "http://localhost:5000"));
+
+		let policy = EgressPolicy::new(vec![], vec![], true, None);
+		assert!(check(&policy, "http://localhost:5000"));
+	}
+
+	#[test]
+	fn reserved_addresses_are_blocked() {
+		let policy = EgressPolicy::default();
+
+		for addr in [
+			"0.1.2.3",
+			"100.64.0.1",
+			"192.0.0.8",
+			"192.0.2.1",
+			"198.18.0.1",
+			"198.19.255.255",
+			"224.0.0.1",
+			"239.255.255.250",
+			"240.0.0.1",
+			"255.255.255.255",
+			"[ff02::1]",
+			"[2001:db8::1]",
+			"[64:ff9b::a00:1]",
+			"[::127.0.0.1]",
+			"[::a00:1]",
+			"[2002:7f00:1::]",
+			"[2002:a9fe:a9fe::1]",
+			"[2001::1]",
+			"[2001:0:4136:e378:8000:63bf:3fff:fdd2]",
+		] {
+			assert!(
+				!check(&policy, &format!("http://{addr}/")),
+				"{addr} is allowed"
+			);
+		}
+
+		assert!(check(&policy, "http://198.20.0.1/"));
+		assert!(check(&policy, "http://[64:ff9b::808:808]/"));
+		assert!(check(&policy, "http://[::8.8.8.8]/"));
+		assert!(check(&policy, "http://[2002:808:808::1]/"));
+	}
+
+	#[tokio::test]
+	async fn hosts_are_resolved_when_requested() {
+		let policy = EgressPolicy::new(vec![], vec!["127.0.0.0/8".parse().unwrap()], true, None);
+		let url = "http://localhost:5000".parse().unwrap();
+
+		// Only the name is checked, until it's resolved.
+		assert!(policy.check(&url).is_ok());
+		assert!(policy.check_resolved(&url).await.is_err());
+		assert!(policy
+			.check_resolved(&"http://does-not-exist.invalid".parse().unwrap())
+			.await
+			.is_ok());
+	}
+
+	#[test]
+	fn only_built_in_schemes_are_allowed_by_default() {
+		let policy = EgressPolicy::default();
+
+		assert!(check(&policy, "data:,hello"));
+		assert!(check(&policy, "s3://bucket/key"));
+		assert!(!check(&policy, "ftp://example.com/file"));
+		assert!(!check(&policy, "gopher://example.com/"));
+
+		let policy = EgressPolicy::new(vec![], vec![], false, Some(vec!["ftp".to_string()]));
+		assert!(check(&policy, "ftp://example.com/file"));
+		assert!(!check(&policy, "data:,hello"));
+	}
+
+	#[test]
+	fn allow_and_deny_rules_are_applied() {
+		let policy = EgressPolicy::new(
+			vec![
+				"*.example.com".parse().unwrap(),
+				"10.1.0.0/16".parse().unwrap(),
+			],
+			vec!["evil.example.com".parse().unwrap()],
+			false,
+			Some(vec!["https".to_string()]),
+		);
+
+		assert!(check(&policy, "https://cdn.example.com/a.png"));
+		assert!(check(&policy, "https://10.1.2.3/a.png"));
+		assert!(!check(&policy, "https://evil.example.com/a.png"));
+		assert!(!check(&policy, "https://10.2.0.1/a.png"));
+		assert!(!check(&policy, "http://cdn.example.com/a.png"));
+		assert!(!check(&policy, "data:,hello"));
+
+		assert!(policy
+			.check_addr("10.1.0.5".parse().unwrap(), false)
+			.is_ok());
+		assert!(policy
+			.check_addr("8.8.8.8".parse().unwrap(), false)
+			.is_err());
+		assert!(policy.check_addr("10.9.0.5".parse().unwrap(), true).is_ok());
+	}
+}
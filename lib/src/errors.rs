@@ -8,7 +8,9 @@ use cog_core::http::ValidationError;
 use jsonschema::ErrorIterator;
 use serde_json::{json, Value};
 
-use crate::prediction::Error as PredictionError;
+use crate::{
+	auth::Error as AuthError, files::Error as FilesError, prediction::Error as PredictionError,
+};
 
 #[derive(Debug)]
 pub struct HTTPError {
@@ -98,11 +100,41 @@ impl From<PredictionError> for HTTPError {
 				status_code: StatusCode::CONFLICT,
 				detail: serde_json::to_value(e.to_string()).unwrap(),
 			},
+			PredictionError::ShuttingDown | PredictionError::Unhealthy => Self {
+				status_code: StatusCode::SERVICE_UNAVAILABLE,
+				detail: serde_json::to_value(e.to_string()).unwrap(),
+			},
 			PredictionError::Validation(e) => e.into(),
-			PredictionError::NotComplete | PredictionError::Receiver(_) => Self {
+			PredictionError::NotComplete => Self {
 				status_code: StatusCode::INTERNAL_SERVER_ERROR,
 				detail: serde_json::to_value(e.to_string()).unwrap(),
 			},
 		}
 	}
 }
+
+#[allow(clippy::fallible_impl_from)]
+impl From<FilesError> for HTTPError {
+	fn from(e: FilesError) -> Self {
+		Self {
+			status_code: match e {
+				FilesError::NotFound => StatusCode::NOT_FOUND,
+				FilesError::InvalidSignature => StatusCode::FORBIDDEN,
+			},
+			detail: serde_json::to_value(e.to_string()).unwrap(),
+		}
+	}
+}
+
+#[allow(clippy::fallible_impl_from)]
+impl From<AuthError> for HTTPError {
+	fn from(e: AuthError) -> Self {
+		Self {
+			status_code: match e {
+				AuthError::Unauthorized => StatusCode::UNAUTHORIZED,
+				AuthError::Forbidden => StatusCode::FORBIDDEN,
+			},
+			detail: serde_json::to_value(e.to_string()).unwrap(),
+		}
+	}
+}
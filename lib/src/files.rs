@@ -0,0 +1,329 @@
+use anyhow::{bail, Result};
+use chrono::Utc;
+use cog_core::webhooks::hmac_sha256;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+	env::temp_dir,
+	path::PathBuf,
+	sync::Arc,
+	time::{Duration, SystemTime},
+};
+use url::{Position, Url};
+use uuid::Uuid;
+
+use crate::{
+	helpers::{is_path_segment, url_join},
+	shutdown::Shutdown,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Keeps `Path` outputs on local disk and serves them from `/files/{prediction_id}/{name}`.
+///
+/// Every server gets its own directory, which is removed when it shuts down.
+#[derive(Debug)]
+pub struct FileServer {
+	root: PathBuf,
+	ttl: Duration,
+	base_url: Option<Url>,
+	secret: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("The requested file does not exist")]
+	NotFound,
+
+	#[error("The link to this file is invalid or has expired")]
+	InvalidSignature,
+}
+
+impl FileServer {
+	/// Links stay valid (and files are kept around) for `ttl`, rounded up to a whole second.
+	pub fn new(base_url: Option<Url>, ttl: Duration, secret: Option<String>) -> Result<Self> {
+		let root = temp_dir().join(format!("cog-outputs-{}", Uuid::new_v4()));
+		std::fs::create_dir_all(&root)?;
+
+		Ok(Self {
+			root,
+			ttl: Duration::from_secs(ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0))
+				.max(Duration::from_secs(1)),
+			base_url,
+			secret,
+		})
+	}
+
+	/// Move a copy of the file into the served directory for the given prediction, and return its url.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the prediction id can't be used as a directory name, or if the file cannot be copied into the served directory.
+	pub fn store(&self, prediction_id: &str, path: &std::path::Path) -> Result<String> {
+		if !is_path_segment(prediction_id) {
+			bail!("Invalid prediction id: {prediction_id:?}");
+		}
+
+		let dir = self.root.join(prediction_id);
+		std::fs::create_dir_all(&dir)?;
+
+		let mut name = path
+			.file_name()
+			.and_then(|name| name.to_str())
+			.filter(|name| is_path_segment(name))
+			.map_or_else(|| Uuid::new_v4().to_string(), ToString::to_string);
+		if dir.join(&name).exists() {
+			name = format!("{}-{name}", Uuid::new_v4());
+		}
+
+		let destination = dir.join(&name);
+		// Hard links are free when the output lives on the same filesystem, but we fall back to copying when it doesn't.
+		if std::fs::hard_link(path, &destination).is_err() {
+			std::fs::copy(path, &destination)?;
+		}
+
+		tracing::debug!("Serving output file from {}", destination.display());
+		Ok(self.url_for(prediction_id, &name))
+	}
+
+	/// Resolve a requested file to its location on disk, validating the link signature if signing is enabled.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the file doesn't exist, or if the signature is missing, invalid or expired.
+	pub fn resolve(
+		&self,
+		prediction_id: &str,
+		name: &str,
+		expires: Option<i64>,
+		signature: Option<&str>,
+	) -> Result<PathBuf, Error> {
+		if !is_path_segment(prediction_id) || !is_path_segment(name) {
+			return Err(Error::NotFound);
+		}
+
+		if self.secret.is_some() {
+			let (Some(expires), Some(signature)) = (expires, signature) else {
+				return Err(Error::InvalidSignature);
+			};
+
+			let signature = hex::decode(signature).map_err(|_| Error::InvalidSignature)?;
+			if expires < Utc::now().timestamp()
+				|| self
+					.mac(prediction_id, name, expires)
+					.verify_slice(&signature)
+					.is_err()
+			{
+				return Err(Error::InvalidSignature);
+			}
+		}
+
+		let path = self.root.join(prediction_id).join(name);
+		if !path.is_file() {
+			return Err(Error::NotFound);
+		}
+
+		Ok(path)
+	}
+
+	/// Periodically enforce the retention policy until the server shuts down, then remove every served file.
+	pub fn start_cleanup(self: Arc<Self>, shutdown: Shutdown) {
+		// Keep the shutdown from finishing until the files have been removed.
+		let guard = shutdown.guard();
+
+		tokio::spawn(async move {
+			let mut stopped = std::pin::pin!(shutdown.handle());
+			let mut interval = tokio::time::interval(
+				self.ttl
+					.clamp(Duration::from_secs(1), Duration::from_mins(1)),
+			);
+
+			loop {
+				tokio::select! {
+					() = &mut stopped => break,
+					_ = interval.tick() => self.cleanup(),
+				}
+			}
+
+			if let Err(error) = std::fs::remove_dir_all(&self.root) {
+				tracing::error!("Failed to remove served output files: {error}");
+			}
+			drop(guard);
+		});
+	}
+
+	/// Remove the outputs of predictions that are older than the retention period.
+	fn cleanup(&self) {
+		let Ok(entries) = std::fs::read_dir(&self.root) else {
+			return;
+		};
+
+		for entry in entries.flatten() {
+			let expired = entry
+				.metadata()
+				.and_then(|metadata| metadata.modified())
+				.ok()
+				.and_then(|modified| SystemTime::now().duration_since(modified).ok())
+				.is_some_and(|age| age > self.ttl);
+
+			if expired {
+				tracing::debug!("Removing expired output files at {:?}", entry.path());
+				if let Err(error) = std::fs::remove_dir_all(entry.path()) {
+					tracing::error!("Failed to remove expired output files: {error}");
+				}
+			}
+		}
+	}
+
+	fn url_for(&self, prediction_id: &str, name: &str) -> String {
+		let base = self
+			.base_url
+			.clone()
+			.unwrap_or_else(|| Url::parse("http://localhost").unwrap());
+
+		let mut url = url_join(&url_join(&url_join(&base, "files"), prediction_id), name);
+		if self.secret.is_some() {
+			#[allow(clippy::cast_possible_wrap)]
+			let expires = Utc::now().timestamp() + self.ttl.as_secs() as i64;
+
+			url.query_pairs_mut()
+				.append_pair("expires", &expires.to_string())
+				.append_pair(
+					"signature",
+					&hex::encode(
+						self.mac(prediction_id, name, expires)
+							.finalize()
+							.into_bytes(),
+					),
+				);
+		}
+
+		// Without a public base url, we return links relative to this server.
+		if self.base_url.is_none() {
+			return url[Position::BeforePath..].to_string();
+		}
+
+		url.to_string()
+	}
+
+	fn mac(&self, prediction_id: &str, name: &str, expires: i64) -> HmacSha256 {
+		let mut mac = hmac_sha256(self.secret.as_deref().unwrap_or_default().as_bytes());
+		mac.update(format!("{prediction_id}/{name}:{expires}").as_bytes());
+
+		mac
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::shutdown::Signals;
+
+	fn file_server(secret: Option<&str>) -> FileServer {
+		FileServer {
+			base_url: None,
+			root: temp_dir().join(format!("cog-outputs-{}", Uuid::new_v4())),
+			ttl: Duration::from_mins(1),
+			secret: secret.map(ToString::to_string),
+		}
+	}
+
+	#[test]
+	fn stored_files_can_be_resolved() {
+		let files = file_server(None);
+		let source = temp_dir().join(format!("{}.txt", Uuid::new_v4()));
+		std::fs::write(&source, "hello").unwrap();
+
+		let url = files.store("abc", &source).unwrap();
+		let name = source.file_name().unwrap().to_str().unwrap();
+
+		assert_eq!(url, format!("/files/abc/{name}"));
+		assert_eq!(
+			std::fs::read_to_string(files.resolve("abc", name, None, None).unwrap()).unwrap(),
+			"hello"
+		);
+		assert!(matches!(
+			files.resolve("..", name, None, None),
+			Err(Error::NotFound)
+		));
+
+		std::fs::remove_file(source).unwrap();
+		std::fs::remove_dir_all(files.root).unwrap();
+	}
+
+	#[test]
+	fn prediction_ids_cannot_escape_the_root() {
+		let files = file_server(None);
+		let source = temp_dir().join(format!("{}.txt", Uuid::new_v4()));
+		std::fs::write(&source, "hello").unwrap();
+
+		for id in ["../../etc", "..", "a/b", "a\\b", ""] {
+			assert!(files.store(id, &source).is_err(), "{id:?} was accepted");
+		}
+		assert!(!files.root.exists());
+
+		std::fs::remove_file(source).unwrap();
+	}
+
+	#[test]
+	fn signed_links_are_verified() {
+		let files = file_server(Some("secret"));
+		let source = temp_dir().join(format!("{}.txt", Uuid::new_v4()));
+		std::fs::write(&source, "hello").unwrap();
+
+		let url = Url::parse("http://localhost")
+			.unwrap()
+			.join(&files.store("abc", &source).unwrap())
+			.unwrap();
+		let name = source.file_name().unwrap().to_str().unwrap();
+		let query = url
+			.query_pairs()
+			.collect::<std::collections::HashMap<_, _>>();
+		let expires = query["expires"].parse().unwrap();
+
+		assert!(files
+			.resolve("abc", name, Some(expires), Some(&query["signature"]))
+			.is_ok());
+		assert!(matches!(
+			files.resolve("abc", name, Some(expires + 1), Some(&query["signature"])),
+			Err(Error::InvalidSignature)
+		));
+		assert!(matches!(
+			files.resolve("abc", name, None, None),
+			Err(Error::InvalidSignature)
+		));
+
+		std::fs::remove_file(source).unwrap();
+		std::fs::remove_dir_all(files.root).unwrap();
+	}
+
+	#[tokio::test]
+	async fn zero_ttls_still_clean_up() {
+		for (ttl, rounded) in [(0, 1), (500, 1), (1500, 2)] {
+			let files = FileServer::new(None, Duration::from_millis(ttl), None).unwrap();
+			assert_eq!(files.ttl, Duration::from_secs(rounded));
+			std::fs::remove_dir_all(files.root).unwrap();
+		}
+
+		let files = Arc::new(FileServer {
+			ttl: Duration::ZERO,
+			..file_server(None)
+		});
+		let root = files.root.clone();
+		let source = temp_dir().join(format!("{}.txt", Uuid::new_v4()));
+		std::fs::write(&source, "hello").unwrap();
+		files.store("abc", &source).unwrap();
+
+		let shutdown = Shutdown::new(Signals::None, Duration::ZERO);
+		files.start_cleanup(shutdown.clone());
+		// Wait for the cleanup task to listen for the shutdown before starting it.
+		while shutdown.sender.receiver_count() == 0 {
+			tokio::task::yield_now().await;
+		}
+		shutdown.start();
+		shutdown.finished().await;
+
+		assert!(!root.exists());
+		std::fs::remove_file(source).unwrap();
+	}
+}
@@ -3,13 +3,10 @@ use axum::{
 	http::{HeaderName, HeaderValue},
 };
 use itertools::Itertools;
-use lazy_static::lazy_static;
 use percent_encoding::{percent_decode_str, percent_encode, NON_ALPHANUMERIC};
 use std::{borrow::Cow, collections::HashMap};
 
-lazy_static! {
-	static ref PREFER: HeaderName = HeaderName::from_lowercase(b"prefer").unwrap();
-}
+static PREFER: HeaderName = HeaderName::from_static("prefer");
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct Prefer(pub HashMap<String, String>);
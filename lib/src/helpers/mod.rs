@@ -1,4 +1,4 @@
-use base64::{engine::general_purpose::STANDARD as Base64, DecodeError, Engine};
+use base64::{engine::general_purpose::STANDARD as Base64, Engine};
 use url::Url;
 
 pub mod headers;
@@ -8,8 +8,18 @@ pub fn base64_encode<T: AsRef<[u8]>>(bytes: T) -> String {
 	Base64.encode(bytes)
 }
 
-pub fn base64_decode<T: AsRef<[u8]>>(bytes: T) -> Result<Vec<u8>, DecodeError> {
-	Base64.decode(bytes)
+/// Encode the given bytes as a data url, sniffing their MIME type.
+pub fn dataurl(bytes: &[u8]) -> String {
+	format!(
+		"data:{mime_type};base64,{base64}",
+		mime_type = tree_magic_mini::from_u8(bytes),
+		base64 = base64_encode(bytes)
+	)
+}
+
+/// Whether the given string can be used as a single path component (like a prediction id, or the name of an output file), without escaping the directory it's joined to.
+pub fn is_path_segment(segment: &str) -> bool {
+	!segment.is_empty() && segment != "." && segment != ".." && !segment.contains(['/', '\\', '\0'])
 }
 
 /// Append a path to a URL.
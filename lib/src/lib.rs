@@ -1,23 +1,42 @@
 #![warn(clippy::all, clippy::pedantic, clippy::nursery)]
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use tracing_subscriber::{
 	prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
 };
 
-pub use cog_core::{Cog, CogResponse};
+pub use cog_core::{
+	Cog, CogResponse, HealthReport, HealthStatus, ModelError, Progress, Reporter, SetupContext,
+	Train, WeightsLocation,
+};
+pub use inputs::SchemeHandler;
+pub use server::ServerBuilder;
+pub use shutdown::Signals;
 pub use spec::Path;
 
+mod auth;
+mod cache;
+mod egress;
 mod errors;
+mod files;
 mod helpers;
+mod inputs;
+mod listener;
+pub mod metrics;
+mod outputs;
+mod panics;
 mod prediction;
 mod routes;
 mod runner;
 mod server;
 mod shutdown;
 mod spec;
+#[cfg(test)]
+mod test_support;
+pub mod testing;
 mod webhooks;
+pub mod weights;
 
 #[derive(Debug, clap::Parser)]
 pub(crate) struct Cli {
@@ -29,32 +48,186 @@ pub(crate) struct Cli {
 	#[arg(long, default_missing_value = "true", require_equals = true, num_args=0..=1, action = clap::ArgAction::Set)]
 	await_explicit_shutdown: Option<bool>,
 
+	/// Address to listen on
+	#[clap(long, default_value = "0.0.0.0")]
+	host: std::net::IpAddr,
+
+	/// Port to listen on
+	#[clap(long, env = "PORT", default_value_t = 5000)]
+	port: u16,
+
+	/// Listen on this Unix domain socket instead of a TCP port
+	#[clap(long, conflicts_with = "tls_cert")]
+	unix_socket: Option<std::path::PathBuf>,
+
+	/// Serve HTTPS with this PEM-encoded certificate chain (reloaded on SIGHUP)
+	#[clap(long, requires = "tls_key")]
+	tls_cert: Option<std::path::PathBuf>,
+
+	/// PEM-encoded private key for --tls-cert
+	#[clap(long, requires = "tls_cert")]
+	tls_key: Option<std::path::PathBuf>,
+
+	/// Path or URL of the weights to set the model up with (defaults to `weights`, if it exists)
+	#[clap(long, env = "COG_WEIGHTS")]
+	weights: Option<WeightsLocation>,
+
+	/// Directory the model can write temporary files to during setup and predictions (defaults to a new temporary directory)
+	#[clap(long, env = "COG_SCRATCH_DIR")]
+	scratch_dir: Option<std::path::PathBuf>,
+
+	/// How long `setup()` may take before it's considered failed, in seconds
+	#[clap(long, env = "COG_SETUP_TIMEOUT", default_value_t = 300)]
+	setup_timeout: u64,
+
+	/// How many times to retry `setup()` if it fails or times out
+	#[clap(long, env = "COG_SETUP_RETRIES", default_value_t = 0)]
+	setup_retries: u32,
+
+	/// How long to wait before retrying `setup()`, in seconds (doubled after every retry)
+	#[clap(long, env = "COG_SETUP_RETRY_BACKOFF", default_value_t = 1)]
+	setup_retry_backoff: u64,
+
+	/// How long the model's `teardown()` may take when shutting down, in seconds
+	#[clap(long, env = "COG_TEARDOWN_TIMEOUT", default_value_t = 30)]
+	teardown_timeout: u64,
+
+	/// How often to run the model's `health_check()` while it's idle, in seconds (0 to only run it when `/health-check` is requested)
+	#[clap(long, env = "COG_HEALTH_CHECK_INTERVAL", default_value_t = 30)]
+	health_check_interval: u64,
+
+	/// After how many panics the model is marked as unhealthy (0 to never mark it)
+	#[clap(long, env = "COG_PANIC_THRESHOLD", default_value_t = 3)]
+	panic_threshold: u32,
+
+	/// How long running predictions may take to finish when shutting down, in seconds (they're canceled after that)
+	#[clap(long, env = "COG_SHUTDOWN_GRACE_PERIOD", default_value_t = 30)]
+	shutdown_grace_period: u64,
+
 	/// An endpoint for Cog to PUT output files to
 	#[clap(long)]
 	upload_url: Option<url::Url>,
+
+	/// Keep output files on disk and serve them from /files instead of returning data URLs (ignored if --upload-url is set)
+	#[clap(long)]
+	serve_output_files: bool,
+
+	/// Public base URL used to link to served output files (defaults to relative links)
+	#[clap(long)]
+	output_files_url: Option<url::Url>,
+
+	/// How long served output files (and their signed links) are kept around, in seconds
+	#[clap(long, default_value_t = 3600, value_parser = clap::value_parser!(u64).range(1..))]
+	output_files_ttl: u64,
+
+	/// Sign links to served output files with this secret, so they can't be guessed and expire after --output-files-ttl
+	#[clap(long, env = "OUTPUT_FILES_SECRET", hide_env_values = true)]
+	output_files_secret: Option<String>,
+
+	/// Allow file:// inputs, as long as they point inside this directory
+	#[clap(long)]
+	input_file_root: Option<std::path::PathBuf>,
+
+	/// Endpoint used to download gs:// inputs
+	#[clap(long, default_value = "https://storage.googleapis.com")]
+	gcs_endpoint: url::Url,
+
+	/// Cache downloaded inputs in this directory, revalidating them on every use
+	#[clap(long)]
+	input_cache_dir: Option<std::path::PathBuf>,
+
+	/// Maximum size of the input cache, in megabytes
+	#[clap(long, default_value_t = 10240)]
+	input_cache_size: u64,
+
+	/// Only allow input and webhook URLs with these schemes (defaults to the built-in ones: http, https, data, file, s3 and gs)
+	#[clap(long, value_delimiter = ',')]
+	allowed_url_schemes: Option<Vec<String>>,
+
+	/// Hosts (or `*.domain` wildcards), IPs and CIDR ranges that input downloads and webhooks may reach. When set, everything else is blocked
+	#[clap(long, value_delimiter = ',')]
+	egress_allow: Vec<egress::Rule>,
+
+	/// Hosts (or `*.domain` wildcards), IPs and CIDR ranges that input downloads and webhooks may never reach
+	#[clap(long, value_delimiter = ',')]
+	egress_deny: Vec<egress::Rule>,
+
+	/// Allow input downloads and webhooks to reach private, loopback and link-local addresses
+	#[clap(long)]
+	allow_private_egress: bool,
+
+	/// Require requests to carry one of these bearer tokens
+	#[clap(
+		long,
+		env = "COG_AUTH_TOKENS",
+		value_delimiter = ',',
+		hide_env_values = true
+	)]
+	auth_token: Vec<String>,
+
+	/// Accept requests signed with this secret: `X-Cog-Signature` must be the hex HMAC-SHA256 of `{timestamp}.{method}.{path}.{body}`, with the unix timestamp sent in `X-Cog-Timestamp`. Signatures expire after 5 minutes, and each one is only accepted once
+	#[clap(long, env = "COG_AUTH_HMAC_SECRET", hide_env_values = true)]
+	auth_hmac_secret: Option<String>,
+
+	/// Require this bearer token for /shutdown and canceling predictions (it is also accepted on every other route)
+	#[clap(long, env = "COG_ADMIN_TOKEN", hide_env_values = true)]
+	admin_token: Option<String>,
+
+	/// Routes that don't require authentication, like /health-check or /docs (a trailing `*` matches any path with that prefix)
+	#[clap(long, env = "COG_AUTH_EXEMPT", value_delimiter = ',')]
+	auth_exempt: Vec<String>,
 }
 
-/// Start the server with the given model.
+impl Default for Cli {
+	/// The defaults of every argument, ignoring the process' arguments and environment.
+	fn default() -> Self {
+		let command = Self::command().mut_args(|arg| arg.env(None));
+
+		command
+			.try_get_matches_from(["cog"])
+			.and_then(|matches| Self::from_arg_matches(&matches))
+			.expect("The defaults of every argument are valid")
+	}
+}
+
+/// Start the server with the given model, configured from the process' arguments.
 ///
 /// # Errors
 ///
-/// This function will return an error if the PORT environment variable is set but cannot be parsed, or if the server fails to start.
+/// This function will return an error if the server fails to start. Invalid arguments (or environment variables, like `PORT`) exit the process with a usage message instead.
 pub async fn start<T: Cog + 'static>() -> Result<()> {
-	let args = Cli::parse();
-
-	if !args.dump_schema_and_exit {
-		tracing_subscriber::registry()
-			.with(tracing_subscriber::fmt::layer().with_filter(
-				EnvFilter::try_from_default_env().unwrap_or_else(|_| "cog_rust=info".into()),
-			))
-			.init();
+	run(ServerBuilder::<T>::from_cli(Cli::parse())).await
+}
+
+/// Start the server with the given model, also serving training jobs on `/trainings` with the given trainer.
+///
+/// # Errors
+///
+/// This function will return an error if the server fails to start. Invalid arguments (or environment variables, like `PORT`) exit the process with a usage message instead.
+pub async fn start_with_training<T: Cog + 'static, U: Train + 'static>() -> Result<()> {
+	run(ServerBuilder::<T>::from_cli(Cli::parse()).train::<U>()).await
+}
+
+async fn run<T: Cog + 'static>(server: ServerBuilder<T>) -> Result<()> {
+	if server.dump_schema_and_exit() {
+		println!("{}", serde_json::to_string(&server.openapi())?);
+		return Ok(());
 	}
 
-	server::start::<T>(args).await
+	tracing_subscriber::registry()
+		.with(
+			tracing_subscriber::fmt::layer().with_filter(
+				EnvFilter::try_from_default_env()
+					.unwrap_or_else(|_| "cog_rust=info,cog_core=info".into()),
+			),
+		)
+		.init();
+
+	server.serve().await
 }
 
 #[macro_export]
-/// Start the server with the given model.
+/// Start the server with the given model (and, optionally, a trainer: `start!(Model, train = Trainer)`).
 macro_rules! start {
 	($struct_name:ident) => {
 		#[tokio::main]
@@ -62,4 +235,42 @@ macro_rules! start {
 			cog_rust::start::<$struct_name>().await.unwrap();
 		}
 	};
+	($struct_name:ident, train = $trainer_name:ident) => {
+		#[tokio::main]
+		async fn main() {
+			cog_rust::start_with_training::<$struct_name, $trainer_name>()
+				.await
+				.unwrap();
+		}
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn default_config_matches_cli_defaults() {
+		for arg in Cli::command().get_arguments() {
+			if let Some(name) = arg.get_env() {
+				std::env::remove_var(name);
+			}
+		}
+
+		assert_eq!(
+			format!("{:?}", Cli::try_parse_from(["cog"]).unwrap()),
+			format!("{:?}", Cli::default())
+		);
+	}
+
+	#[test]
+	fn output_files_must_be_kept_for_at_least_a_second() {
+		assert!(Cli::try_parse_from(["cog", "--output-files-ttl", "0"]).is_err());
+		assert_eq!(
+			Cli::try_parse_from(["cog", "--output-files-ttl", "1"])
+				.unwrap()
+				.output_files_ttl,
+			1
+		);
+	}
 }
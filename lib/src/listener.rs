@@ -0,0 +1,435 @@
+use anyhow::{bail, Context, Result};
+use axum::{Router, Server};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use std::{
+	future::Future,
+	io::{BufReader, ErrorKind},
+	net::SocketAddr,
+	path::{Path, PathBuf},
+	sync::{Arc, RwLock},
+	time::Duration,
+};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{net::TcpListener, sync::mpsc};
+use tokio_rustls::{
+	rustls::{Certificate, PrivateKey, ServerConfig},
+	TlsAcceptor,
+};
+
+/// How long a client has to complete the TLS handshake before it's disconnected.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before accepting connections again after a failure (like running out of file descriptors), as hyper does.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Where the server accepts connections.
+#[derive(Debug, Clone)]
+pub enum Listener {
+	Tcp(SocketAddr),
+	#[cfg(unix)]
+	Unix(PathBuf),
+	/// HTTPS on the given address, with a certificate chain and private key loaded from PEM files (and reloaded on `SIGHUP`).
+	Tls {
+		addr: SocketAddr,
+		cert: PathBuf,
+		key: PathBuf,
+	},
+}
+
+impl std::fmt::Display for Listener {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Tcp(addr) => write!(f, "http://{addr}"),
+			Self::Tls { addr, .. } => write!(f, "https://{addr}"),
+			#[cfg(unix)]
+			Self::Unix(path) => write!(f, "unix:{}", path.display()),
+		}
+	}
+}
+
+impl Listener {
+	/// Listen on the Unix socket if one is given, or else on the address (with TLS, when both a certificate and key are given).
+	///
+	/// # Errors
+	///
+	/// Returns an error if a Unix socket is given on a platform that doesn't support them.
+	#[cfg_attr(unix, allow(clippy::unnecessary_wraps))]
+	pub fn new(
+		addr: SocketAddr,
+		unix_socket: Option<PathBuf>,
+		tls: Option<(PathBuf, PathBuf)>,
+	) -> Result<Self> {
+		Ok(match (unix_socket, tls) {
+			#[cfg(unix)]
+			(Some(path), _) => Self::Unix(path),
+			#[cfg(not(unix))]
+			(Some(_), _) => bail!("Unix sockets are not supported on this platform"),
+			(None, Some((cert, key))) => Self::Tls { addr, cert, key },
+			(None, None) => Self::Tcp(addr),
+		})
+	}
+
+	/// Serve the router until the given signal completes.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the listener cannot be bound, the TLS configuration is invalid, or the server fails.
+	pub async fn serve(self, router: Router, signal: impl Future<Output = ()>) -> Result<()> {
+		match self {
+			Self::Tcp(addr) => {
+				Server::try_bind(&addr)?
+					.serve(router.into_make_service())
+					.with_graceful_shutdown(signal)
+					.await?;
+			},
+			#[cfg(unix)]
+			Self::Unix(path) => {
+				// A socket left behind by a previous run would make binding fail, but we never remove anything else.
+				if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+					if !metadata.file_type().is_socket() {
+						bail!("{} already exists and is not a socket", path.display());
+					}
+
+					std::fs::remove_file(&path)?;
+				}
+
+				let listener = UnixListener::bind(&path)
+					.with_context(|| format!("Failed to bind to {}", path.display()))?;
+				let (tx, mut rx) = mpsc::channel(32);
+				let accept = tokio::spawn(async move {
+					loop {
+						let (stream, _) = next_connection(|| listener.accept()).await;
+						if tx.send(stream).await.is_err() {
+							break;
+						}
+					}
+				});
+
+				let incoming = hyper::server::accept::poll_fn(move |cx| {
+					rx.poll_recv(cx)
+						.map(|stream| stream.map(Ok::<_, std::io::Error>))
+				});
+				let result = Server::builder(incoming)
+					.serve(router.into_make_service())
+					.with_graceful_shutdown(signal)
+					.await;
+
+				accept.abort();
+				std::fs::remove_file(&path).ok();
+				result?;
+			},
+			Self::Tls { addr, cert, key } => {
+				let config = Arc::new(RwLock::new(Arc::new(load_tls_config(&cert, &key)?)));
+				let reload = tokio::spawn(reload_on_sighup(config.clone(), cert, key));
+
+				let listener = TcpListener::bind(addr).await?;
+				let (tx, mut rx) = mpsc::channel(32);
+				let accept = tokio::spawn(async move {
+					loop {
+						let (stream, _) = next_connection(|| listener.accept()).await;
+
+						// Handshakes happen in the background (with a deadline), so a slow client can't block others from connecting.
+						let (tx, acceptor) = (
+							tx.clone(),
+							TlsAcceptor::from(config.read().unwrap().clone()),
+						);
+						tokio::spawn(async move {
+							match tokio::time::timeout(
+								TLS_HANDSHAKE_TIMEOUT,
+								acceptor.accept(stream),
+							)
+							.await
+							{
+								Ok(Ok(stream)) => tx.send(stream).await.ok(),
+								Ok(Err(error)) => {
+									tracing::debug!("TLS handshake failed: {error}");
+									None
+								},
+								Err(_) => {
+									tracing::debug!("TLS handshake timed out");
+									None
+								},
+							}
+						});
+					}
+				});
+
+				let incoming = hyper::server::accept::poll_fn(move |cx| {
+					rx.poll_recv(cx)
+						.map(|stream| stream.map(Ok::<_, std::io::Error>))
+				});
+				let result = Server::builder(incoming)
+					.serve(router.into_make_service())
+					.with_graceful_shutdown(signal)
+					.await;
+
+				accept.abort();
+				reload.abort();
+				result?;
+			},
+		}
+
+		Ok(())
+	}
+}
+
+/// Replace the TLS configuration with a fresh one from disk every time we receive a `SIGHUP`, keeping the current one if loading fails.
+async fn reload_on_sighup(config: Arc<RwLock<Arc<ServerConfig>>>, cert: PathBuf, key: PathBuf) {
+	#[cfg(unix)]
+	{
+		let Ok(mut hangup) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+		else {
+			tracing::error!("Failed to install SIGHUP handler, TLS certificates won't be reloaded");
+			return;
+		};
+
+		while hangup.recv().await.is_some() {
+			match load_tls_config(&cert, &key) {
+				Ok(new_config) => {
+					*config.write().unwrap() = Arc::new(new_config);
+					tracing::info!("Reloaded TLS certificate");
+				},
+				Err(error) => tracing::error!("Failed to reload TLS certificate: {error}"),
+			}
+		}
+	}
+}
+
+/// Wait for the next connection, skipping the ones that fail and backing off when accepting fails (like when we run out of file descriptors), instead of stopping the server.
+async fn next_connection<T, F: Future<Output = std::io::Result<T>>>(
+	mut accept: impl FnMut() -> F,
+) -> T {
+	loop {
+		match accept().await {
+			Ok(connection) => return connection,
+			// Errors about a single connection don't affect the next one.
+			Err(error) if is_connection_error(&error) => {},
+			Err(error) => {
+				tracing::error!("Failed to accept connection: {error}");
+				tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+			},
+		}
+	}
+}
+
+fn is_connection_error(error: &std::io::Error) -> bool {
+	matches!(
+		error.kind(),
+		ErrorKind::ConnectionRefused | ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset
+	)
+}
+
+fn load_tls_config(cert: &Path, key: &Path) -> Result<ServerConfig> {
+	let certs = rustls_pemfile::certs(&mut BufReader::new(
+		std::fs::File::open(cert)
+			.with_context(|| format!("Failed to open TLS certificate {}", cert.display()))?,
+	))?
+	.into_iter()
+	.map(Certificate)
+	.collect::<Vec<_>>();
+
+	let key = rustls_pemfile::read_all(&mut BufReader::new(
+		std::fs::File::open(key)
+			.with_context(|| format!("Failed to open TLS key {}", key.display()))?,
+	))?
+	.into_iter()
+	.find_map(|item| match item {
+		rustls_pemfile::Item::RSAKey(key)
+		| rustls_pemfile::Item::PKCS8Key(key)
+		| rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+		_ => None,
+	})
+	.with_context(|| format!("No private key found in {}", key.display()))?;
+
+	let mut config = ServerConfig::builder()
+		.with_safe_defaults()
+		.with_no_client_auth()
+		.with_single_cert(certs, key)?;
+	config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+	Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use axum::routing::get;
+	use std::env::temp_dir;
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+	use uuid::Uuid;
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn serves_on_unix_sockets() {
+		let path = temp_dir().join(format!("cog-{}.sock", Uuid::new_v4()));
+		let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+
+		let server = tokio::spawn(Listener::Unix(path.clone()).serve(
+			Router::new().route("/", get(|| async { "hello" })),
+			async {
+				stopped.await.ok();
+			},
+		));
+
+		let mut stream = loop {
+			if let Ok(stream) = tokio::net::UnixStream::connect(&path).await {
+				break stream;
+			}
+			tokio::task::yield_now().await;
+		};
+		stream
+			.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+			.await
+			.unwrap();
+		let mut response = String::new();
+		stream.read_to_string(&mut response).await.unwrap();
+
+		assert!(response.starts_with("HTTP/1.1 200 OK"));
+		assert!(response.ends_with("hello"));
+
+		stop.send(()).unwrap();
+		server.await.unwrap().unwrap();
+		assert!(!path.exists());
+	}
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn only_sockets_are_replaced() {
+		let path = temp_dir().join(format!("cog-{}.sock", Uuid::new_v4()));
+		std::fs::write(&path, "not a socket").unwrap();
+
+		let result = Listener::Unix(path.clone())
+			.serve(Router::new(), std::future::pending())
+			.await;
+
+		assert!(result.unwrap_err().to_string().contains("is not a socket"));
+		assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+		std::fs::remove_file(path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn accept_errors_dont_stop_the_server() {
+		let mut errors = vec![
+			std::io::Error::from(ErrorKind::ConnectionAborted),
+			std::io::Error::from_raw_os_error(24),
+			std::io::Error::from(ErrorKind::ConnectionReset),
+		]
+		.into_iter();
+
+		let started = std::time::Instant::now();
+		let connection = next_connection(|| {
+			let result = errors.next().map_or(Ok("connection"), Err);
+			async move { result }
+		})
+		.await;
+
+		assert_eq!(connection, "connection");
+		// Only the failure that wasn't about a single connection is backed off from.
+		assert!(started.elapsed() >= ACCEPT_ERROR_BACKOFF);
+		assert!(started.elapsed() < ACCEPT_ERROR_BACKOFF * 2);
+	}
+
+	/// Write a new self-signed certificate for `localhost` (and its key) to the given paths, returning the certificate.
+	fn write_certificate(cert: &Path, key: &Path) -> Certificate {
+		let certificate =
+			rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+		// Each serialization signs the certificate again, so only serialize it once.
+		let pem = certificate.serialize_pem().unwrap();
+
+		std::fs::write(cert, &pem).unwrap();
+		std::fs::write(key, certificate.serialize_private_key_pem()).unwrap();
+		Certificate(
+			rustls_pemfile::certs(&mut pem.as_bytes())
+				.unwrap()
+				.remove(0),
+		)
+	}
+
+	/// Connect to the server, trusting the given certificates, and return the one it presented.
+	async fn handshake(addr: SocketAddr, trusted: &[&Certificate]) -> std::io::Result<Certificate> {
+		let mut roots = tokio_rustls::rustls::RootCertStore::empty();
+		for cert in trusted {
+			roots.add(cert).unwrap();
+		}
+		let config = tokio_rustls::rustls::ClientConfig::builder()
+			.with_safe_defaults()
+			.with_root_certificates(roots)
+			.with_no_client_auth();
+
+		let stream = tokio::net::TcpStream::connect(addr).await?;
+		let mut stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+			.connect("localhost".try_into().unwrap(), stream)
+			.await?;
+		let presented = stream.get_ref().1.peer_certificates().unwrap()[0].clone();
+
+		stream
+			.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+			.await?;
+		let mut response = Vec::new();
+		// The server may close the connection without a TLS close_notify.
+		stream.read_to_end(&mut response).await.ok();
+		assert!(String::from_utf8_lossy(&response).ends_with("hello"));
+
+		Ok(presented)
+	}
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn serves_tls_and_reloads_certificates_on_sighup() {
+		let dir = temp_dir().join(format!("cog-tls-{}", Uuid::new_v4()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+		let first = write_certificate(&cert, &key);
+
+		// Handle SIGHUP in the test too, so the signal can't kill the test process before the server starts listening for it.
+		let _hangup =
+			tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).unwrap();
+
+		let addr = std::net::TcpListener::bind("127.0.0.1:0")
+			.unwrap()
+			.local_addr()
+			.unwrap();
+		let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+		let server = tokio::spawn(
+			Listener::Tls {
+				addr,
+				cert: cert.clone(),
+				key: key.clone(),
+			}
+			.serve(Router::new().route("/", get(|| async { "hello" })), async {
+				stopped.await.ok();
+			}),
+		);
+
+		let presented = loop {
+			if let Ok(presented) = handshake(addr, &[&first]).await {
+				break presented;
+			}
+			tokio::task::yield_now().await;
+		};
+		assert_eq!(presented, first);
+
+		let second = write_certificate(&cert, &key);
+		tokio::time::timeout(Duration::from_secs(10), async {
+			loop {
+				std::process::Command::new("kill")
+					.args(["-HUP", &std::process::id().to_string()])
+					.status()
+					.unwrap();
+
+				if handshake(addr, &[&first, &second]).await.unwrap() == second {
+					break;
+				}
+				tokio::time::sleep(Duration::from_millis(50)).await;
+			}
+		})
+		.await
+		.expect("The certificate wasn't reloaded");
+
+		stop.send(()).unwrap();
+		server.await.unwrap().unwrap();
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+}
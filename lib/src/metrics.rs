@@ -0,0 +1,208 @@
+//! Record custom metrics (like token counts) from `predict()`, which are sent in the prediction's response and exported on `/metrics`.
+//!
+//! ```no_run
+//! # fn generate(prompt: &str) -> Vec<String> { vec![] }
+//! # fn predict(prompt: String) -> anyhow::Result<String> {
+//! let tokens = generate(&prompt);
+//! cog_rust::metrics::record("output_token_count", tokens.len() as f64);
+//! # Ok(tokens.concat())
+//! # }
+//! ```
+
+use cog_core::http::Response;
+use serde_json::Value;
+use std::{
+	cell::RefCell,
+	collections::{BTreeMap, HashMap},
+	fmt::Write,
+	sync::Mutex,
+};
+
+thread_local! {
+	/// The metrics recorded by the prediction running on this thread, if any.
+	static RECORDED: RefCell<Option<HashMap<String, f64>>> = const { RefCell::new(None) };
+}
+
+/// Record a metric for the running prediction, replacing any previous value with the same name.
+///
+/// Must be called from the thread `predict()` (or `train()`) runs on; metrics recorded anywhere else are ignored.
+pub fn record(name: impl Into<String>, value: f64) {
+	let name = name.into();
+
+	RECORDED.with(|recorded| {
+		if let Some(recorded) = recorded.borrow_mut().as_mut() {
+			recorded.insert(name, value);
+		} else {
+			tracing::debug!("Ignoring metric {name:?}, recorded outside of a prediction");
+		}
+	});
+}
+
+/// Run `f`, collecting the metrics it records.
+pub(crate) fn capture<T>(f: impl FnOnce() -> T) -> (T, HashMap<String, f64>) {
+	RECORDED.with(|recorded| recorded.replace(Some(HashMap::new())));
+	let result = f();
+	let recorded = RECORDED.with(|recorded| recorded.take().unwrap_or_default());
+
+	(result, recorded)
+}
+
+/// Aggregates the metrics of completed predictions (and training jobs), to export them in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Registry {
+	state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+	/// How many jobs completed, by kind and status.
+	completed: BTreeMap<(&'static str, String), u64>,
+	/// The sum and count of every numeric metric, by kind and name.
+	metrics: BTreeMap<(&'static str, String), (f64, u64)>,
+}
+
+impl Registry {
+	/// Count a completed job of the given kind ("prediction" or "training"), and its numeric metrics.
+	///
+	/// # Panics
+	///
+	/// Panics if another thread panicked while observing.
+	pub fn observe(&self, kind: &'static str, response: &Response) {
+		let status = serde_json::to_value(response.status).unwrap_or(Value::Null);
+		let status = status.as_str().unwrap_or_default().to_string();
+
+		let mut state = self.state.lock().unwrap();
+		*state.completed.entry((kind, status)).or_default() += 1;
+
+		for (name, value) in response.metrics.iter().flatten() {
+			let Some(value) = value.as_f64() else {
+				continue;
+			};
+
+			let (sum, count) = state.metrics.entry((kind, name.clone())).or_default();
+			*sum += value;
+			*count += 1;
+		}
+		drop(state);
+	}
+
+	/// The observed metrics, in the Prometheus text format.
+	///
+	/// # Panics
+	///
+	/// Panics if another thread panicked while observing.
+	pub fn render(&self) -> String {
+		let state = self.state.lock().unwrap();
+		let mut out = String::new();
+
+		out.push_str(
+			"# HELP cog_completed_total Completed predictions and training jobs, by status.\n",
+		);
+		out.push_str("# TYPE cog_completed_total counter\n");
+		for ((kind, status), count) in &state.completed {
+			writeln!(
+				out,
+				"cog_completed_total{{kind=\"{kind}\",status=\"{status}\"}} {count}"
+			)
+			.unwrap();
+		}
+
+		out.push_str("# HELP cog_metric Metrics reported in responses, like predict_time or total_time, and the ones recorded by the model.\n");
+		out.push_str("# TYPE cog_metric summary\n");
+		for ((kind, name), (sum, count)) in &state.metrics {
+			let name = escape(name);
+			writeln!(
+				out,
+				"cog_metric_sum{{kind=\"{kind}\",name=\"{name}\"}} {sum}"
+			)
+			.unwrap();
+			writeln!(
+				out,
+				"cog_metric_count{{kind=\"{kind}\",name=\"{name}\"}} {count}"
+			)
+			.unwrap();
+		}
+		drop(state);
+
+		out
+	}
+}
+
+/// Escape a Prometheus label value.
+fn escape(value: &str) -> String {
+	value
+		.replace('\\', r"\\")
+		.replace('"', r#"\""#)
+		.replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support::{call, get, predict, set_up, Behavior};
+	use axum::http::{Method, StatusCode};
+	use tower::ServiceExt;
+
+	#[test]
+	fn metrics_are_only_recorded_during_predictions() {
+		record("ignored", 1.0);
+
+		let ((), recorded) = capture(|| {
+			record("input_token_count", 12.0);
+			record("output_token_count", 30.0);
+			record("output_token_count", 34.0);
+		});
+
+		assert_eq!(
+			recorded,
+			HashMap::from([
+				("input_token_count".to_string(), 12.0),
+				("output_token_count".to_string(), 34.0),
+			])
+		);
+		assert_eq!(capture(|| ()).1, HashMap::new());
+	}
+
+	#[tokio::test]
+	#[allow(clippy::cast_precision_loss)]
+	async fn recorded_metrics_are_reported_and_exported() {
+		let (server, _) = Behavior {
+			predict: |input| {
+				record("input_token_count", input.text.len() as f64);
+				record("output_token_count", 2.0 * input.text.len() as f64);
+
+				Ok(input.text.repeat(2))
+			},
+			..Behavior::default()
+		}
+		.server();
+		let router = server.into_router().unwrap();
+		set_up(router.clone()).await;
+
+		for (text, length) in [("hello", 5.0), ("hi", 2.0)] {
+			let response = call(router.clone(), predict(Method::POST, "/predictions", text)).await;
+
+			assert_eq!(response["metrics"]["input_token_count"], length);
+			assert_eq!(response["metrics"]["output_token_count"], 2.0 * length);
+			assert!(response["metrics"]["predict_time"].is_f64());
+		}
+
+		let response = router.oneshot(get("/metrics")).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+		let metrics = String::from_utf8(
+			hyper::body::to_bytes(response.into_body())
+				.await
+				.unwrap()
+				.to_vec(),
+		)
+		.unwrap();
+
+		for line in [
+			r#"cog_completed_total{kind="prediction",status="succeeded"} 2"#,
+			r#"cog_metric_sum{kind="prediction",name="input_token_count"} 7"#,
+			r#"cog_metric_count{kind="prediction",name="output_token_count"} 2"#,
+		] {
+			assert!(metrics.contains(line), "{line} isn't in {metrics}");
+		}
+	}
+}
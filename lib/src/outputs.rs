@@ -0,0 +1,411 @@
+use anyhow::{anyhow, Result};
+use axum::http::StatusCode;
+use futures::{StreamExt, TryStreamExt};
+use percent_encoding::percent_decode_str;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use serde_json::Value;
+use std::{
+	cell::RefCell,
+	collections::HashMap,
+	env::temp_dir,
+	path::{Path, PathBuf},
+	sync::Arc,
+	time::Duration,
+};
+use tokio::io::AsyncReadExt;
+use tokio_util::io::ReaderStream;
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+	egress::EgressPolicy,
+	files::FileServer,
+	helpers::{dataurl, url_join},
+};
+
+/// How many times an output file upload is attempted before giving up.
+const UPLOAD_ATTEMPTS: u32 = 3;
+
+/// How many output files are persisted at once.
+const MAX_CONCURRENT_UPLOADS: usize = 8;
+
+/// How much of a file is read to detect its MIME type.
+const MIME_SNIFF_SIZE: usize = 8 * 1024;
+
+thread_local! {
+	static COLLECTOR: RefCell<Option<Outputs>> = const { RefCell::new(None) };
+}
+
+/// Where the files of a prediction's output should end up.
+#[derive(Debug, Clone)]
+pub enum Destination {
+	DataUrl,
+	/// PUT files to this endpoint, appending the file name as a path segment.
+	Upload(Url),
+	/// PUT files to the URL obtained by appending the file name to this prefix.
+	Prefix(Url),
+	Serve(Arc<FileServer>),
+}
+
+/// Output files gathered while serializing a prediction's response, so they can be persisted concurrently afterwards.
+#[derive(Debug)]
+pub struct Outputs {
+	staging: PathBuf,
+	prediction_id: String,
+	destination: Destination,
+	egress: Arc<EgressPolicy>,
+	files: Vec<(String, PathBuf)>,
+}
+
+impl Outputs {
+	pub fn new(prediction_id: String, destination: Destination, egress: Arc<EgressPolicy>) -> Self {
+		Self {
+			egress,
+			destination,
+			prediction_id,
+			files: Vec::new(),
+			staging: temp_dir().join(format!("cog-staging-{}", Uuid::new_v4())),
+		}
+	}
+
+	/// Run the given closure while collecting any `Path` serialized on the current thread.
+	pub fn collect<R>(self, f: impl FnOnce() -> R) -> (R, Self) {
+		COLLECTOR.with(|collector| collector.replace(Some(self)));
+		let guard = CollectGuard;
+
+		let result = f();
+		drop(guard);
+
+		(result, COLLECTOR.with(RefCell::take).unwrap())
+	}
+
+	/// Stage a file for persistence, returning a placeholder to serialize in its place.
+	///
+	/// Returns `None` if outputs aren't being collected on the current thread.
+	pub fn stage(path: &Path) -> Option<Result<String>> {
+		COLLECTOR.with(|collector| {
+			let mut collector = collector.borrow_mut();
+			let outputs = collector.as_mut()?;
+
+			Some(outputs.stage_file(path))
+		})
+	}
+
+	pub const fn is_empty(&self) -> bool {
+		self.files.is_empty()
+	}
+
+	/// Upload (or otherwise persist) all staged files concurrently (up to [`MAX_CONCURRENT_UPLOADS`] at once), and replace their placeholders in `value` with the resulting urls.
+	///
+	/// # Errors
+	///
+	/// Returns an error if any of the files cannot be persisted.
+	pub async fn persist(self, value: &mut Value) -> Result<()> {
+		let client = self.egress.client_builder().build()?;
+
+		let uploads = self
+			.files
+			.iter()
+			.map(|(placeholder, path)| async {
+				let url = self.persist_file(&client, path).await?;
+
+				anyhow::Ok((placeholder.clone(), url))
+			})
+			.collect::<Vec<_>>();
+		let urls = futures::stream::iter(uploads)
+			.buffer_unordered(MAX_CONCURRENT_UPLOADS)
+			.try_collect::<HashMap<_, _>>()
+			.await?;
+
+		replace_placeholders(value, &urls);
+
+		Ok(())
+	}
+
+	fn stage_file(&mut self, path: &Path) -> Result<String> {
+		std::fs::create_dir_all(&self.staging)?;
+
+		let name = path
+			.file_name()
+			.and_then(|name| name.to_str())
+			.map_or_else(|| Uuid::new_v4().to_string(), ToString::to_string);
+		let mut staged = self.staging.join(&name);
+		if staged.exists() {
+			staged = self.staging.join(format!("{}-{name}", Uuid::new_v4()));
+		}
+
+		// The `Path` deletes its file once the response is dropped, so we keep our own link to it.
+		if std::fs::hard_link(path, &staged).is_err() {
+			std::fs::copy(path, &staged)?;
+		}
+
+		let placeholder = format!("cog-output://{}", Uuid::new_v4());
+		self.files.push((placeholder.clone(), staged));
+
+		Ok(placeholder)
+	}
+
+	async fn persist_file(&self, client: &reqwest::Client, path: &Path) -> Result<String> {
+		match &self.destination {
+			Destination::DataUrl => Ok(dataurl(&tokio::fs::read(path).await?)),
+			Destination::Upload(upload_url) => {
+				let url = url_join(upload_url, &file_name(path));
+				self.egress.check(&url)?;
+
+				upload(client, path, url).await
+			},
+			Destination::Prefix(prefix) => {
+				let url = append_to_path(prefix, &file_name(path))?;
+				self.egress.check(&url)?;
+
+				upload(client, path, url).await
+			},
+			Destination::Serve(files) => {
+				let (files, path) = (files.clone(), path.to_path_buf());
+				let prediction_id = self.prediction_id.clone();
+
+				tokio::task::spawn_blocking(move || files.store(&prediction_id, &path)).await?
+			},
+		}
+	}
+}
+
+impl Drop for Outputs {
+	fn drop(&mut self) {
+		if self.staging.exists() {
+			if let Err(error) = std::fs::remove_dir_all(&self.staging) {
+				tracing::error!("Failed to remove staged output files: {error}");
+			}
+		}
+	}
+}
+
+/// Stops collecting outputs when dropped, even if serialization panics.
+struct CollectGuard;
+
+impl Drop for CollectGuard {
+	fn drop(&mut self) {
+		if std::thread::panicking() {
+			COLLECTOR.with(RefCell::take);
+		}
+	}
+}
+
+fn file_name(path: &Path) -> String {
+	path.file_name()
+		.map(|name| name.to_string_lossy().to_string())
+		.unwrap_or_default()
+}
+
+/// Append the file name to the prefix's path, keeping its query (like the signature of a pre-signed url) in place.
+fn append_to_path(prefix: &Url, name: &str) -> Result<Url> {
+	let last = prefix
+		.path_segments()
+		.and_then(Iterator::last)
+		.map(|segment| percent_decode_str(segment).decode_utf8_lossy().to_string())
+		.unwrap_or_default();
+
+	let mut url = prefix.clone();
+	url.path_segments_mut()
+		.map_err(|()| anyhow!("{prefix} can't be used as an output file prefix"))?
+		.pop()
+		.push(&format!("{last}{name}"));
+
+	Ok(url)
+}
+
+/// PUT the file to the given url (retrying with backoff on connection errors, timeouts and server errors) and return where it ended up.
+///
+/// The file is streamed from disk, so it's never held in memory in full.
+async fn upload(client: &reqwest::Client, path: &Path, url: Url) -> Result<String> {
+	let size = tokio::fs::metadata(path).await?.len();
+	let mut head = Vec::with_capacity(MIME_SNIFF_SIZE);
+	tokio::fs::File::open(path)
+		.await?
+		.take(MIME_SNIFF_SIZE as u64)
+		.read_to_end(&mut head)
+		.await?;
+	let mime_type = tree_magic_mini::from_u8(&head);
+
+	let mut attempt = 1;
+	loop {
+		tracing::debug!("Uploading file to {url} (attempt {attempt})");
+
+		let file = tokio::fs::File::open(path).await?;
+		let result = client
+			.put(url.clone())
+			.header(CONTENT_TYPE, mime_type)
+			.header(CONTENT_LENGTH, size)
+			.body(reqwest::Body::wrap_stream(ReaderStream::new(file)))
+			.send()
+			.await;
+
+		let (error, retryable) = match result {
+			Ok(response) if response.status().is_success() => {
+				let mut url = response.url().clone();
+				url.set_query(None);
+
+				tracing::debug!("Uploaded file to {url}");
+				return Ok(url.to_string());
+			},
+			Ok(response) => {
+				let status = response.status();
+
+				(
+					anyhow!(
+						"Failed to upload file to {url}: got {status}. {}",
+						response.text().await.unwrap_or_default()
+					),
+					status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+				)
+			},
+			Err(error) => {
+				let retryable = error.is_connect() || error.is_timeout();
+
+				(error.into(), retryable)
+			},
+		};
+
+		// Client errors (like an expired pre-signed url) won't go away by trying again.
+		if !retryable || attempt >= UPLOAD_ATTEMPTS {
+			return Err(error);
+		}
+
+		tracing::warn!("{error}. Retrying...");
+		tokio::time::sleep(Duration::from_millis(500 * 2_u64.pow(attempt - 1))).await;
+		attempt += 1;
+	}
+}
+
+fn replace_placeholders(value: &mut Value, urls: &HashMap<String, String>) {
+	match value {
+		Value::String(string) => {
+			if let Some(url) = urls.get(string) {
+				string.clone_from(url);
+			}
+		},
+		Value::Array(values) => values
+			.iter_mut()
+			.for_each(|value| replace_placeholders(value, urls)),
+		Value::Object(map) => map
+			.values_mut()
+			.for_each(|value| replace_placeholders(value, urls)),
+		_ => {},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	#[tokio::test]
+	async fn collected_files_are_replaced_in_output() {
+		let files = (0..3)
+			.map(|i| {
+				let path = temp_dir().join(format!("{}-{i}.txt", Uuid::new_v4()));
+				std::fs::write(&path, format!("file {i}")).unwrap();
+				crate::Path::from(path)
+			})
+			.collect::<Vec<_>>();
+
+		let (value, outputs) =
+			Outputs::new("abc".to_string(), Destination::DataUrl, Arc::default())
+				.collect(|| serde_json::to_value(&files));
+		drop(files);
+
+		let mut value = value.unwrap();
+		assert!(value[0].as_str().unwrap().starts_with("cog-output://"));
+
+		outputs.persist(&mut value).await.unwrap();
+
+		assert_eq!(
+			value,
+			json!([
+				"data:text/plain;base64,ZmlsZSAw",
+				"data:text/plain;base64,ZmlsZSAx",
+				"data:text/plain;base64,ZmlsZSAy",
+			])
+		);
+	}
+
+	#[test]
+	fn file_names_are_appended_to_the_prefix_path() {
+		let prefixed = |prefix: &str, name: &str| {
+			append_to_path(&prefix.parse().unwrap(), name)
+				.unwrap()
+				.to_string()
+		};
+
+		assert_eq!(
+			prefixed(
+				"https://bucket.example.com/outputs/?X-Signature=abc",
+				"out 1.png"
+			),
+			"https://bucket.example.com/outputs/out%201.png?X-Signature=abc"
+		);
+		assert_eq!(
+			prefixed("https://bucket.example.com/outputs/run-", "a?b.png"),
+			"https://bucket.example.com/outputs/run-a%3Fb.png"
+		);
+	}
+
+	#[tokio::test]
+	async fn client_errors_are_not_retried() {
+		let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+		let counter = attempts.clone();
+		let router = axum::Router::new().fallback(move || {
+			counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+			async { StatusCode::FORBIDDEN }
+		});
+		let server =
+			axum::Server::bind(&([127, 0, 0, 1], 0).into()).serve(router.into_make_service());
+		let url = format!("http://{}/", server.local_addr()).parse().unwrap();
+		tokio::spawn(server);
+
+		let path = temp_dir().join(format!("{}.txt", Uuid::new_v4()));
+		std::fs::write(&path, "hello").unwrap();
+
+		assert!(upload(&reqwest::Client::new(), &path, url).await.is_err());
+		assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+		std::fs::remove_file(path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn files_are_streamed_with_their_length_and_type() {
+		let received = Arc::new(std::sync::Mutex::new(None));
+		let state = received.clone();
+		let router = axum::Router::new().fallback(
+			move |headers: axum::http::HeaderMap, body: axum::body::Bytes| async move {
+				*state.lock().unwrap() = Some((
+					headers[CONTENT_LENGTH].to_str().unwrap().to_string(),
+					headers[CONTENT_TYPE].to_str().unwrap().to_string(),
+					body,
+				));
+				StatusCode::OK
+			},
+		);
+		let server =
+			axum::Server::bind(&([127, 0, 0, 1], 0).into()).serve(router.into_make_service());
+		let url = format!("http://{}/out.png", server.local_addr())
+			.parse()
+			.unwrap();
+		tokio::spawn(server);
+
+		// Larger than the part read to detect the file's type.
+		let mut contents = b"\x89PNG\r\n\x1a\n".to_vec();
+		contents.resize(3 * MIME_SNIFF_SIZE, 0);
+		let path = temp_dir().join(format!("{}.png", Uuid::new_v4()));
+		std::fs::write(&path, &contents).unwrap();
+
+		upload(&reqwest::Client::new(), &path, url).await.unwrap();
+
+		let (length, mime_type, body) = received.lock().unwrap().take().unwrap();
+		assert_eq!(length, contents.len().to_string());
+		assert_eq!(mime_type, "image/png");
+		assert_eq!(body, contents);
+
+		std::fs::remove_file(path).unwrap();
+	}
+}
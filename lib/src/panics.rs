@@ -0,0 +1,175 @@
+//! Capture the message, location and backtrace of the model's panics, which `catch_unwind` alone throws away.
+
+use futures::FutureExt;
+use std::{
+	any::Any,
+	backtrace::{Backtrace, BacktraceStatus},
+	cell::RefCell,
+	fmt::Display,
+	future::Future,
+	panic::{AssertUnwindSafe, PanicHookInfo},
+	sync::Once,
+};
+
+thread_local! {
+	/// The last panic on this thread, recorded by our panic hook.
+	static LAST_PANIC: RefCell<Option<Panic>> = const { RefCell::new(None) };
+}
+
+/// A panic caught while running the model.
+#[derive(Debug, Clone)]
+pub struct Panic {
+	pub message: String,
+	/// Where the model panicked, if known.
+	pub location: Option<String>,
+	/// The stack at the time of the panic, when enabled with `RUST_BACKTRACE` (or `RUST_LIB_BACKTRACE`).
+	pub backtrace: Option<String>,
+}
+
+impl Panic {
+	/// A panic we only have the payload of (because it wasn't recorded by our hook).
+	pub fn from_payload(payload: &(dyn Any + Send)) -> Self {
+		Self {
+			message: message(payload),
+			location: None,
+			backtrace: None,
+		}
+	}
+}
+
+impl Display for Panic {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match &self.location {
+			Some(location) => write!(f, "The model panicked at {location}: {}", self.message)?,
+			None => write!(f, "The model panicked: {}", self.message)?,
+		}
+
+		if let Some(backtrace) = &self.backtrace {
+			write!(f, "\n\nstack backtrace:\n{backtrace}")?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Run `f`, catching (and recording the details of) any panic.
+pub fn catch_unwind<T>(f: impl FnOnce() -> T) -> Result<T, Panic> {
+	install_hook();
+	LAST_PANIC.with(|last| last.borrow_mut().take());
+
+	std::panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+		LAST_PANIC
+			.with(|last| last.borrow_mut().take())
+			.unwrap_or_else(|| Panic::from_payload(&*payload))
+	})
+}
+
+/// Run `future` to completion, catching (and recording the details of) any panic.
+pub async fn catch_unwind_async<T>(future: impl Future<Output = T>) -> Result<T, Panic> {
+	install_hook();
+
+	// The payload is handled right after the panicking poll, on the same thread, so the hook's record is still there.
+	AssertUnwindSafe(future)
+		.catch_unwind()
+		.await
+		.map_err(|payload| {
+			LAST_PANIC
+				.with(|last| last.borrow_mut().take())
+				.unwrap_or_else(|| Panic::from_payload(&*payload))
+		})
+}
+
+/// Record the details of every panic for [`catch_unwind`], before running the previous hook (which prints the panic, by default).
+fn install_hook() {
+	static HOOK: Once = Once::new();
+
+	HOOK.call_once(|| {
+		let previous = std::panic::take_hook();
+
+		std::panic::set_hook(Box::new(move |info: &PanicHookInfo<'_>| {
+			let backtrace = Backtrace::capture();
+
+			LAST_PANIC.with(|last| {
+				*last.borrow_mut() = Some(Panic {
+					message: message(info.payload()),
+					location: info.location().map(ToString::to_string),
+					backtrace: (backtrace.status() == BacktraceStatus::Captured)
+						.then(|| backtrace.to_string()),
+				});
+			});
+
+			previous(info);
+		}));
+	});
+}
+
+fn message(payload: &(dyn Any + Send)) -> String {
+	payload
+		.downcast_ref::<&str>()
+		.map(ToString::to_string)
+		.or_else(|| payload.downcast_ref::<String>().cloned())
+		.unwrap_or_else(|| "Box<dyn Any>".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support::{call, get, predict, set_up, Behavior};
+	use axum::http::{Method, StatusCode};
+	use tower::ServiceExt;
+
+	#[test]
+	fn panics_are_caught_with_their_message_and_location() {
+		let panic = catch_unwind(|| {
+			let items: Vec<u32> = Vec::new();
+			panic!("no items, got {}", items.len());
+		})
+		.unwrap_err();
+
+		assert_eq!(panic.message, "no items, got 0");
+		assert!(panic.location.unwrap().starts_with("lib/src/panics.rs:"));
+
+		assert_eq!(catch_unwind(|| 42).unwrap(), 42);
+	}
+
+	#[tokio::test]
+	async fn panicking_predictions_fail_and_are_counted() {
+		let (server, _) = Behavior {
+			predict: |input| panic!("Can't handle {:?}", input.text),
+			..Behavior::default()
+		}
+		.server();
+		let router = server
+			.health_check_interval(None)
+			.panic_threshold(Some(2))
+			.into_router()
+			.unwrap();
+		set_up(router.clone()).await;
+
+		for _ in 0..2 {
+			let response = call(
+				router.clone(),
+				predict(Method::POST, "/predictions", "hello"),
+			)
+			.await;
+			assert_eq!(response["status"], "failed");
+
+			let error = response["error"].as_str().unwrap();
+			assert!(error.starts_with("The model panicked at lib/src/panics.rs:"));
+			assert!(error.contains(r#"Can't handle "hello""#));
+		}
+
+		let health = call(router.clone(), get("/health-check")).await;
+		assert_eq!(health["status"], "UNHEALTHY");
+		assert!(health["model"]["details"]
+			.as_str()
+			.unwrap()
+			.contains("panicked 2 times"));
+
+		let response = router
+			.oneshot(predict(Method::POST, "/predictions", "hello"))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+	}
+}
@@ -1,24 +1,41 @@
 use chrono::{DateTime, Utc};
-use cog_core::http::{Request, Response, Status};
-use map_macro::hash_map;
+use cog_core::{
+	http::{Request, Response, Status, ValidationError},
+	SetupContext,
+};
 use serde_json::Value;
 use std::{
 	future::Future,
 	sync::{atomic::Ordering, Arc},
-	time::Duration,
 };
-use tokio::sync::RwLock;
+use tokio::sync::{oneshot, watch, RwLock};
 
 use crate::{
+	egress::EgressPolicy,
 	errors::ValidationErrorSet,
-	runner::{Error as RunnerError, Health, Runner, RUNNER_HEALTH},
-	shutdown::Shutdown,
+	inputs::Inputs,
+	metrics::Registry,
+	outputs::Destination,
+	runner::{
+		AtomicHealth, Error as RunnerError, Health, HealthChecks, Lifecycle, Metrics, Model,
+		Predictor, Runner, Trainer,
+	},
+	shutdown::{Guard, Shutdown},
 	webhooks::WebhookSender,
-	Cog,
+	Cog, Train,
 };
 
 pub type Extension = axum::Extension<Arc<RwLock<Prediction>>>;
 
+/// The state of the (single) training job the server can run at a time, kept apart from predictions.
+#[derive(Clone)]
+pub struct Training {
+	pub training: Arc<RwLock<Prediction>>,
+	/// The health of the trainer, which can be read without locking the training.
+	pub health: Arc<AtomicHealth>,
+}
+pub type TrainingExtension = axum::Extension<Training>;
+
 #[derive(Debug, Clone, thiserror::Error)]
 pub enum Error {
 	#[error("Attempted to re-initialize a prediction")]
@@ -30,92 +47,317 @@ pub enum Error {
 	#[error("The requested prediction does not exist")]
 	Unknown,
 
-	#[error("Failed to wait for prediction: {0}")]
-	Receiver(#[from] flume::RecvError),
+	#[error("The server is shutting down")]
+	ShuttingDown,
+
+	#[error("The model is unhealthy")]
+	Unhealthy,
 
 	#[error("Failed to run prediction: {0}")]
 	Validation(#[from] ValidationErrorSet),
 }
 
+/// The state of the (single) prediction the server can run at a time. Training jobs go through the same lifecycle, with a separate instance.
+///
+/// The state is shared behind a lock, which is only held while it changes (and not while the model runs), so a prediction can be inspected, waited on and canceled while it's running.
 pub struct Prediction {
 	runner: Runner,
+	/// What the jobs are called, like "prediction".
+	kind: &'static str,
+	metrics: Arc<Registry>,
 	pub status: Status,
 	pub id: Option<String>,
 	pub shutdown: Shutdown,
-	webhooks: WebhookSender,
-	cancel: flume::Sender<()>,
+	webhooks: Arc<WebhookSender>,
+	egress: Arc<EgressPolicy>,
+	/// Cancels the initialized prediction (and only that one), whether or not it started running.
+	cancel: Option<oneshot::Sender<()>>,
+	/// Handed to the runner along with the prediction, once it starts.
+	canceled: Option<oneshot::Receiver<()>>,
 	pub request: Option<Request>,
 	pub response: Option<Response>,
-	complete: Option<flume::Receiver<Response>>,
+	complete: Option<watch::Sender<Option<Response>>>,
+	/// Keeps a shutdown draining until the initialized prediction completes.
+	in_flight: Option<Guard>,
+}
+
+/// Everything needed to run a started prediction without holding the lock.
+struct Task {
+	id: Option<String>,
+	kind: &'static str,
+	metrics: Arc<Registry>,
+	request: Request,
+	runner: Runner,
+	shutdown: Shutdown,
+	canceled: oneshot::Receiver<()>,
+	webhooks: Arc<WebhookSender>,
+	_in_flight: Option<Guard>,
 }
 
 impl Prediction {
-	pub fn setup<T: Cog + 'static>(shutdown: Shutdown) -> Self {
-		let (cancel_tx, cancel_rx) = flume::unbounded();
+	pub fn setup<T: Cog + 'static>(
+		shutdown: Shutdown,
+		destination: Destination,
+		inputs: Inputs,
+		egress: Arc<EgressPolicy>,
+		ctx: SetupContext,
+		lifecycle: Lifecycle,
+		metrics: Arc<Registry>,
+	) -> Self {
+		Self::new::<Predictor<T>>(
+			shutdown,
+			destination,
+			inputs,
+			egress,
+			ctx,
+			lifecycle,
+			metrics,
+		)
+	}
 
+	/// Set up a trainer, whose training jobs run like predictions.
+	pub fn training<T: Train + 'static>(
+		shutdown: Shutdown,
+		destination: Destination,
+		inputs: Inputs,
+		egress: Arc<EgressPolicy>,
+		ctx: SetupContext,
+		lifecycle: Lifecycle,
+		metrics: Arc<Registry>,
+	) -> Self {
+		Self::new::<Trainer<T>>(
+			shutdown,
+			destination,
+			inputs,
+			egress,
+			ctx,
+			lifecycle,
+			metrics,
+		)
+	}
+
+	fn new<M: Model>(
+		shutdown: Shutdown,
+		destination: Destination,
+		inputs: Inputs,
+		egress: Arc<EgressPolicy>,
+		ctx: SetupContext,
+		lifecycle: Lifecycle,
+		metrics: Arc<Registry>,
+	) -> Self {
 		Self {
 			id: None,
+			metrics,
+			kind: M::KIND,
+			egress: egress.clone(),
 			request: None,
 			complete: None,
+			in_flight: None,
 			response: None,
-			cancel: cancel_tx,
+			cancel: None,
+			canceled: None,
 			status: Status::Idle,
 			shutdown: shutdown.clone(),
-			webhooks: WebhookSender::new().unwrap(),
-			runner: Runner::new::<T>(shutdown, cancel_rx),
+			webhooks: Arc::new(WebhookSender::new(egress).unwrap()),
+			runner: Runner::new::<M>(shutdown, destination, inputs, ctx, lifecycle),
 		}
 	}
 
 	pub fn init(&mut self, id: Option<String>, req: Request) -> Result<&mut Self, Error> {
+		if self.shutdown.draining() {
+			tracing::debug!("Attempted to initialize a prediction while shutting down");
+			return Err(Error::ShuttingDown);
+		}
+
+		if matches!(
+			self.runner.health().load(Ordering::SeqCst),
+			Health::Unhealthy | Health::Defunct
+		) {
+			tracing::debug!("Attempted to initialize a prediction while the model is unhealthy");
+			return Err(Error::Unhealthy);
+		}
+
 		if !matches!(self.status, Status::Idle) {
 			tracing::debug!("Attempted to re-initialize a prediction");
 			return Err(Error::AlreadyRunning);
 		}
 
-		self.validate(&req.input)
-			.map_err(|e| e.fill_loc(&["body", "input"]))?;
+		// A canceled prediction keeps the runner busy until the model returns.
+		if matches!(self.runner.health().load(Ordering::SeqCst), Health::Busy) {
+			tracing::debug!("Attempted to initialize a prediction while the runner is busy");
+			return Err(Error::AlreadyRunning);
+		}
+
+		self.validate(&req)?;
 
 		tracing::debug!("Initializing prediction: {id:?}");
 
 		self.id = id;
 		self.request = Some(req);
 		self.status = Status::Starting;
+		self.complete = Some(watch::channel(None).0);
+		let (cancel, canceled) = oneshot::channel();
+		(self.cancel, self.canceled) = (Some(cancel), Some(canceled));
+		self.in_flight = Some(self.shutdown.in_flight());
 
 		Ok(self)
 	}
 
-	pub fn validate(&self, input: &Value) -> Result<(), ValidationErrorSet> {
-		self.runner.validate(input)
+	/// Validate the request's input, and check the urls we'll send requests to against the egress policy.
+	pub fn validate(&self, req: &Request) -> Result<(), ValidationErrorSet> {
+		self.runner
+			.validate(&req.input)
+			.map_err(|e| e.fill_loc(&["body", "input"]))?;
+
+		let errors = [
+			("webhook", &req.webhook),
+			("output_file_prefix", &req.output_file_prefix),
+		]
+		.into_iter()
+		.filter_map(|(field, url)| {
+			let error = self.egress.check(url.as_ref()?).err()?;
+
+			Some(ValidationError {
+				msg: error.to_string(),
+				loc: vec!["body".to_string(), field.to_string()],
+			})
+		})
+		.collect::<Vec<_>>();
+
+		if !errors.is_empty() {
+			return Err(ValidationErrorSet { errors });
+		}
+
+		Ok(())
 	}
 
-	pub async fn run(&mut self) -> Result<Response, Error> {
-		self.process()?.await;
+	/// Validate the request, then make sure the hosts of the urls in it resolve to addresses the egress policy allows, so they're rejected upfront instead of failing the prediction when they're requested.
+	///
+	/// The hosts are resolved without holding the lock.
+	pub async fn resolve(prediction: &RwLock<Self>, req: &Request) -> Result<(), Error> {
+		let (egress, urls) = {
+			let prediction = prediction.read().await;
+			prediction.validate(req)?;
+
+			let urls = prediction
+				.runner
+				.urls(&req.input)
+				.into_iter()
+				.map(|(loc, url)| {
+					let prefix = vec!["body".to_string(), "input".to_string()];
+					([prefix, loc].concat(), url)
+				});
+			let urls = [
+				("webhook", &req.webhook),
+				("output_file_prefix", &req.output_file_prefix),
+			]
+			.into_iter()
+			.filter_map(|(field, url)| {
+				Some((vec!["body".to_string(), field.to_string()], url.clone()?))
+			})
+			.chain(urls)
+			.collect::<Vec<_>>();
+
+			(prediction.egress.clone(), urls)
+		};
+
+		let mut errors = Vec::new();
+		for (loc, url) in urls {
+			if let Err(error) = egress.check_resolved(&url).await {
+				errors.push(ValidationError {
+					loc,
+					msg: error.to_string(),
+				});
+			}
+		}
+
+		if !errors.is_empty() {
+			return Err(ValidationErrorSet { errors }.into());
+		}
 
-		self.result()
+		Ok(())
 	}
 
-	pub async fn wait_for(&self, id: String) -> Result<Response, Error> {
-		if self.id != Some(id.clone()) {
+	/// The current response of the prediction with the given id.
+	pub fn current(&self, id: &str) -> Result<Response, Error> {
+		if self.id.as_deref() != Some(id) {
+			return Err(Error::Unknown);
+		}
+
+		Ok(self
+			.response
+			.clone()
+			.unwrap_or_else(|| Response::starting(self.id.clone(), self.request.clone().unwrap())))
+	}
+
+	/// Wait for the prediction with the given id to complete.
+	///
+	/// The returned future doesn't borrow the prediction, so the lock should be released before awaiting it.
+	pub fn wait_for(
+		&self,
+		id: &str,
+	) -> Result<impl Future<Output = Result<Response, Error>> + 'static, Error> {
+		if self.id.as_deref() != Some(id) {
 			tracing::debug!("Attempted to wait for prediction with unknown ID: {id:?}");
 			return Err(Error::Unknown);
 		}
 
-		if let Some(response) = self.response.clone() {
-			tracing::debug!("Prediction already complete: {id:?}");
-			return Ok(response);
+		tracing::debug!("Waiting for prediction: {id:?}");
+		let mut complete = self.complete.as_ref().ok_or(Error::Unknown)?.subscribe();
+
+		Ok(async move {
+			let response = complete
+				.wait_for(Option::is_some)
+				.await
+				.map_err(|_| Error::NotComplete)?;
+
+			Ok(response.clone().unwrap())
+		})
+	}
+
+	/// Run the initialized prediction to completion, only locking it to record its progress.
+	///
+	/// If the server is shutting down, the prediction is given until the end of the grace period to finish, and is canceled after that. Either way, its final webhook is sent before the shutdown completes.
+	pub async fn process(prediction: &RwLock<Self>) -> Result<Response, Error> {
+		let started_at = Utc::now();
+		let task = prediction.write().await.start()?;
+		tracing::debug!("Running prediction: {:?}", task.id);
+
+		if let Err(e) = task.webhooks.starting(task.id.clone(), &task.request).await {
+			tracing::error!("Failed to send start webhook for prediction: {e:?}",);
 		}
 
-		if !matches!(self.status, Status::Processing) {
-			tracing::debug!("Attempted to wait for prediction that isn't running: {id:?}");
-			return Err(Error::AlreadyRunning);
+		let response = tokio::select! {
+			() = task.shutdown.handle() => {
+				tracing::debug!("Shutdown requested. Cancelling running prediction: {:?}", task.id);
+				Response::canceled(task.id.clone(), task.request.clone(), started_at)
+			},
+			output = task.runner.run(task.id.clone(), task.request.clone(), task.canceled) => {
+				tracing::debug!("Prediction complete: {:?}", task.id);
+
+				match output {
+					Ok((output, metrics)) => Response::success(task.id.clone(), task.request.clone(), output, metrics, started_at),
+					Err(RunnerError::Canceled) => Response::canceled(task.id.clone(), task.request.clone(), started_at),
+					Err(error) => Response::error(task.id.clone(), task.request.clone(), &error, started_at),
+				}
+			}
+		};
+
+		prediction.write().await.finish(response.clone());
+		task.metrics.observe(task.kind, &response);
+
+		if let Err(e) = task
+			.webhooks
+			.finished(&task.request, response.clone())
+			.await
+		{
+			tracing::error!("Failed to send finished webhook for prediction: {e:?}",);
 		}
 
-		tracing::debug!("Waiting for prediction: {id:?}");
-		let complete = self.complete.as_ref().unwrap();
-		Ok(complete.recv_async().await?)
+		Ok(response)
 	}
 
-	pub fn process(&mut self) -> Result<impl Future<Output = ()> + '_, Error> {
+	fn start(&mut self) -> Result<Task, Error> {
 		if !matches!(self.status, Status::Starting) {
 			tracing::debug!(
 				"Attempted to process prediction while not ready: {:?}",
@@ -124,72 +366,31 @@ impl Prediction {
 			return Err(Error::AlreadyRunning);
 		}
 
-		let req = self.request.clone().unwrap();
+		let request = self.request.clone().unwrap();
+		let canceled = self.canceled.take().unwrap();
 		self.status = Status::Processing;
-
-		let (complete_tx, complete_rx) = flume::bounded(1);
-		self.complete = Some(complete_rx);
-
-		Ok(async move {
-			let started_at = Utc::now();
-			tracing::debug!("Running prediction: {:?}", self.id);
-
-			self.status = Status::Processing;
-			self.response = Some(Response::starting(self.id.clone(), req.clone()));
-			if let Err(e) = self.webhooks.starting(self).await {
-				tracing::error!("Failed to send start webhook for prediction: {e:?}",);
-			};
-
-			tokio::select! {
-				() = self.shutdown.handle() => {
-					tracing::debug!("Shutdown requested. Cancelling running prediction: {:?}", self.id);
-					return;
-				},
-				output = self.runner.run(req.clone()) => {
-					tracing::debug!("Prediction complete: {:?}", self.id);
-
-					match output {
-						Ok((output, predict_time)) => {
-							self.status = Status::Succeeded;
-							self.response = Some(Response::success(self.id.clone(), req, output, predict_time, started_at));
-						},
-						Err(RunnerError::Canceled) => {
-							self.status = Status::Canceled;
-							self.response = Some(Response::canceled(self.id.clone(), req, started_at));
-
-						},
-						Err(error) => {
-							self.status = Status::Failed;
-							self.response = Some(Response::error(self.id.clone(), req, &error, started_at));
-						}
-					}
-
-					if let Err(e) = self.webhooks.finished(self, self.response.clone().unwrap()).await {
-						tracing::error!("Failed to send finished webhook for prediction: {e:?}",);
-					};
-				}
-			}
-			complete_tx.send(self.response.clone().unwrap()).unwrap();
+		self.response = Some(Response::starting(self.id.clone(), request.clone()));
+
+		Ok(Task {
+			request,
+			canceled,
+			id: self.id.clone(),
+			kind: self.kind,
+			metrics: self.metrics.clone(),
+			runner: self.runner.clone(),
+			shutdown: self.shutdown.clone(),
+			webhooks: self.webhooks.clone(),
+			_in_flight: self.in_flight.take(),
 		})
 	}
 
-	pub fn result(&mut self) -> Result<Response, Error> {
-		if !matches!(
-			self.status,
-			Status::Succeeded | Status::Failed | Status::Canceled
-		) {
-			tracing::debug!(
-				"Attempted to get result of prediction that is not complete: {:?}",
-				self.id
-			);
-			return Err(Error::NotComplete);
-		}
-
-		tracing::debug!("Getting result of prediction: {:?}", self.id);
-		let response = self.response.clone().ok_or(Error::NotComplete)?;
-		self.reset();
+	fn finish(&mut self, response: Response) {
+		self.status = response.status;
+		self.response = Some(response.clone());
 
-		Ok(response)
+		if let Some(complete) = &self.complete {
+			complete.send_replace(Some(response));
+		}
 	}
 
 	pub fn cancel(&mut self, id: &str) -> Result<&mut Self, Error> {
@@ -198,14 +399,15 @@ impl Prediction {
 			return Err(Error::Unknown);
 		}
 
-		if !matches!(self.status, Status::Processing) {
+		if !matches!(self.status, Status::Starting | Status::Processing) {
 			tracing::debug!("Attempted to cancel prediction that is not running: {id}");
 			return Err(Error::AlreadyRunning);
 		}
 
 		tracing::debug!("Canceling prediction: {id}");
-		self.cancel.send(()).unwrap();
-		self.status = Status::Canceled;
+		if let Some(cancel) = self.cancel.take() {
+			let _ = cancel.send(());
+		}
 
 		Ok(self)
 	}
@@ -217,41 +419,77 @@ impl Prediction {
 		self.request = None;
 		self.response = None;
 		self.complete = None;
+		self.in_flight = None;
+		self.cancel = None;
+		self.canceled = None;
 		self.status = Status::Idle;
 	}
 
+	/// A handle to the health of the underlying runner, which can be read without locking the prediction.
+	pub fn health(&self) -> Arc<AtomicHealth> {
+		self.runner.health()
+	}
+
+	/// A handle to the model's health checks, which can be used without locking the prediction.
+	pub fn health_checks(&self) -> HealthChecks {
+		self.runner.health_checks()
+	}
+
 	pub fn extension(self) -> Extension {
 		axum::Extension(Arc::new(RwLock::new(self)))
 	}
+
+	pub fn training_extension(self) -> TrainingExtension {
+		axum::Extension(Training {
+			health: self.health(),
+			training: Arc::new(RwLock::new(self)),
+		})
+	}
 }
 
-pub struct SyncGuard<'a> {
-	prediction: tokio::sync::RwLockWriteGuard<'a, Prediction>,
+/// Runs a synchronous prediction, canceling it if the request is dropped (because the client disconnected) before it completes.
+pub struct SyncGuard {
+	prediction: Arc<RwLock<Prediction>>,
+	running: bool,
 }
 
-impl<'a> SyncGuard<'a> {
-	pub fn new(prediction: tokio::sync::RwLockWriteGuard<'a, Prediction>) -> Self {
-		Self { prediction }
+impl SyncGuard {
+	pub const fn new(prediction: Arc<RwLock<Prediction>>) -> Self {
+		Self {
+			prediction,
+			running: false,
+		}
 	}
 
-	pub fn init(&mut self, id: Option<String>, req: Request) -> Result<&mut Self, Error> {
-		self.prediction.init(id, req)?;
-		Ok(self)
-	}
+	pub async fn run(&mut self, id: Option<String>, req: Request) -> Result<Response, Error> {
+		Prediction::resolve(&self.prediction, &req).await?;
+		self.prediction.write().await.init(id, req)?;
+		self.running = true;
 
-	pub async fn run(&mut self) -> Result<Response, Error> {
-		self.prediction.run().await
+		let response = Prediction::process(&self.prediction).await;
+		self.prediction.write().await.reset();
+		self.running = false;
+
+		response
 	}
 }
 
-impl Drop for SyncGuard<'_> {
+impl Drop for SyncGuard {
 	fn drop(&mut self) {
-		tracing::debug!("SyncGuard dropped, resetting prediction");
-
-		self.prediction.reset();
-		if matches!(RUNNER_HEALTH.load(Ordering::SeqCst), Health::Busy) {
-			self.prediction.cancel.send(()).unwrap();
+		if !self.running {
+			return;
 		}
+
+		tracing::debug!("SyncGuard dropped, canceling prediction");
+		let prediction = self.prediction.clone();
+		tokio::spawn(async move {
+			let mut prediction = prediction.write().await;
+
+			if let Some(cancel) = prediction.cancel.take() {
+				let _ = cancel.send(());
+			}
+			prediction.reset();
+		});
 	}
 }
 
@@ -261,7 +499,7 @@ pub trait ResponseHelpers {
 		id: Option<String>,
 		req: Request,
 		output: Value,
-		predict_time: Duration,
+		metrics: Metrics,
 		started_at: DateTime<Utc>,
 	) -> Self;
 	fn error(
@@ -278,7 +516,7 @@ impl ResponseHelpers for Response {
 		id: Option<String>,
 		req: Request,
 		output: Value,
-		predict_time: Duration,
+		metrics: Metrics,
 		started_at: DateTime<Utc>,
 	) -> Self {
 		Self {
@@ -288,9 +526,7 @@ impl ResponseHelpers for Response {
 			status: Status::Succeeded,
 			started_at: Some(started_at),
 			completed_at: Some(Utc::now()),
-			metrics: Some(hash_map! {
-				"predict_time".to_string() => predict_time.as_secs_f64().into()
-			}),
+			metrics: Some(metrics),
 			..Self::default()
 		}
 	}
@@ -306,6 +542,7 @@ impl ResponseHelpers for Response {
 			status: Status::Failed,
 			started_at: Some(started_at),
 			error: Some(error.to_string()),
+			failure: Some(error.model_error()),
 			..Self::default()
 		}
 	}
@@ -16,7 +16,7 @@ async fn openapi_schema(Extension(openapi): Extension<OpenApi>) -> Json<OpenApi>
 	Json(openapi)
 }
 
-#[allow(clippy::unused_async)]
+#[allow(clippy::unused_async, clippy::literal_string_with_formatting_args)]
 async fn swagger() -> Html<String> {
 	Html(SWAGGER_UI_TEMPLATE.replace("{:spec_url}", "/openapi.json"))
 }
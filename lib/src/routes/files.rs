@@ -0,0 +1,50 @@
+use aide::axum::ApiRouter;
+use axum::{
+	body::StreamBody,
+	extract::{Path, Query},
+	http::{header, StatusCode},
+	response::IntoResponse,
+	routing::get,
+	Extension,
+};
+use std::sync::Arc;
+use tokio_util::io::ReaderStream;
+
+use crate::{errors::HTTPError, files::FileServer};
+
+pub fn handler() -> ApiRouter {
+	ApiRouter::new().route("/files/:prediction_id/:name", get(serve_file))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct FileQuery {
+	expires: Option<i64>,
+	signature: Option<String>,
+}
+
+async fn serve_file(
+	Path((prediction_id, name)): Path<(String, String)>,
+	Query(query): Query<FileQuery>,
+	Extension(files): Extension<Arc<FileServer>>,
+) -> Result<impl IntoResponse, HTTPError> {
+	let path = files.resolve(
+		&prediction_id,
+		&name,
+		query.expires,
+		query.signature.as_deref(),
+	)?;
+
+	let file = tokio::fs::File::open(&path).await.map_err(|_| {
+		HTTPError::new("The requested file does not exist").with_status(StatusCode::NOT_FOUND)
+	})?;
+
+	Ok((
+		[(
+			header::CONTENT_TYPE,
+			mime_guess::from_path(&path)
+				.first_or_octet_stream()
+				.to_string(),
+		)],
+		StreamBody::new(ReaderStream::new(file)),
+	))
+}
@@ -1,8 +1,10 @@
 use aide::axum::ApiRouter;
 
 mod docs;
+mod files;
 mod predict;
 mod system;
+mod train;
 
 pub fn handler() -> ApiRouter {
 	ApiRouter::new()
@@ -10,3 +12,6 @@ pub fn handler() -> ApiRouter {
 		.merge(predict::handler())
 		.merge(docs::handler())
 }
+
+pub use files::handler as files;
+pub use train::handler as trainings;
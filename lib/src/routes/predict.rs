@@ -4,18 +4,25 @@ use aide::axum::{
 };
 use axum::{extract::Path, http::StatusCode, Extension, TypedHeader};
 use axum_jsonschema::Json;
-use cog_core::http::Status;
+use std::sync::Arc;
+use tokio::sync::RwLock;
 
 use crate::{
 	errors::HTTPError,
-	helpers::headers::Prefer,
-	prediction::{Extension as ExtractPrediction, ResponseHelpers, SyncGuard},
+	helpers::{headers::Prefer, is_path_segment},
+	prediction::{
+		Error as PredictionError, Extension as ExtractPrediction, Prediction, ResponseHelpers,
+		SyncGuard,
+	},
 };
 
 pub fn handler() -> ApiRouter {
 	ApiRouter::new()
 		.api_route("/predictions", post(create_prediction))
-		.api_route("/predictions/:prediction_id", put(create_prediction))
+		.api_route(
+			"/predictions/:prediction_id",
+			put(create_prediction).get(get_prediction),
+		)
 		.api_route(
 			"/predictions/:prediction_id/cancel",
 			post(cancel_prediction),
@@ -28,79 +35,95 @@ async fn create_prediction(
 	Extension(prediction): ExtractPrediction,
 	Json(req): Json<cog_core::http::Request>,
 ) -> Result<(StatusCode, Json<cog_core::http::Response>), HTTPError> {
-	let id = id.map(|id| id.0);
+	create("prediction", id.map(|id| id.0), prefer, prediction, req).await
+}
+
+/// Run (or wait for, or check on) a prediction, or a training job when `kind` is "training".
+pub(super) async fn create(
+	kind: &'static str,
+	id: Option<String>,
+	prefer: Option<TypedHeader<Prefer>>,
+	prediction: Arc<RwLock<Prediction>>,
+	req: cog_core::http::Request,
+) -> Result<(StatusCode, Json<cog_core::http::Response>), HTTPError> {
 	let respond_async = prefer
 		.map(|prefer| prefer.0)
 		.unwrap_or_default()
 		.has("respond-async");
 
 	tracing::debug!(
-		"Received {}prediction request{}.",
+		"Received {}{kind} request{}.",
 		if respond_async { "async " } else { "" },
 		id.as_ref()
 			.map_or(String::new(), |id| format!(" with id {id}")),
 	);
 	tracing::trace!("{req:?}");
 
+	// Ids name the directories output files are stored in, so they must not be able to escape them.
+	if id.as_deref().is_some_and(|id| !is_path_segment(id)) {
+		return Err(HTTPError::new(&format!("Invalid {kind} id")));
+	}
+
 	let r_prediction = prediction.read().await;
 
 	// If a named prediction is already running...
-	if let Some(prediction_id) = r_prediction.id.clone() {
+	if let (Some(prediction_id), Some(id)) = (r_prediction.id.clone(), id.clone()) {
 		// ...and the request is for a different prediction, return an error.
-		if let Some(id) = id.clone() {
-			if Some(prediction_id.clone()) != Some(id.clone()) {
-				tracing::debug!(
-					"Trying to run a named prediction {id} while another prediction {prediction_id} is running"
-				);
-				return Err(HTTPError::new("Already running a prediction")
-					.with_status(StatusCode::CONFLICT));
-			}
-
-			// ...and this is an async request, return the current response.
-			if respond_async {
-				return Ok((
-					StatusCode::ACCEPTED,
-					Json(r_prediction.response.clone().unwrap()),
-				));
-			}
-
-			// wait for the current prediction to complete
-			return Ok((StatusCode::OK, Json(r_prediction.wait_for(id).await?)));
+		if prediction_id != id {
+			tracing::debug!(
+				"Trying to run a named {kind} {id} while another {kind} {prediction_id} is running"
+			);
+			return Err(already_running(kind));
 		}
-	}
 
-	// If the request is synchronous, run the prediction and return the result.
-	if !respond_async {
+		// ...and this is an async request, return the current response.
+		if respond_async {
+			return Ok((StatusCode::ACCEPTED, Json(r_prediction.current(&id)?)));
+		}
+
+		// wait for the current prediction to complete
+		let complete = r_prediction.wait_for(&id)?;
 		drop(r_prediction);
-		let mut prediction = SyncGuard::new(prediction.write().await);
 
-		return Ok((StatusCode::OK, Json(prediction.init(id, req)?.run().await?)));
+		return Ok((StatusCode::OK, Json(complete.await?)));
 	}
+	drop(r_prediction);
 
-	// If there's a running prediction, return an error.
-	if !matches!(r_prediction.status, Status::Idle) {
-		return Err(
-			HTTPError::new("Already running a prediction").with_status(StatusCode::CONFLICT)
-		);
+	// If the request is synchronous, run the prediction and return the result.
+	if !respond_async {
+		let response =
+			SyncGuard::new(prediction)
+				.run(id, req)
+				.await
+				.map_err(|error| match error {
+					PredictionError::AlreadyRunning => already_running(kind),
+					error => error.into(),
+				})?;
+
+		return Ok((StatusCode::OK, Json(response)));
 	}
 
-	// Throw an error if the request is invalid.
-	r_prediction.validate(&req.input)?;
-	drop(r_prediction);
+	// Throw an error if there's a running prediction, the server is shutting down or the request is invalid.
+	Prediction::resolve(&prediction, &req).await?;
+	let mut w_prediction = prediction.write().await;
+	w_prediction
+		.init(id.clone(), req.clone())
+		.map_err(|error| match error {
+			PredictionError::AlreadyRunning => already_running(kind),
+			error => error.into(),
+		})?;
+	drop(w_prediction);
 
-	let thread_req = req.clone();
 	let thread_id = id.clone();
 	tokio::spawn(async move {
-		tracing::debug!("Running prediction asynchronously: {:?}", thread_id);
+		tracing::debug!("Running {kind} asynchronously: {:?}", thread_id);
 
-		let mut prediction = prediction.write().await;
-
-		prediction.init(thread_id.clone(), thread_req).unwrap();
-		prediction.process().unwrap().await;
-		prediction.reset();
-		drop(prediction);
+		if let Err(error) = Prediction::process(&prediction).await {
+			tracing::error!("Failed to run asynchronous {kind}: {error}");
+		}
+		prediction.write().await.reset();
 
-		tracing::debug!("Asynchronous prediction complete: {thread_id:?}");
+		tracing::debug!("Asynchronous {kind} complete: {thread_id:?}");
 	});
 
 	Ok((
@@ -109,13 +132,73 @@ async fn create_prediction(
 	))
 }
 
+/// Wait for (or, with `Prefer: respond-async`, check on) the running prediction with the given id, without ever starting it.
+async fn get_prediction(
+	Path(id): Path<String>,
+	prefer: Option<TypedHeader<Prefer>>,
+	Extension(prediction): ExtractPrediction,
+) -> Result<(StatusCode, Json<cog_core::http::Response>), HTTPError> {
+	let respond_async = prefer
+		.map(|prefer| prefer.0)
+		.unwrap_or_default()
+		.has("respond-async");
+
+	let r_prediction = prediction.read().await;
+	if respond_async {
+		return Ok((StatusCode::ACCEPTED, Json(r_prediction.current(&id)?)));
+	}
+
+	let complete = r_prediction.wait_for(&id)?;
+	drop(r_prediction);
+
+	Ok((StatusCode::OK, Json(complete.await?)))
+}
+
+fn already_running(kind: &str) -> HTTPError {
+	HTTPError::new(&format!("Already running a {kind}")).with_status(StatusCode::CONFLICT)
+}
+
 async fn cancel_prediction(
 	Path(id): Path<String>,
 	Extension(prediction): ExtractPrediction,
+) -> Result<Json<()>, HTTPError> {
+	cancel(&id, &prediction).await
+}
+
+pub(super) async fn cancel(
+	id: &str,
+	prediction: &RwLock<Prediction>,
 ) -> Result<Json<()>, HTTPError> {
 	let mut prediction = prediction.write().await;
-	prediction.cancel(&id)?;
+	prediction.cancel(id)?;
 	drop(prediction);
 
 	Ok(Json(()))
 }
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		test_support::{predict, set_up, TestModel},
+		ServerBuilder,
+	};
+	use axum::http::{Method, StatusCode};
+	use std::time::Duration;
+	use tower::ServiceExt;
+
+	#[tokio::test]
+	async fn prediction_ids_cannot_escape_the_output_directory() {
+		let router = ServerBuilder::<TestModel>::new()
+			.serve_output_files(None, Duration::from_mins(1))
+			.into_router()
+			.unwrap();
+		set_up(router.clone()).await;
+
+		let response = router
+			.oneshot(predict(Method::PUT, "/predictions/..%2F..%2Fetc", "hello"))
+			.await
+			.unwrap();
+
+		assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+	}
+}
@@ -1,17 +1,22 @@
-use std::sync::atomic::Ordering;
+use std::{
+	sync::{atomic::Ordering, Arc},
+	time::Duration,
+};
 
 use aide::axum::{
 	routing::{get, post},
 	ApiRouter,
 };
-use axum::Extension;
+use axum::{http::header::CONTENT_TYPE, response::IntoResponse, Extension};
 use axum_jsonschema::Json;
 use chrono::Utc;
-use cog_core::http::Status;
+use cog_core::{http::Status, HealthReport, Progress, Reporter};
 use schemars::JsonSchema;
 
 use crate::{
-	runner::{Health, RUNNER_HEALTH},
+	metrics::Registry,
+	prediction::Training,
+	runner::{AtomicHealth, Health, HealthChecks},
 	shutdown::Agent as Shutdown,
 };
 
@@ -20,13 +25,14 @@ pub fn handler() -> ApiRouter {
 		.api_route("/", get(root))
 		.api_route("/health-check", get(health_check))
 		.api_route("/shutdown", post(shutdown))
+		.route("/metrics", axum::routing::get(metrics))
 }
 
 #[derive(Debug, serde::Serialize, JsonSchema)]
 pub struct RootResponse {
 	/// Relative URL to Swagger UI
 	pub docs_url: String,
-	/// Relative URL to OpenAPI specification
+	/// Relative URL to `OpenAPI` specification
 	pub openapi_url: String,
 }
 
@@ -48,24 +54,50 @@ pub struct HealthCheckSetup {
 	pub started_at: String,
 	/// Setup completed time
 	pub completed_at: String,
+	/// Progress of the current setup step, if the model reports it
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub progress: Option<Progress>,
 }
 
+/// How long a request to `/health-check` waits for the model's `health_check()`, before reporting the previous result.
+const MODEL_HEALTH_CHECK_WAIT: Duration = Duration::from_secs(1);
+
 #[derive(Debug, serde::Serialize, JsonSchema)]
 pub struct HealthCheck {
 	/// Current health status
 	pub status: Health,
 	/// Setup information
 	pub setup: HealthCheckSetup,
+	/// The model's own health check, once it has run
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub model: Option<HealthReport>,
 }
 
-#[allow(clippy::unused_async)]
-pub async fn health_check() -> Json<HealthCheck> {
-	let status = RUNNER_HEALTH.load(Ordering::SeqCst);
+pub async fn health_check(
+	Extension(health): Extension<Arc<AtomicHealth>>,
+	Extension(checks): Extension<HealthChecks>,
+	Extension(reporter): Extension<Reporter>,
+	training: Option<Extension<Training>>,
+) -> Json<HealthCheck> {
+	let model = checks.refresh(MODEL_HEALTH_CHECK_WAIT).await;
+	let status = health.load(Ordering::SeqCst);
+
+	// The server isn't ready until the trainer (if any) is set up too.
+	let status = match training.map(|training| training.health.load(Ordering::SeqCst)) {
+		Some(training @ (Health::Unknown | Health::Starting | Health::SetupFailed))
+			if !matches!(status, Health::SetupFailed) =>
+		{
+			training
+		},
+		_ => status,
+	};
 
 	Json(HealthCheck {
+		model,
 		status,
 		setup: HealthCheckSetup {
-			logs: String::new(),
+			logs: reporter.logs(),
+			progress: reporter.last_progress(),
 			status: match status {
 				Health::Unknown | Health::Starting => Status::Starting,
 				Health::SetupFailed => Status::Failed,
@@ -83,3 +115,12 @@ pub async fn shutdown(Extension(shutdown): Extension<Shutdown>) -> Json<String>
 
 	Json(String::new())
 }
+
+/// The metrics of completed predictions, in the Prometheus text format.
+#[allow(clippy::unused_async)]
+pub async fn metrics(Extension(metrics): Extension<Arc<Registry>>) -> impl IntoResponse {
+	(
+		[(CONTENT_TYPE, "text/plain; version=0.0.4")],
+		metrics.render(),
+	)
+}
@@ -0,0 +1,36 @@
+use aide::axum::{
+	routing::{post, put},
+	ApiRouter,
+};
+use axum::{extract::Path, http::StatusCode, Extension, TypedHeader};
+use axum_jsonschema::Json;
+
+use super::predict;
+use crate::{
+	errors::HTTPError,
+	helpers::headers::Prefer,
+	prediction::{Training, TrainingExtension as ExtractTraining},
+};
+
+pub fn handler() -> ApiRouter {
+	ApiRouter::new()
+		.api_route("/trainings", post(create_training))
+		.api_route("/trainings/:training_id", put(create_training))
+		.api_route("/trainings/:training_id/cancel", post(cancel_training))
+}
+
+async fn create_training(
+	id: Option<Path<String>>,
+	prefer: Option<TypedHeader<Prefer>>,
+	Extension(Training { training, .. }): ExtractTraining,
+	Json(req): Json<cog_core::http::Request>,
+) -> Result<(StatusCode, Json<cog_core::http::Response>), HTTPError> {
+	predict::create("training", id.map(|id| id.0), prefer, training, req).await
+}
+
+async fn cancel_training(
+	Path(id): Path<String>,
+	Extension(Training { training, .. }): ExtractTraining,
+) -> Result<Json<()>, HTTPError> {
+	predict::cancel(&id, &training).await
+}
@@ -1,19 +1,39 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use atomic_enum::atomic_enum;
-use cog_core::{Cog, CogResponse};
+use cog_core::{
+	http::ValidationError, Cog, CogResponse, HealthReport, HealthStatus, ModelError, SetupContext,
+	Train,
+};
 use jsonschema::JSONSchema;
 use schemars::{schema_for, JsonSchema};
+use serde::de::DeserializeOwned;
 use serde_json::Value;
 use std::{
+	collections::HashMap,
 	env,
-	panic::{catch_unwind, AssertUnwindSafe},
-	sync::{atomic::Ordering, Arc, Mutex},
+	fmt::Debug,
+	future::Future,
+	pin::pin,
+	sync::{atomic::Ordering, Arc},
 	time::{Duration, Instant},
 };
-use tokio::sync::{mpsc, oneshot};
+use tokio::{
+	sync::{mpsc, oneshot, watch, Mutex, Notify},
+	time::{Interval, MissedTickBehavior},
+};
 use tracing::{trace_span, Instrument};
+use url::Url;
+use uuid::Uuid;
 
-use crate::{errors::ValidationErrorSet, shutdown::Shutdown};
+use crate::{
+	egress::EgressPolicy,
+	errors::ValidationErrorSet,
+	inputs::Inputs,
+	metrics,
+	outputs::{Destination, Outputs},
+	panics::{self, Panic},
+	shutdown::Shutdown,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -23,16 +43,48 @@ pub enum Error {
 	#[error("Prediction was canceled")]
 	Canceled,
 
-	#[error("The model panicked.")]
-	Panic,
+	#[error("{0}")]
+	Panic(Panic),
 
 	#[error("Failed to validate input.")]
 	Validation(ValidationErrorSet),
 
+	#[error("Failed to process input: {0}")]
+	Input(serde_json::Error),
+
+	#[error("{0}")]
+	Model(ModelError),
+
 	#[error("Failed to run prediction: {0}")]
 	Prediction(#[from] anyhow::Error),
 }
 
+impl Error {
+	/// The error returned by the model's prediction, telling apart the model's own [`ModelError`]s from any other failure.
+	fn from_prediction(error: anyhow::Error) -> Self {
+		let model_error = error
+			.chain()
+			.find_map(|error| error.downcast_ref::<ModelError>())
+			.cloned();
+
+		model_error.map_or(Self::Prediction(error), Self::Model)
+	}
+
+	/// A machine-readable description of the error, safe to show to users.
+	pub fn model_error(&self) -> ModelError {
+		match self {
+			Self::Model(error) => error.clone(),
+			Self::Input(_) | Self::Validation(_) => {
+				ModelError::new("invalid_input", "The input is invalid")
+			},
+			Self::Busy => ModelError::new("busy", "The model is busy").retryable(),
+			Self::Canceled => ModelError::new("canceled", "The prediction was canceled"),
+			Self::Panic(_) => ModelError::new("internal_error", "The model crashed"),
+			Self::Prediction(_) => ModelError::new("internal_error", "The prediction failed"),
+		}
+	}
+}
+
 #[atomic_enum]
 #[derive(serde::Serialize, JsonSchema)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
@@ -42,151 +94,1020 @@ pub enum Health {
 	Ready,
 	Busy,
 	SetupFailed,
+	/// The model's health check failed, so it won't get predictions until it passes again.
+	Unhealthy,
+	/// The model's health check reported it can't recover, or the runner stopped.
+	Defunct,
+}
+
+/// How long the model's `health_check()` may take before the model is considered unhealthy.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type Metrics = HashMap<String, Value>;
+
+/// Something the runner can set up and then run inputs through: a [`Cog`] model's predictions, or a [`Train`] trainer's training jobs.
+pub trait Model: Sized + Send + 'static {
+	/// What a job is called, like "prediction".
+	const KIND: &'static str;
+
+	type Input: DeserializeOwned + JsonSchema + Send;
+	type Output: CogResponse + Debug + 'static;
+
+	fn setup(ctx: SetupContext) -> impl Future<Output = Result<Self>> + Send;
+	fn run(&self, input: Self::Input) -> Result<Self::Output>;
+	fn health_check(&mut self) -> impl Future<Output = HealthReport> + Send;
+	fn teardown(&mut self) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Runs predictions on a [`Cog`] model.
+pub struct Predictor<T>(T);
+
+impl<T: Cog + 'static> Model for Predictor<T> {
+	const KIND: &'static str = "prediction";
+
+	type Input = T::Request;
+	type Output = T::Response;
+
+	async fn setup(ctx: SetupContext) -> Result<Self> {
+		T::setup(ctx).await.map(Self)
+	}
+
+	fn run(&self, input: Self::Input) -> Result<Self::Output> {
+		let _span = trace_span!("cog_predict").entered();
+		self.0.predict(input)
+	}
+
+	fn health_check(&mut self) -> impl Future<Output = HealthReport> + Send {
+		self.0.health_check()
+	}
+
+	fn teardown(&mut self) -> impl Future<Output = Result<()>> + Send {
+		self.0.teardown()
+	}
+}
+
+/// Runs training jobs on a [`Train`] trainer.
+pub struct Trainer<T>(T);
+
+impl<T: Train + 'static> Model for Trainer<T> {
+	const KIND: &'static str = "training";
+
+	type Input = T::Request;
+	type Output = T::Response;
+
+	async fn setup(ctx: SetupContext) -> Result<Self> {
+		T::setup(ctx).await.map(Self)
+	}
+
+	fn run(&self, input: Self::Input) -> Result<Self::Output> {
+		let _span = trace_span!("cog_train").entered();
+		self.0.train(input)
+	}
+
+	async fn health_check(&mut self) -> HealthReport {
+		HealthReport::healthy()
+	}
+
+	fn teardown(&mut self) -> impl Future<Output = Result<()>> + Send {
+		self.0.teardown()
+	}
 }
 
-pub static RUNNER_HEALTH: AtomicHealth = AtomicHealth::new(Health::Unknown);
+/// How the runner sets the model up and tears it down.
+#[derive(Debug, Clone, Copy)]
+pub struct Lifecycle {
+	/// How long `setup()` may take before it's considered failed.
+	pub setup_timeout: Duration,
+	/// How many times to retry `setup()` if it fails (or times out).
+	pub setup_retries: u32,
+	/// How long to wait before the first retry, doubling after every retry.
+	pub setup_backoff: Duration,
+	/// How long `teardown()` (including waiting for the running prediction to stop) may take when shutting down.
+	pub teardown_timeout: Duration,
+	/// How often to run the model's `health_check()` while it's idle, if at all.
+	pub health_check_interval: Option<Duration>,
+	/// After how many panics the model is considered unhealthy (since its state may be inconsistent), if ever.
+	pub panic_threshold: Option<u32>,
+}
 
-type ResponseSender = oneshot::Sender<Result<(Value, Duration), Error>>;
+impl Lifecycle {
+	/// Whether the model panicked too many times to be trusted.
+	fn poisoned(&self, panics: u32) -> bool {
+		self.panic_threshold
+			.is_some_and(|threshold| panics >= threshold)
+	}
+}
+
+impl Default for Lifecycle {
+	fn default() -> Self {
+		Self {
+			setup_retries: 0,
+			setup_timeout: Duration::from_mins(5),
+			setup_backoff: Duration::from_secs(1),
+			teardown_timeout: Duration::from_secs(30),
+			health_check_interval: Some(Duration::from_secs(30)),
+			panic_threshold: Some(3),
+		}
+	}
+}
+
+/// The latest report from the model's `health_check()`, and a way to ask for a new one.
+#[derive(Debug, Clone)]
+pub struct HealthChecks {
+	health: Arc<AtomicHealth>,
+	report: watch::Receiver<Option<HealthReport>>,
+	requests: Arc<Notify>,
+}
+
+impl HealthChecks {
+	/// The model's latest health report, running a new check first (waiting up to `timeout` for it) if the model is idle.
+	pub async fn refresh(&self, timeout: Duration) -> Option<HealthReport> {
+		let mut report = self.report.clone();
+
+		if matches!(
+			self.health.load(Ordering::SeqCst),
+			Health::Ready | Health::Unhealthy
+		) {
+			report.borrow_and_update();
+			self.requests.notify_one();
+			tokio::time::timeout(timeout, report.changed()).await.ok();
+		}
+
+		let report = report.borrow().clone();
+		report
+	}
+}
+
+type ResponseSender = oneshot::Sender<Result<(Value, Metrics), Error>>;
+type RunnerMessage = (
+	ResponseSender,
+	Option<String>,
+	cog_core::http::Request,
+	Instant,
+	oneshot::Receiver<()>,
+);
 
 #[derive(Clone)]
 pub struct Runner {
+	health: Arc<AtomicHealth>,
+	checks: HealthChecks,
 	schema: Arc<JSONSchema>,
-	sender: mpsc::Sender<(ResponseSender, cog_core::http::Request)>,
+	input_schema: Arc<Value>,
+	egress: Arc<EgressPolicy>,
+	sender: mpsc::Sender<RunnerMessage>,
 }
 
 impl Runner {
-	pub fn new<T: Cog + 'static>(shutdown: Shutdown, cancel: flume::Receiver<()>) -> Self {
-		RUNNER_HEALTH.swap(Health::Starting, Ordering::SeqCst);
-
-		let (sender, mut rx) = mpsc::channel::<(ResponseSender, cog_core::http::Request)>(1);
-
-		let handle_shutdown = shutdown.clone();
-		let handle = tokio::spawn(async move {
-			tracing::info!("Running setup()...");
-			let cog = tokio::select! {
-				() = tokio::time::sleep(Duration::from_secs(5 * 60)) => {
-					tracing::error!("Failed run setup(): Timed out");
-					RUNNER_HEALTH.swap(Health::SetupFailed, Ordering::SeqCst);
-					handle_shutdown.start();
-					return;
-				}
-				cog = T::setup().instrument(trace_span!("cog_setup")) => {
-					match cog {
-						Ok(cog) => Arc::new(Mutex::new(cog)),
-						Err(error) => {
-							tracing::error!("Failed run setup(): {error}");
-							RUNNER_HEALTH.swap(Health::SetupFailed, Ordering::SeqCst);
-							handle_shutdown.start();
-							return;
-						}
-					}
-				}
-			};
+	#[allow(clippy::too_many_lines)]
+	pub fn new<M: Model>(
+		shutdown: Shutdown,
+		destination: Destination,
+		inputs: Inputs,
+		ctx: SetupContext,
+		lifecycle: Lifecycle,
+	) -> Self {
+		let health = Arc::new(AtomicHealth::new(Health::Starting));
+		let (sender, mut rx) = mpsc::channel::<RunnerMessage>(1);
+		let egress = inputs.egress();
+		let (reports, report) = watch::channel(None);
+		let checks = HealthChecks {
+			report,
+			health: health.clone(),
+			requests: Arc::new(Notify::new()),
+		};
 
-			tracing::debug!("setup() finished. Cog is ready to accept predictions.");
-			RUNNER_HEALTH.swap(Health::Ready, Ordering::SeqCst);
-			if env::var("KUBERNETES_SERVICE_HOST").is_ok() {
-				if let Err(err) = tokio::fs::create_dir_all("/var/run/cog").await {
-					tracing::error!("Failed to create cog runtime state directory: {err}");
-					RUNNER_HEALTH.swap(Health::SetupFailed, Ordering::SeqCst);
-					handle_shutdown.start();
-					return;
-				}
+		let (task_health, setup_egress) = (health.clone(), egress.clone());
+		let guard = shutdown.guard();
+		let check_requests = checks.requests.clone();
+		tokio::spawn(async move {
+			let _guard = guard;
+			let mut stopped = pin!(shutdown.handle());
 
-				if let Err(error) = tokio::fs::File::create("/var/run/cog/ready").await {
-					tracing::error!("Failed to signal cog is ready: {error}");
-					RUNNER_HEALTH.swap(Health::SetupFailed, Ordering::SeqCst);
-					handle_shutdown.start();
-					return;
-				}
-			}
+			let started = tokio::select! {
+				cog = setup_egress.scope(start::<M>(ctx, lifecycle, &task_health)) => cog,
+				() = &mut stopped => return,
+			};
+			let Some(cog) = started else {
+				task_health.swap(Health::SetupFailed, Ordering::SeqCst);
+				shutdown.start();
+				return;
+			};
+			let cog = Arc::new(Mutex::new(cog));
 
-			// Cog is not Sync, so we wrap it with a Mutex and this function to run it from an async context (and thus make it cancellable).
-			let run_prediction_async = |input| {
-				async {
-					let cog = cog.lock().unwrap();
+			let mut interval = lifecycle.health_check_interval.map(health_check_interval);
 
-					catch_unwind(AssertUnwindSafe(|| cog.predict(input)))
-				}
-				.instrument(trace_span!("cog_predict"))
-			};
+			let mut panics = 0;
+			loop {
+				let panicked = lifecycle.poisoned(panics).then_some(panics);
+				let (tx, id, req, enqueued, mut canceled) = tokio::select! {
+					message = rx.recv() => match message {
+						Some(message) => message,
+						None => break,
+					},
+					() = &mut stopped => break,
+					() = check_requests.notified() => {
+						check_health(&cog, &task_health, &reports, panicked).await;
+						continue;
+					},
+					() = tick(interval.as_mut()) => {
+						check_health(&cog, &task_health, &reports, panicked).await;
+						continue;
+					},
+				};
 
-			while let Some((tx, req)) = rx.recv().await {
 				tracing::debug!("Processing prediction: {req:?}");
-				RUNNER_HEALTH.swap(Health::Busy, Ordering::SeqCst);
+				task_health.swap(Health::Busy, Ordering::SeqCst);
 
-				// We need spawn_blocking here to (sneakily) allow blocking code in serde Deserialize impls (used in `Path`, for example).
-				let input = req.input.clone();
-				let input =
-					tokio::task::spawn_blocking(move || serde_json::from_value(input).unwrap())
-						.await
-						.unwrap();
+				let queue = enqueued.elapsed();
+				let (input, download) = tokio::select! {
+					Ok(()) = &mut canceled => {
+						tracing::debug!("Prediction canceled");
+						task_health.swap(Health::Ready, Ordering::SeqCst);
+						let _ = tx.send(Err(Error::Canceled));
+						continue;
+					},
+					() = &mut stopped => {
+						let _ = tx.send(Err(Error::Canceled));
+						tracing::debug!("Shutting down, prediction canceled");
+						break;
+					},
+					input = timed(deserialize_input(req.input.clone(), inputs.clone())) => input,
+				};
+				let input = match input {
+					Ok(input) => input,
+					Err(error) => {
+						tracing::error!("Failed to process input: {error}");
+						task_health.swap(Health::Ready, Ordering::SeqCst);
+						let _ = tx.send(Err(Error::Input(error)));
+						continue;
+					},
+				};
 
-				let start = Instant::now();
-				tokio::select! {
-					_ = cancel.recv_async() => {
+				let mut model = pin!(timed(run_model(cog.clone(), input)));
+				let ((response, predict), tx) = tokio::select! {
+					Ok(()) = &mut canceled => {
 						let _ = tx.send(Err(Error::Canceled));
-						tracing::debug!("Prediction canceled");
+						tracing::debug!("Prediction canceled, waiting for predict() to return");
+
+						// predict() can't be interrupted, and holds the model until it returns, so the runner stays busy until then.
+						tokio::select! {
+							output = &mut model => (output, None),
+							() = &mut stopped => break,
+						}
+					},
+					() = &mut stopped => {
+						let _ = tx.send(Err(Error::Canceled));
+						tracing::debug!("Shutting down, prediction canceled");
+						break;
 					},
-					response = run_prediction_async(input) => {
-						tracing::debug!("Prediction complete: {response:?}");
-						let _ = tx.send(match response {
-							Err(_) => Err(Error::Panic),
-							Ok(Err(error)) => Err(Error::Prediction(error)),
-							Ok(Ok(response)) => match response.into_response(req).await {
-								Err(error) => Err(Error::Prediction(error)),
-								Ok(response) => Ok((response, start.elapsed())),
-							},
-						});
-					}
+					output = &mut model => (output, Some(tx)),
+				};
+
+				tracing::debug!("Prediction complete: {response:?}");
+				if let Err(Error::Panic(panic)) = &response {
+					panics += 1;
+					count_panic(panic, panics, &lifecycle, &task_health);
 				}
 
-				RUNNER_HEALTH.swap(Health::Ready, Ordering::SeqCst);
+				let result = match (response, &tx) {
+					(Err(error), _) => Err(error),
+					(Ok(_), None) => Err(Error::Canceled),
+					(Ok((response, recorded)), Some(_)) => {
+						let timings = Timings {
+							enqueued,
+							queue,
+							download,
+							predict,
+						};
+						respond(
+							response,
+							req,
+							id,
+							&destination,
+							inputs.egress(),
+							timings,
+							recorded,
+						)
+						.await
+					},
+				};
+
+				// Mark the runner as ready before responding, so the next prediction can be submitted right away.
+				if !lifecycle.poisoned(panics) {
+					task_health.swap(Health::Ready, Ordering::SeqCst);
+				}
+				if let Some(tx) = tx {
+					let _ = tx.send(result);
+				}
 			}
-		});
 
-		tokio::spawn(async move {
-			shutdown.handle().await;
+			// The runner won't take any more predictions.
+			task_health.swap(Health::Defunct, Ordering::SeqCst);
 			tracing::debug!("Shutting down runner...");
-			handle.abort();
+			teardown(&cog, lifecycle.teardown_timeout).await;
 		});
 
-		let schema = jsonschema::JSONSchema::compile(
-			&serde_json::to_value(schema_for!(T::Request)).unwrap(),
-		)
-		.unwrap();
+		let input_schema = serde_json::to_value(schema_for!(M::Input)).unwrap();
+		let schema = jsonschema::JSONSchema::compile(&input_schema).unwrap();
 
 		Self {
 			sender,
+			health,
+			checks,
+			egress,
 			schema: Arc::new(schema),
+			input_schema: Arc::new(input_schema),
 		}
 	}
 
+	/// A handle to the runner's health, which is updated as it sets up and runs predictions.
+	pub fn health(&self) -> Arc<AtomicHealth> {
+		self.health.clone()
+	}
+
+	/// A handle to the model's health checks.
+	pub fn health_checks(&self) -> HealthChecks {
+		self.checks.clone()
+	}
+
+	/// Validate the input against the model's schema, and any urls in it (like `Path` inputs) against the egress policy.
 	pub fn validate(&self, input: &Value) -> Result<(), ValidationErrorSet> {
 		self.schema.validate(input)?;
 
+		let errors = self
+			.urls(input)
+			.into_iter()
+			.filter_map(|(loc, url)| {
+				let error = self.egress.check(&url).err()?;
+
+				Some(ValidationError {
+					loc,
+					msg: error.to_string(),
+				})
+			})
+			.collect::<Vec<_>>();
+
+		if !errors.is_empty() {
+			return Err(ValidationErrorSet { errors });
+		}
+
 		Ok(())
 	}
 
-	pub async fn run(&self, req: cog_core::http::Request) -> Result<(Value, Duration), Error> {
-		if !matches!(RUNNER_HEALTH.load(Ordering::SeqCst), Health::Ready) {
+	/// The urls in the input (like `Path` inputs), along with their location.
+	pub fn urls(&self, input: &Value) -> Vec<(Vec<String>, Url)> {
+		let mut urls = Vec::new();
+		find_urls(
+			&self.input_schema,
+			&self.input_schema,
+			input,
+			&[],
+			&mut urls,
+		);
+
+		urls.into_iter()
+			.filter_map(|(loc, url)| Some((loc, Url::parse(url).ok()?)))
+			.collect()
+	}
+
+	/// Run a prediction, which is canceled if `canceled` receives a message.
+	pub async fn run(
+		&self,
+		id: Option<String>,
+		req: cog_core::http::Request,
+		canceled: oneshot::Receiver<()>,
+	) -> Result<(Value, Metrics), Error> {
+		self.validate(&req.input).map_err(Error::Validation)?;
+
+		if self
+			.health
+			.compare_exchange(
+				Health::Ready,
+				Health::Busy,
+				Ordering::SeqCst,
+				Ordering::SeqCst,
+			)
+			.is_err()
+		{
 			tracing::debug!("Failed to run prediction: runner is busy");
 			return Err(Error::Busy);
 		}
 
-		self.validate(&req.input).map_err(Error::Validation)?;
-		RUNNER_HEALTH.swap(Health::Busy, Ordering::SeqCst);
-
 		let (tx, rx) = oneshot::channel();
 
 		tracing::debug!("Sending prediction to runner: {req:?}");
-		let _ = self.sender.send((tx, req)).await;
+		let message = (tx, id, req, Instant::now(), canceled);
+		if self.sender.send(message).await.is_err() {
+			tracing::debug!("Failed to run prediction: runner has stopped");
+			return Err(Error::Canceled);
+		}
+
 		tracing::debug!("Waiting for prediction response...");
-		let result = rx.await.unwrap();
+		// The runner drops the prediction without responding if it stops.
+		let result = rx.await.unwrap_or(Err(Error::Canceled));
 		tracing::debug!("Prediction response received: {result:?}");
 
-		RUNNER_HEALTH.swap(Health::Ready, Ordering::SeqCst);
-
 		result
 	}
 }
+
+/// Create the model's scratch directory and run its `setup()`, retrying it (and recording every attempt in the setup logs) as configured.
+async fn setup<M: Model>(ctx: SetupContext, lifecycle: Lifecycle) -> Result<M> {
+	tokio::fs::create_dir_all(ctx.scratch_dir())
+		.await
+		.context("Failed to create scratch directory")?;
+
+	let reporter = ctx.reporter().clone();
+	let attempts = lifecycle.setup_retries + 1;
+	let mut backoff = lifecycle.setup_backoff;
+	let mut attempt = 1;
+
+	loop {
+		reporter.log(format!("Running setup() (attempt {attempt}/{attempts})"));
+		let result = tokio::time::timeout(
+			lifecycle.setup_timeout,
+			M::setup(ctx.clone()).instrument(trace_span!("cog_setup", attempt)),
+		)
+		.await
+		.unwrap_or_else(|_| {
+			Err(anyhow::anyhow!(
+				"Timed out after {:?}",
+				lifecycle.setup_timeout
+			))
+		});
+
+		match result {
+			Ok(cog) => {
+				reporter.log(format!("setup() succeeded (attempt {attempt}/{attempts})"));
+				return Ok(cog);
+			},
+			Err(error) if attempt < attempts => {
+				reporter.log(format!(
+					"setup() failed (attempt {attempt}/{attempts}): {error:#}. Retrying in {backoff:?}"
+				));
+				tokio::time::sleep(backoff).await;
+
+				backoff = backoff.saturating_mul(2);
+				attempt += 1;
+			},
+			Err(error) => return Err(error),
+		}
+	}
+}
+
+/// Wait for the running prediction (if any) to stop, then run the model's `teardown()`, giving up once the timeout elapses.
+async fn teardown<M: Model>(cog: &Mutex<M>, timeout: Duration) {
+	tracing::info!("Running teardown()...");
+	let teardown = async { cog.lock().await.teardown().await };
+
+	match tokio::time::timeout(timeout, teardown.instrument(trace_span!("cog_teardown"))).await {
+		Ok(Ok(())) => tracing::debug!("teardown() finished."),
+		Ok(Err(error)) => tracing::error!("Failed to run teardown(): {error:#}"),
+		Err(_) => tracing::error!("Failed to run teardown(): Timed out after {timeout:?}"),
+	}
+}
+
+/// Log a panic, marking the model as unhealthy once it panicked too many times.
+fn count_panic(panic: &Panic, panics: u32, lifecycle: &Lifecycle, health: &AtomicHealth) {
+	tracing::error!("{panic}");
+
+	if lifecycle.poisoned(panics) {
+		tracing::error!("The model panicked {panics} times, marking it as unhealthy");
+		health.swap(Health::Unhealthy, Ordering::SeqCst);
+	}
+}
+
+/// When a prediction was queued, and how long its phases took.
+struct Timings {
+	enqueued: Instant,
+	/// Waiting for the runner to pick the prediction up.
+	queue: Duration,
+	/// Deserializing the input, including downloading any files in it.
+	download: Duration,
+	/// Running the model.
+	predict: Duration,
+}
+
+/// Run a future, measuring how long it takes.
+async fn timed<T>(future: impl Future<Output = T>) -> (T, Duration) {
+	let start = Instant::now();
+	let output = future.await;
+
+	(output, start.elapsed())
+}
+
+/// Serialize a successful prediction's output (uploading any files in it), with its metrics: the ones the model recorded, and how long each phase of the prediction took.
+async fn respond<T: CogResponse + 'static>(
+	response: T,
+	req: cog_core::http::Request,
+	id: Option<String>,
+	destination: &Destination,
+	egress: Arc<EgressPolicy>,
+	timings: Timings,
+	recorded: HashMap<String, f64>,
+) -> Result<(Value, Metrics), Error> {
+	let outputs = Outputs::new(
+		id.unwrap_or_else(|| Uuid::new_v4().to_string()),
+		output_destination(&req, destination),
+		egress,
+	);
+	let (output, upload) = timed(serialize_response(response, req, outputs)).await;
+	let output = output.map_err(Error::Prediction)?;
+
+	let mut metrics = recorded
+		.into_iter()
+		.map(|(name, value)| (name, value.into()))
+		.collect::<Metrics>();
+	metrics.extend(
+		[
+			("queue_time", timings.queue),
+			("download_time", timings.download),
+			("predict_time", timings.predict),
+			("upload_time", upload),
+			("total_time", timings.enqueued.elapsed()),
+		]
+		.map(|(name, time)| (name.to_string(), time.as_secs_f64().into())),
+	);
+
+	Ok((output, metrics))
+}
+
+/// Set the model up and mark it as ready (letting Kubernetes know, when running there).
+async fn start<M: Model>(
+	ctx: SetupContext,
+	lifecycle: Lifecycle,
+	health: &AtomicHealth,
+) -> Option<M> {
+	tracing::info!("Running setup()...");
+	let reporter = ctx.reporter().clone();
+
+	let cog = match setup::<M>(ctx, lifecycle).await {
+		Ok(cog) => cog,
+		Err(error) => {
+			tracing::error!("Failed run setup(): {error:#}");
+			reporter.log(format!("setup() failed: {error:#}"));
+			return None;
+		},
+	};
+
+	tracing::debug!("setup() finished. Cog is ready to accept predictions.");
+	health.swap(Health::Ready, Ordering::SeqCst);
+	if env::var("KUBERNETES_SERVICE_HOST").is_ok() {
+		if let Err(error) = signal_ready().await {
+			tracing::error!("{error:#}");
+			return None;
+		}
+	}
+
+	Some(cog)
+}
+
+/// An interval that doesn't try to catch up with ticks missed while the model was busy.
+fn health_check_interval(period: Duration) -> Interval {
+	let mut interval = tokio::time::interval(period);
+	interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+	interval
+}
+
+/// Wait for the next tick of the health check interval, or forever if periodic checks are disabled.
+async fn tick(interval: Option<&mut Interval>) {
+	match interval {
+		Some(interval) => {
+			interval.tick().await;
+		},
+		None => std::future::pending().await,
+	}
+}
+
+/// Run the model's `health_check()`, updating the runner's health with its report (unless the model is already defunct).
+///
+/// Models that `panicked` too many times are unhealthy, whatever they report.
+async fn check_health<M: Model>(
+	cog: &Mutex<M>,
+	health: &AtomicHealth,
+	reports: &watch::Sender<Option<HealthReport>>,
+	panicked: Option<u32>,
+) {
+	if matches!(health.load(Ordering::SeqCst), Health::Defunct) {
+		return;
+	}
+
+	let check = async { cog.lock().await.health_check().await };
+	let report = match tokio::time::timeout(
+		HEALTH_CHECK_TIMEOUT,
+		panics::catch_unwind_async(check).instrument(trace_span!("cog_health_check")),
+	)
+	.await
+	{
+		Ok(Ok(report)) => report,
+		Ok(Err(panic)) => {
+			tracing::error!("{panic}");
+			HealthReport::unhealthy()
+				.with_details(format!("health_check() panicked: {}", panic.message))
+		},
+		Err(_) => HealthReport::unhealthy().with_details(format!(
+			"health_check() timed out after {HEALTH_CHECK_TIMEOUT:?}"
+		)),
+	};
+
+	let report = match panicked {
+		Some(panics) if report.status == HealthStatus::Healthy => HealthReport::unhealthy()
+			.with_details(format!(
+				"The model panicked {panics} times, and may be in an inconsistent state"
+			)),
+		_ => report,
+	};
+
+	if report.status != HealthStatus::Healthy {
+		tracing::warn!("Model is {:?}: {}", report.status, report.details);
+	}
+
+	apply_report(health, report.status);
+	reports.send_replace(Some(report));
+}
+
+/// Update the runner's health with the status the model reported.
+///
+/// Only a ready (or unhealthy) runner's health changes, since a prediction may have claimed the runner while the check ran.
+fn apply_report(health: &AtomicHealth, status: HealthStatus) {
+	let reported = match status {
+		HealthStatus::Healthy => Health::Ready,
+		HealthStatus::Unhealthy => Health::Unhealthy,
+		HealthStatus::Defunct => Health::Defunct,
+	};
+
+	let mut current = health.load(Ordering::SeqCst);
+	while matches!(current, Health::Ready | Health::Unhealthy) {
+		match health.compare_exchange(current, reported, Ordering::SeqCst, Ordering::SeqCst) {
+			Ok(_) => break,
+			Err(actual) => current = actual,
+		}
+	}
+}
+
+/// Let Kubernetes know the model is ready, by creating `/var/run/cog/ready`.
+async fn signal_ready() -> Result<()> {
+	tokio::fs::create_dir_all("/var/run/cog")
+		.await
+		.context("Failed to create cog runtime state directory")?;
+	tokio::fs::File::create("/var/run/cog/ready")
+		.await
+		.context("Failed to signal cog is ready")?;
+
+	Ok(())
+}
+
+/// Run the model on a blocking thread, so the prediction can be canceled (or the server shut down) while the model is running, returning its output and the metrics it recorded.
+/// Models are not Sync, so they're wrapped with a Mutex (which `teardown()` locks to wait for the running prediction to stop).
+async fn run_model<M: Model>(
+	cog: Arc<Mutex<M>>,
+	input: M::Input,
+) -> Result<(M::Output, HashMap<String, f64>), Error> {
+	let (output, recorded) = tokio::task::spawn_blocking(move || {
+		let cog = cog.blocking_lock();
+
+		metrics::capture(|| panics::catch_unwind(|| cog.run(input)))
+	})
+	.await
+	.map_err(|error| {
+		error.try_into_panic().map_or(Error::Canceled, |payload| {
+			Error::Panic(Panic::from_payload(&*payload))
+		})
+	})?;
+
+	let output = output
+		.map_err(Error::Panic)?
+		.map_err(Error::from_prediction)?;
+	Ok((output, recorded))
+}
+
+/// Collect the values (and their location) of `value` that the schema describes as urls.
+fn find_urls<'a>(
+	root: &Value,
+	schema: &Value,
+	value: &'a Value,
+	loc: &[String],
+	urls: &mut Vec<(Vec<String>, &'a str)>,
+) {
+	let schema = schema
+		.get("$ref")
+		.and_then(Value::as_str)
+		.and_then(|reference| root.pointer(reference.strip_prefix('#')?))
+		.unwrap_or(schema);
+
+	if schema.get("format").and_then(Value::as_str) == Some("uri") {
+		if let Some(url) = value.as_str() {
+			urls.push((loc.to_vec(), url));
+		}
+	}
+
+	for subschemas in ["allOf", "anyOf", "oneOf"]
+		.iter()
+		.filter_map(|key| schema.get(key).and_then(Value::as_array))
+	{
+		for subschema in subschemas {
+			find_urls(root, subschema, value, loc, urls);
+		}
+	}
+
+	let child_loc = |key: String| loc.iter().cloned().chain([key]).collect::<Vec<_>>();
+	match value {
+		Value::Object(map) => {
+			let Some(properties) = schema.get("properties") else {
+				return;
+			};
+
+			for (key, value) in map {
+				if let Some(property) = properties.get(key) {
+					find_urls(root, property, value, &child_loc(key.clone()), urls);
+				}
+			}
+		},
+		Value::Array(items) => {
+			let Some(item_schema) = schema.get("items") else {
+				return;
+			};
+
+			for (i, value) in items.iter().enumerate() {
+				find_urls(root, item_schema, value, &child_loc(i.to_string()), urls);
+			}
+		},
+		_ => {},
+	}
+}
+
+/// Deserialize the model's input, making the configured scheme handlers available to `Path` inputs.
+async fn deserialize_input<I: DeserializeOwned + Send + 'static>(
+	input: Value,
+	inputs: Inputs,
+) -> Result<I, serde_json::Error> {
+	// We need spawn_blocking here to (sneakily) allow blocking code in serde Deserialize impls (used in `Path`, for example).
+	tokio::task::spawn_blocking(move || inputs.scope(|| serde_json::from_value(input)))
+		.await
+		.unwrap()
+}
+
+/// Where output files for the given request should go.
+/// A per-request `output_file_prefix` takes precedence over the server-wide configuration.
+fn output_destination(req: &cog_core::http::Request, default: &Destination) -> Destination {
+	req.output_file_prefix
+		.clone()
+		.map_or_else(|| default.clone(), Destination::Prefix)
+}
+
+/// Convert the model's response into JSON, then persist any output files concurrently (recording how long it took).
+async fn serialize_response<R: CogResponse + 'static>(
+	response: R,
+	req: cog_core::http::Request,
+	outputs: Outputs,
+) -> anyhow::Result<Value> {
+	// We use spawn_blocking here to allow blocking code in serde Serialize impls.
+	let (value, outputs) = tokio::task::spawn_blocking(move || {
+		outputs.collect(|| response.into_response_blocking(req))
+	})
+	.await?;
+
+	let mut value = value?;
+	if !outputs.is_empty() {
+		outputs.persist(&mut value).await?;
+	}
+
+	Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{apply_report, AtomicHealth, Health};
+	use crate::{
+		test_support::{call, get, predict, set_up, Behavior, TestModel},
+		HealthStatus, ServerBuilder,
+	};
+	use axum::{
+		body::Body,
+		http::{Method, Request, StatusCode},
+	};
+	use std::{
+		sync::atomic::{AtomicBool, Ordering},
+		time::Duration,
+	};
+	use tower::ServiceExt;
+
+	#[tokio::test]
+	async fn failed_setups_are_retried() {
+		let (server, _) = Behavior {
+			failed_setups: 1,
+			..Behavior::default()
+		}
+		.server();
+		let router = server
+			.setup_retries(1, Duration::ZERO)
+			.into_router()
+			.unwrap();
+
+		let health = set_up(router).await;
+		assert_eq!(health["status"], "READY");
+
+		let logs = health["setup"]["logs"].as_str().unwrap();
+		assert!(logs.contains("setup() failed (attempt 1/2): Weights server unavailable"));
+		assert!(logs.contains("setup() succeeded (attempt 2/2)"));
+	}
+
+	#[tokio::test]
+	async fn setups_time_out() {
+		let (server, _) = Behavior {
+			setup_delay: Duration::from_mins(1),
+			..Behavior::default()
+		}
+		.server();
+		let router = server
+			.setup_timeout(Duration::from_millis(10))
+			.into_router()
+			.unwrap();
+
+		let health = set_up(router).await;
+		assert_eq!(health["status"], "SETUP_FAILED");
+		assert!(health["setup"]["logs"]
+			.as_str()
+			.unwrap()
+			.contains("setup() failed: Timed out after 10ms"));
+	}
+
+	#[tokio::test]
+	async fn models_report_their_own_health() {
+		let (server, behavior) = Behavior::default().server();
+		let router = server.health_check_interval(None).into_router().unwrap();
+
+		let health = set_up(router.clone()).await;
+		assert_eq!(health["status"], "READY");
+		assert_eq!(health["model"]["status"], "HEALTHY");
+
+		behavior.broken.store(true, Ordering::SeqCst);
+		let health = call(router.clone(), get("/health-check")).await;
+		assert_eq!(health["status"], "UNHEALTHY");
+		assert_eq!(health["model"]["details"]["cuda"], "context lost");
+
+		let response = router
+			.oneshot(predict(Method::POST, "/predictions", "hello"))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+	}
+
+	#[tokio::test]
+	async fn panicking_health_checks_make_the_model_unhealthy() {
+		let (server, behavior) = Behavior::default().server();
+		let router = server.health_check_interval(None).into_router().unwrap();
+		set_up(router.clone()).await;
+
+		behavior.panicking.store(true, Ordering::SeqCst);
+		let health = call(router.clone(), get("/health-check")).await;
+		assert_eq!(health["status"], "UNHEALTHY");
+		assert_eq!(
+			health["model"]["details"],
+			"health_check() panicked: CUDA driver crashed"
+		);
+
+		behavior.panicking.store(false, Ordering::SeqCst);
+		let health = call(router.clone(), get("/health-check")).await;
+		assert_eq!(health["status"], "READY");
+
+		let response = call(router, predict(Method::POST, "/predictions", "hello")).await;
+		assert_eq!(response["output"], "hello");
+	}
+
+	#[test]
+	fn health_checks_only_update_idle_runners() {
+		let health = AtomicHealth::new(Health::Busy);
+		apply_report(&health, HealthStatus::Healthy);
+		assert!(matches!(health.load(Ordering::SeqCst), Health::Busy));
+
+		let health = AtomicHealth::new(Health::Ready);
+		apply_report(&health, HealthStatus::Unhealthy);
+		assert!(matches!(health.load(Ordering::SeqCst), Health::Unhealthy));
+		apply_report(&health, HealthStatus::Healthy);
+		assert!(matches!(health.load(Ordering::SeqCst), Health::Ready));
+	}
+
+	#[tokio::test]
+	async fn models_can_return_structured_errors() {
+		let (server, _) = Behavior {
+			predict: |input| {
+				if input.text.is_empty() {
+					return Err(
+						cog_core::ModelError::new("empty_text", "The text can't be empty")
+							.with_details(serde_json::json!({ "field": "text" }))
+							.into(),
+					);
+				}
+				anyhow::ensure!(input.text.is_ascii(), "Tokenizer exploded");
+
+				Ok(input.text)
+			},
+			..Behavior::default()
+		}
+		.server();
+		let router = server.into_router().unwrap();
+		set_up(router.clone()).await;
+
+		let response = call(router.clone(), predict(Method::POST, "/predictions", "")).await;
+		assert_eq!(response["status"], "failed");
+		assert_eq!(response["error"], "The text can't be empty");
+		assert_eq!(
+			response["failure"],
+			serde_json::json!({
+				"code": "empty_text",
+				"message": "The text can't be empty",
+				"retryable": false,
+				"details": { "field": "text" },
+			})
+		);
+
+		let response = call(
+			router.clone(),
+			predict(Method::POST, "/predictions", "héllo"),
+		)
+		.await;
+		assert_eq!(
+			response["error"],
+			"Failed to run prediction: Tokenizer exploded"
+		);
+		assert_eq!(response["failure"]["code"], "internal_error");
+
+		let openapi = call(router, get("/openapi.json")).await;
+		let failure =
+			&openapi["components"]["schemas"]["PredictionResponse"]["properties"]["failure"];
+		for property in ["code", "message", "retryable", "details"] {
+			assert!(
+				failure["properties"][property].is_object(),
+				"{property} isn't documented: {failure}"
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn phase_timings_are_reported() {
+		let router = ServerBuilder::<TestModel>::new().into_router().unwrap();
+		set_up(router.clone()).await;
+
+		let response = call(router, predict(Method::POST, "/predictions", "hello")).await;
+
+		let metrics = &response["metrics"];
+		let phases = ["queue_time", "download_time", "predict_time", "upload_time"].map(|phase| {
+			metrics[phase]
+				.as_f64()
+				.unwrap_or_else(|| panic!("{phase} is missing"))
+		});
+		assert!(metrics["total_time"].as_f64().unwrap() >= phases.iter().sum::<f64>());
+	}
+
+	#[tokio::test]
+	async fn canceled_predictions_keep_the_runner_busy_until_predict_returns() {
+		static RELEASED: AtomicBool = AtomicBool::new(false);
+
+		let (server, _) = Behavior {
+			predict: |input| {
+				while !RELEASED.load(Ordering::SeqCst) {
+					std::thread::sleep(Duration::from_millis(10));
+				}
+				Ok(input.text)
+			},
+			..Behavior::default()
+		}
+		.server();
+		let router = server.health_check_interval(None).into_router().unwrap();
+		set_up(router.clone()).await;
+
+		let mut request = predict(Method::PUT, "/predictions/slow", "hello");
+		request
+			.headers_mut()
+			.insert("Prefer", "respond-async".parse().unwrap());
+		let response = router.clone().oneshot(request).await.unwrap();
+		assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+		while call(router.clone(), get("/health-check")).await["status"] != "BUSY" {
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+		let cancel = Request::post("/predictions/slow/cancel")
+			.body(Body::empty())
+			.unwrap();
+		call(router.clone(), cancel).await;
+
+		let response = router
+			.clone()
+			.oneshot(predict(Method::POST, "/predictions", "again"))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::CONFLICT);
+		assert_eq!(
+			call(router.clone(), get("/health-check")).await["status"],
+			"BUSY"
+		);
+
+		RELEASED.store(true, Ordering::SeqCst);
+		while call(router.clone(), get("/health-check")).await["status"] != "READY" {
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+		let response = call(router, predict(Method::POST, "/predictions", "again")).await;
+		assert_eq!(response["output"], "again");
+	}
+}
@@ -1,65 +1,537 @@
-use std::{env, net::SocketAddr};
+use std::{
+	convert::Infallible,
+	future::Future,
+	marker::PhantomData,
+	net::{IpAddr, SocketAddr},
+	path::PathBuf,
+	pin::Pin,
+	sync::Arc,
+	time::Duration,
+};
 
 use aide::openapi::{self, OpenApi};
 use anyhow::Result;
-use axum::{http::Method, Extension, Server};
-use indexmap::indexmap;
+use axum::{
+	body::Body,
+	http::{Method, Request},
+	middleware,
+	response::IntoResponse,
+	routing::Route,
+	Extension, Router,
+};
+use clap::Parser;
+use indexmap::{indexmap, IndexMap};
 use schemars::{
 	gen::{SchemaGenerator, SchemaSettings},
 	schema::SchemaObject as Schema,
+	JsonSchema,
 };
+use tower::{Layer, Service};
+use url::Url;
+use uuid::Uuid;
 
 use crate::{
+	auth::{self, Auth},
+	cache::DownloadCache,
+	egress::EgressPolicy,
+	files::FileServer,
 	helpers::openapi::{replace_request_schema, replace_response_schema, schema_with_properties},
+	inputs::Inputs,
+	listener::Listener,
+	metrics::Registry,
+	outputs::Destination,
 	prediction::Prediction,
 	routes,
-	shutdown::Shutdown,
-	Cli, Cog,
+	runner::Lifecycle,
+	shutdown::{Shutdown, Signals},
+	Cli, Cog, Reporter, SchemeHandler, SetupContext, Train, WeightsLocation,
 };
 
-#[allow(clippy::redundant_pub_crate)]
-pub(crate) async fn start<T: Cog + 'static>(args: Cli) -> Result<()> {
-	if let Some(url) = args.upload_url {
-		env::set_var("UPLOAD_URL", url.to_string());
+/// Builds a Cog server for the given model, which can be served directly or mounted into an existing axum application.
+///
+/// Unlike [`crate::start`], the builder doesn't parse the process' arguments or install a tracing subscriber, and can be used more than once per process.
+pub struct ServerBuilder<T> {
+	args: Cli,
+	signals: Signals,
+	lifecycle: Lifecycle,
+	grace_period: Duration,
+	routes: Router,
+	training: Option<Training>,
+	scheme_handlers: Vec<(String, Arc<dyn SchemeHandler>)>,
+	layers: Vec<Box<dyn FnOnce(Router) -> Router + Send>>,
+	shutdown_signal: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+	model: PhantomData<fn() -> T>,
+}
+
+impl<T: Cog + 'static> Default for ServerBuilder<T> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl<T: Cog + 'static> ServerBuilder<T> {
+	/// Create a builder with the default configuration, listening on `0.0.0.0:5000`.
+	///
+	/// Since the builder is meant to be embedded, it doesn't handle any process signals unless asked to with [`Self::signals`].
+	#[must_use]
+	pub fn new() -> Self {
+		Self::from_cli(Cli::default()).signals(Signals::None)
+	}
+
+	/// Create a builder configured from the process' arguments (and environment), like [`crate::start`]. It shuts down on SIGINT and SIGTERM (or just SIGINT, with `--await-explicit-shutdown`).
+	#[must_use]
+	pub fn from_args() -> Self {
+		Self::from_cli(Cli::parse())
+	}
+
+	pub(crate) fn from_cli(args: Cli) -> Self {
+		Self {
+			signals: if args.await_explicit_shutdown.unwrap_or_default() {
+				Signals::Interrupt
+			} else {
+				Signals::All
+			},
+			lifecycle: Lifecycle {
+				setup_retries: args.setup_retries,
+				setup_timeout: Duration::from_secs(args.setup_timeout),
+				setup_backoff: Duration::from_secs(args.setup_retry_backoff),
+				teardown_timeout: Duration::from_secs(args.teardown_timeout),
+				health_check_interval: (args.health_check_interval > 0)
+					.then(|| Duration::from_secs(args.health_check_interval)),
+				panic_threshold: (args.panic_threshold > 0).then_some(args.panic_threshold),
+			},
+			grace_period: Duration::from_secs(args.shutdown_grace_period),
+			args,
+			layers: Vec::new(),
+			routes: Router::new(),
+			training: None,
+			scheme_handlers: Vec::new(),
+			shutdown_signal: None,
+			model: PhantomData,
+		}
+	}
+
+	/// Listen on the given address.
+	#[must_use]
+	pub const fn host(mut self, host: IpAddr) -> Self {
+		self.args.host = host;
+		self
+	}
+
+	/// Listen on the given port.
+	#[must_use]
+	pub const fn port(mut self, port: u16) -> Self {
+		self.args.port = port;
+		self
+	}
+
+	/// Listen on a Unix domain socket instead of a TCP port.
+	#[must_use]
+	pub fn unix_socket(mut self, path: impl Into<PathBuf>) -> Self {
+		self.args.unix_socket = Some(path.into());
+		self
+	}
+
+	/// Serve HTTPS with the given PEM-encoded certificate chain and private key (reloaded on SIGHUP).
+	#[must_use]
+	pub fn tls(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+		self.args.tls_cert = Some(cert.into());
+		self.args.tls_key = Some(key.into());
+		self
+	}
+
+	/// Set the model up with the weights at this location, instead of the bundled `weights` (if any).
+	#[must_use]
+	pub fn weights(mut self, weights: WeightsLocation) -> Self {
+		self.args.weights = Some(weights);
+		self
+	}
+
+	/// Let the model write temporary files to this directory, instead of a new temporary directory.
+	#[must_use]
+	pub fn scratch_dir(mut self, path: impl Into<PathBuf>) -> Self {
+		self.args.scratch_dir = Some(path.into());
+		self
+	}
+
+	/// Consider `setup()` failed if it takes longer than this. Defaults to 5 minutes.
+	#[must_use]
+	pub const fn setup_timeout(mut self, timeout: Duration) -> Self {
+		self.lifecycle.setup_timeout = timeout;
+		self
+	}
+
+	/// Retry a failed (or timed out) `setup()` up to `retries` times, waiting `backoff` before the first retry and doubling it after every retry.
+	#[must_use]
+	pub const fn setup_retries(mut self, retries: u32, backoff: Duration) -> Self {
+		self.lifecycle.setup_retries = retries;
+		self.lifecycle.setup_backoff = backoff;
+		self
+	}
+
+	/// Give the model's `teardown()` this long to finish (after the running prediction stops) when shutting down. Defaults to 30 seconds.
+	#[must_use]
+	pub const fn teardown_timeout(mut self, timeout: Duration) -> Self {
+		self.lifecycle.teardown_timeout = timeout;
+		self
 	}
 
-	let mut openapi = generate_schema::<T>();
-	let router = routes::handler().finish_api(&mut openapi);
-	tweak_generated_schema(&mut openapi);
+	/// Run the model's `health_check()` this often while it's idle (or, with `None`, only when `/health-check` is requested). Defaults to every 30 seconds.
+	#[must_use]
+	pub const fn health_check_interval(mut self, interval: Option<Duration>) -> Self {
+		self.lifecycle.health_check_interval = interval;
+		self
+	}
 
-	let shutdown = Shutdown::new(args.await_explicit_shutdown.unwrap_or_default())?;
-	if args.dump_schema_and_exit {
-		println!("{}", serde_json::to_string(&openapi).unwrap());
-		shutdown.start();
-		return Ok(());
+	/// Mark the model as unhealthy after it panics this many times (or, with `None`, never). Defaults to 3.
+	#[must_use]
+	pub const fn panic_threshold(mut self, threshold: Option<u32>) -> Self {
+		self.lifecycle.panic_threshold = threshold;
+		self
 	}
 
-	let prediction = Prediction::setup::<T>(shutdown.clone());
+	/// PUT output files to this endpoint, instead of returning them as data urls.
+	#[must_use]
+	pub fn upload_url(mut self, url: Url) -> Self {
+		self.args.upload_url = Some(url);
+		self
+	}
+
+	/// Keep output files on disk and serve them from `/files`, instead of returning them as data urls (ignored if an upload url is set).
+	///
+	/// Links are relative to the server unless `base_url` is given, and stay valid for `ttl` (rounded up to a whole second).
+	#[must_use]
+	pub fn serve_output_files(mut self, base_url: Option<Url>, ttl: Duration) -> Self {
+		self.args.serve_output_files = true;
+		self.args.output_files_url = base_url;
+		self.args.output_files_ttl = (ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0)).max(1);
+		self
+	}
+
+	/// Sign links to served output files with this secret, so they can't be guessed and expire with the files.
+	#[must_use]
+	pub fn output_files_secret(mut self, secret: impl Into<String>) -> Self {
+		self.args.output_files_secret = Some(secret.into());
+		self
+	}
+
+	/// Allow input downloads and webhooks to reach private, loopback and link-local addresses.
+	#[must_use]
+	pub const fn allow_private_egress(mut self, allow: bool) -> Self {
+		self.args.allow_private_egress = allow;
+		self
+	}
+
+	/// Download `Path` inputs with the given scheme using `handler`.
+	///
+	/// The scheme must also be allowed with `--allowed-url-schemes`, and the built-in ones (`http`, `https`, `data`, `file`, `s3` and `gs`) can't be replaced.
+	#[must_use]
+	pub fn scheme_handler(mut self, scheme: &str, handler: impl SchemeHandler + 'static) -> Self {
+		self.scheme_handlers
+			.push((scheme.to_string(), Arc::new(handler)));
+		self
+	}
+
+	/// Also serve training jobs on `/trainings`, run by the given trainer (which is set up alongside the model).
+	#[must_use]
+	pub fn train<U: Train + 'static>(mut self) -> Self {
+		self.training = Some(Training::new::<U>());
+		self
+	}
+
+	/// Choose which process signals shut the server down. Defaults to none, unless the builder was created with [`Self::from_args`].
+	#[must_use]
+	pub const fn signals(mut self, signals: Signals) -> Self {
+		self.signals = signals;
+		self
+	}
+
+	/// Also shut the server down (and stop the model) once the given future completes.
+	#[must_use]
+	pub fn shutdown_signal(mut self, signal: impl Future<Output = ()> + Send + 'static) -> Self {
+		self.shutdown_signal = Some(Box::pin(signal));
+		self
+	}
+
+	/// Give running predictions this long to finish when shutting down, before canceling them. Defaults to 30 seconds.
+	///
+	/// New predictions are rejected (with a 503) as soon as the shutdown starts.
+	#[must_use]
+	pub const fn grace_period(mut self, grace_period: Duration) -> Self {
+		self.grace_period = grace_period;
+		self
+	}
+
+	/// Serve these routes alongside Cog's.
+	#[must_use]
+	pub fn routes(mut self, routes: Router) -> Self {
+		self.routes = self.routes.merge(routes);
+		self
+	}
+
+	/// Wrap every route (including the ones added with [`Self::routes`]) with the given layer.
+	#[must_use]
+	pub fn layer<L>(mut self, layer: L) -> Self
+	where
+		L: Layer<Route> + Clone + Send + 'static,
+		L::Service: Service<Request<Body>> + Clone + Send + 'static,
+		<L::Service as Service<Request<Body>>>::Response: IntoResponse + 'static,
+		<L::Service as Service<Request<Body>>>::Error: Into<Infallible> + 'static,
+		<L::Service as Service<Request<Body>>>::Future: Send + 'static,
+	{
+		self.layers
+			.push(Box::new(move |router| router.layer(layer)));
+		self
+	}
+
+	/// Whether the server was asked to print its `OpenAPI` schema and exit, instead of serving.
+	pub(crate) const fn dump_schema_and_exit(&self) -> bool {
+		self.args.dump_schema_and_exit
+	}
+
+	/// The server's `OpenAPI` schema.
+	pub(crate) fn openapi(&self) -> OpenApi {
+		api::<T>(
+			self.args.serve_output_files && self.args.upload_url.is_none(),
+			self.training,
+		)
+		.1
+	}
+
+	/// Start the model's setup and return a router serving it, to mount into an existing application.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the configuration is invalid.
+	///
+	/// # Panics
+	///
+	/// Panics if called outside of a Tokio runtime.
+	pub fn into_router(self) -> Result<Router> {
+		Ok(self.build()?.0)
+	}
+
+	/// Start the model's setup and serve it until shut down.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the configuration is invalid, or if the server fails to start.
+	pub async fn serve(self) -> Result<()> {
+		let (router, shutdown, listener) = self.build()?;
+
+		tracing::info!("Starting server on {listener}...");
+		listener.serve(router, shutdown.handle()).await?;
+
+		// Wait for the model to be torn down before exiting.
+		shutdown.finished().await;
+		Ok(())
+	}
 
-	let router = router
-		.layer(Extension(openapi))
-		.layer(shutdown.extension())
-		.layer(prediction.extension());
+	fn build(self) -> Result<(Router, Shutdown, Listener)> {
+		let args = self.args;
 
-	let addr = SocketAddr::from((
-		[0, 0, 0, 0],
-		env::var("PORT").map_or(Ok(5000), |p| p.parse())?,
-	));
+		let files = if args.serve_output_files && args.upload_url.is_none() {
+			Some(Arc::new(FileServer::new(
+				args.output_files_url,
+				Duration::from_secs(args.output_files_ttl),
+				args.output_files_secret,
+			)?))
+		} else {
+			None
+		};
+		let destination = match (&files, args.upload_url) {
+			(Some(files), _) => Destination::Serve(files.clone()),
+			(None, Some(url)) => Destination::Upload(url),
+			(None, None) => Destination::DataUrl,
+		};
 
-	tracing::info!("Starting server on {addr}...");
-	Server::bind(&addr)
-		.serve(router.into_make_service())
-		.with_graceful_shutdown(shutdown.handle())
-		.await?;
+		let cache = args
+			.input_cache_dir
+			.map(|dir| DownloadCache::new(dir, args.input_cache_size * 1024 * 1024))
+			.transpose()?;
+		let egress = Arc::new(EgressPolicy::new(
+			args.egress_allow,
+			args.egress_deny,
+			args.allow_private_egress,
+			args.allowed_url_schemes,
+		));
+		let inputs = Inputs::new(
+			args.input_file_root,
+			args.gcs_endpoint,
+			cache,
+			egress.clone(),
+		)?
+		.with_handlers(self.scheme_handlers)?;
 
-	Ok(())
+		let shutdown = Shutdown::new(self.signals, self.grace_period);
+		if let Some(signal) = self.shutdown_signal {
+			let shutdown = shutdown.clone();
+			tokio::spawn(async move {
+				signal.await;
+				shutdown.start();
+			});
+		}
+
+		let reporter = Reporter::default();
+		let metrics = Arc::new(Registry::default());
+		let ctx = setup_context(args.weights, args.scratch_dir, reporter.clone());
+
+		let training = self.training.map(|training| {
+			(training.setup)(
+				shutdown.clone(),
+				destination.clone(),
+				inputs.clone(),
+				egress.clone(),
+				ctx.clone(),
+				self.lifecycle,
+				metrics.clone(),
+			)
+		});
+		let prediction = Prediction::setup::<T>(
+			shutdown.clone(),
+			destination,
+			inputs,
+			egress,
+			ctx,
+			self.lifecycle,
+			metrics.clone(),
+		);
+
+		let (router, openapi) = api::<T>(files.is_some(), self.training);
+		let mut router = router.merge(self.routes).layer(Extension(openapi));
+		if let Some(files) = files {
+			files.clone().start_cleanup(shutdown.clone());
+			router = router.layer(Extension(files));
+		}
+
+		let mut router = router
+			.layer(shutdown.extension())
+			.layer(Extension(prediction.health()))
+			.layer(Extension(prediction.health_checks()))
+			.layer(Extension(reporter))
+			.layer(Extension(metrics))
+			.layer(prediction.extension());
+		if let Some(training) = training {
+			router = router.layer(training.training_extension());
+		}
+		if let Some(auth) = Auth::new(
+			args.auth_token,
+			args.auth_hmac_secret,
+			args.admin_token,
+			args.auth_exempt,
+		) {
+			router = router.layer(middleware::from_fn_with_state(
+				Arc::new(auth),
+				auth::middleware,
+			));
+		}
+
+		for layer in self.layers {
+			router = layer(router);
+		}
+
+		let listener = Listener::new(
+			SocketAddr::from((args.host, args.port)),
+			args.unix_socket,
+			args.tls_cert.zip(args.tls_key),
+		)?;
+
+		Ok((router, shutdown, listener))
+	}
+}
+
+/// The context the model (and trainer) are set up with: the configured (or bundled) weights, the process' environment and a scratch directory.
+fn setup_context(
+	weights: Option<WeightsLocation>,
+	scratch_dir: Option<PathBuf>,
+	reporter: Reporter,
+) -> SetupContext {
+	let ctx =
+		SetupContext::new(scratch_dir.unwrap_or_else(|| {
+			std::env::temp_dir().join(format!("cog-scratch-{}", Uuid::new_v4()))
+		}))
+		.with_env(std::env::vars())
+		.with_reporter(reporter);
+
+	let weights = weights.or_else(|| {
+		let bundled = PathBuf::from("weights");
+		bundled.exists().then_some(WeightsLocation::Path(bundled))
+	});
+
+	match weights {
+		Some(weights) => ctx.with_weights(weights),
+		None => ctx,
+	}
+}
+
+/// Sets up a model's runner, returning its (idle) prediction state.
+type Setup = fn(
+	Shutdown,
+	Destination,
+	Inputs,
+	Arc<EgressPolicy>,
+	SetupContext,
+	Lifecycle,
+	Arc<Registry>,
+) -> Prediction;
+
+/// How to set up a trainer for the model, and describe its training jobs in the `OpenAPI` schema.
+#[derive(Clone, Copy)]
+struct Training {
+	setup: Setup,
+	schemas: fn(&mut SchemaGenerator) -> IndexMap<String, openapi::SchemaObject>,
 }
 
-fn generate_schema<T: Cog>() -> OpenApi {
+impl Training {
+	fn new<U: Train + 'static>() -> Self {
+		Self {
+			setup: Prediction::training::<U>,
+			schemas: |generator| {
+				job_schemas::<U::Request, U::Response>(
+					generator,
+					("TrainingInput", "TrainingOutput"),
+					("TrainingRequest", "TrainingResponse"),
+				)
+			},
+		}
+	}
+}
+
+/// Cog's routes, along with their `OpenAPI` schema.
+fn api<T: Cog>(serve_output_files: bool, training: Option<Training>) -> (Router, OpenApi) {
+	let mut openapi = generate_schema::<T>(training);
+
+	let mut router = routes::handler();
+	if serve_output_files {
+		router = router.merge(routes::files());
+	}
+	if training.is_some() {
+		router = router.merge(routes::trainings());
+	}
+	let router = router.finish_api(&mut openapi);
+
+	tweak_generated_schema(&mut openapi, "predictions", "prediction_id", "Prediction");
+	if training.is_some() {
+		tweak_generated_schema(&mut openapi, "trainings", "training_id", "Training");
+	}
+
+	(router, openapi)
+}
+
+fn generate_schema<T: Cog>(training: Option<Training>) -> OpenApi {
 	let mut generator = SchemaGenerator::new(SchemaSettings::openapi3().with(|settings| {
 		settings.inline_subschemas = true;
 	}));
 
+	let mut schemas = job_schemas::<T::Request, T::Response>(
+		&mut generator,
+		("Input", "Output"),
+		("PredictionRequest", "PredictionResponse"),
+	);
+	if let Some(training) = training {
+		schemas.extend((training.schemas)(&mut generator));
+	}
+
 	OpenApi {
 		info: openapi::Info {
 			title: "Cog".to_string(),
@@ -67,87 +539,159 @@ fn generate_schema<T: Cog>() -> OpenApi {
 			..openapi::Info::default()
 		},
 		components: Some(openapi::Components {
-			schemas: indexmap! {
-				"Input".to_string() => openapi::SchemaObject {
-					example: None,
-					external_docs: None,
-					json_schema: schema_with_properties::<T::Request>(&mut generator, |name, schema, i| {
-						schema.metadata().title = Some(titlecase::titlecase(&name));
-						schema.extensions.insert("x-order".to_string(), i.into());
-					})
-				},
-				"PredictionRequest".to_string() => openapi::SchemaObject {
-					example: None,
-					external_docs: None,
-					json_schema: schema_with_properties::<cog_core::http::Request>(&mut generator, |name, schema, _| {
-						if name == "input" {
-							schema.reference = Some("#/components/schemas/Input".to_string());
-						}
-					})
-				},
-				"Output".to_string() => openapi::SchemaObject {
-					example: None,
-					external_docs: None,
-					json_schema: generator.subschema_for::<T::Response>()
-				},
-				"PredictionResponse".to_string() => openapi::SchemaObject {
-					example: None,
-					external_docs: None,
-					json_schema: schema_with_properties::<cog_core::http::Response>(&mut generator, |name, schema, _| {
-						if name == "input" {
-							schema.reference = Some("#/components/schemas/Input".to_string());
-						}
-
-						if name == "output" {
-							schema.reference = Some("#/components/schemas/Output".to_string());
-						}
-					})
-				},
-			},
+			schemas,
 			..openapi::Components::default()
 		}),
 		..OpenApi::default()
 	}
 }
 
-fn tweak_generated_schema(openapi: &mut OpenApi) {
-	replace_request_schema(
-		openapi,
-		"/predictions",
-		(Method::POST, "application/json"),
-		Schema::new_ref("#/components/schemas/PredictionRequest".to_string()),
-	)
-	.unwrap();
-
-	replace_request_schema(
-		openapi,
-		"/predictions/{prediction_id}",
-		(Method::PUT, "application/json"),
-		Schema::new_ref("#/components/schemas/PredictionRequest".to_string()),
-	)
-	.unwrap();
-
-	replace_response_schema(
-		openapi,
-		"/predictions",
-		(
-			Method::POST,
-			openapi::StatusCode::Code(200),
-			"application/json".to_string(),
-		),
-		Schema::new_ref("#/components/schemas/PredictionResponse".to_string()),
-	)
-	.unwrap();
-
-	replace_response_schema(
-		openapi,
-		"/predictions/{prediction_id}",
-		(
-			Method::PUT,
-			openapi::StatusCode::Code(200),
-			"application/json".to_string(),
-		),
-		Schema::new_ref("#/components/schemas/PredictionResponse".to_string()),
-	)
-	.unwrap();
+/// The components describing a kind of job (predictions or trainings): its input and output, and the request and response wrapping them.
+fn job_schemas<I: JsonSchema, O: JsonSchema>(
+	generator: &mut SchemaGenerator,
+	(input, output): (&str, &str),
+	(request, response): (&str, &str),
+) -> IndexMap<String, openapi::SchemaObject> {
+	let input_ref = format!("#/components/schemas/{input}");
+	let output_ref = format!("#/components/schemas/{output}");
+
+	indexmap! {
+		input.to_string() => openapi::SchemaObject {
+			example: None,
+			external_docs: None,
+			json_schema: schema_with_properties::<I>(generator, |name, schema, i| {
+				schema.metadata().title = Some(titlecase::titlecase(&name));
+				schema.extensions.insert("x-order".to_string(), i.into());
+			})
+		},
+		request.to_string() => openapi::SchemaObject {
+			example: None,
+			external_docs: None,
+			json_schema: schema_with_properties::<cog_core::http::Request>(generator, |name, schema, _| {
+				if name == "input" {
+					schema.reference = Some(input_ref.clone());
+				}
+			})
+		},
+		output.to_string() => openapi::SchemaObject {
+			example: None,
+			external_docs: None,
+			json_schema: generator.subschema_for::<O>()
+		},
+		response.to_string() => openapi::SchemaObject {
+			example: None,
+			external_docs: None,
+			json_schema: schema_with_properties::<cog_core::http::Response>(generator, |name, schema, _| {
+				if name == "input" {
+					schema.reference = Some(input_ref.clone());
+				}
+
+				if name == "output" {
+					schema.reference = Some(output_ref.clone());
+				}
+			})
+		},
+	}
+}
+
+/// Point the request and response bodies of a kind of job's routes (like `/predictions` and `/predictions/{prediction_id}`) to its components.
+fn tweak_generated_schema(openapi: &mut OpenApi, path: &str, id_param: &str, component: &str) {
+	let request = Schema::new_ref(format!("#/components/schemas/{component}Request"));
+	let response = Schema::new_ref(format!("#/components/schemas/{component}Response"));
+
+	for (path, method) in [
+		(format!("/{path}"), Method::POST),
+		(format!("/{path}/{{{id_param}}}"), Method::PUT),
+	] {
+		replace_request_schema(
+			openapi,
+			&path,
+			(method.clone(), "application/json"),
+			request.clone(),
+		)
+		.unwrap();
+
+		replace_response_schema(
+			openapi,
+			&path,
+			(
+				method,
+				openapi::StatusCode::Code(200),
+				"application/json".to_string(),
+			),
+			response.clone(),
+		)
+		.unwrap();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support::{call, get, predict, set_up, Input, TestModel};
+
+	struct Reverse;
+
+	impl Train for Reverse {
+		type Request = Input;
+		type Response = String;
+
+		async fn setup(_: SetupContext) -> Result<Self> {
+			Ok(Self)
+		}
+
+		fn train(&self, input: Self::Request) -> Result<Self::Response> {
+			Ok(input.text.chars().rev().collect())
+		}
+	}
+
+	#[test]
+	fn only_builders_configured_from_arguments_handle_signals() {
+		assert_eq!(ServerBuilder::<TestModel>::new().signals, Signals::None);
+		assert_eq!(
+			ServerBuilder::<TestModel>::from_cli(Cli::default()).signals,
+			Signals::All
+		);
+	}
+
+	#[tokio::test]
+	async fn builders_can_be_used_more_than_once() {
+		let routers = (0..2)
+			.map(|_| ServerBuilder::<TestModel>::new().into_router().unwrap())
+			.collect::<Vec<_>>();
+
+		for router in routers {
+			assert_eq!(set_up(router.clone()).await["status"], "READY");
+
+			let response = call(router, predict(Method::POST, "/predictions", "hello")).await;
+			assert_eq!(response["output"], "hello");
+		}
+	}
+
+	#[tokio::test]
+	async fn trainings_run_alongside_predictions() {
+		let router = ServerBuilder::<TestModel>::new()
+			.train::<Reverse>()
+			.into_router()
+			.unwrap();
+
+		let openapi = call(router.clone(), get("/openapi.json")).await;
+		assert_eq!(
+			openapi["paths"]["/trainings"]["post"]["requestBody"]["content"]["application/json"]
+				["schema"]["$ref"],
+			"#/components/schemas/TrainingRequest"
+		);
+		for component in ["TrainingInput", "TrainingOutput", "TrainingResponse"] {
+			assert!(openapi["components"]["schemas"][component].is_object());
+		}
+
+		assert_eq!(set_up(router.clone()).await["status"], "READY");
+
+		let response = call(router, predict(Method::PUT, "/trainings/abc", "hello")).await;
+		assert_eq!(response["id"], "abc");
+		assert_eq!(response["output"], "olleh");
+
+		let openapi = ServerBuilder::<TestModel>::new().openapi();
+		assert!(openapi.paths.unwrap().paths.get("/trainings").is_none());
+	}
 }
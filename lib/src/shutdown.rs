@@ -1,64 +1,130 @@
 use axum::Extension;
-use std::{
-	error::Error,
-	fmt,
-	fmt::Display,
-	future::Future,
-	sync::atomic::{AtomicBool, Ordering},
+use std::{future::Future, sync::Arc, time::Duration};
+use tokio::{
+	signal,
+	sync::{broadcast, watch},
 };
-use tokio::{signal, sync::broadcast};
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct AlreadyCreatedError;
-
-impl Error for AlreadyCreatedError {}
-
-impl Display for AlreadyCreatedError {
-	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		f.write_str("shutdown handler already created")
-	}
+/// Which process signals shut the server down.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Signals {
+	/// Shut down on SIGINT (Ctrl+C) or SIGTERM.
+	#[default]
+	All,
+	/// Ignore SIGTERM, waiting for a request to `/shutdown` (or a SIGINT) before exiting.
+	Interrupt,
+	/// Ignore signals, only shutting down on a request to `/shutdown` (or when the embedding application asks).
+	None,
 }
 
-static CREATED: AtomicBool = AtomicBool::new(false);
-
+/// Shuts the server down in two steps: it first drains (rejecting new predictions while the running ones finish, for up to the grace period), and then stops.
 #[derive(Debug, Clone)]
 pub struct Shutdown {
 	pub sender: broadcast::Sender<()>,
+	/// Whether the server is draining (or has stopped).
+	draining: Arc<watch::Sender<bool>>,
+	/// How many predictions the drain is waiting for.
+	in_flight: Arc<watch::Sender<usize>>,
+	/// How many guards are keeping the shutdown from finishing.
+	guards: Arc<watch::Sender<usize>>,
+	grace_period: Duration,
+}
+
+/// Keeps the shutdown from finishing (see [`Shutdown::finished`]) until it's dropped, so work like tearing down the model can complete before the process exits.
+#[derive(Debug)]
+pub struct Guard {
+	counters: Vec<Arc<watch::Sender<usize>>>,
+}
+
+impl Guard {
+	fn new(counters: Vec<Arc<watch::Sender<usize>>>) -> Self {
+		for counter in &counters {
+			counter.send_modify(|count| *count += 1);
+		}
+
+		Self { counters }
+	}
+}
+
+impl Drop for Guard {
+	fn drop(&mut self) {
+		for counter in &self.counters {
+			counter.send_modify(|count| *count -= 1);
+		}
+	}
 }
 
 #[derive(Debug, Clone)]
 pub struct Agent {
-	sender: broadcast::Sender<()>,
+	shutdown: Shutdown,
 }
 
 impl Agent {
 	pub fn start(&self) {
-		self.sender.send(()).ok();
+		self.shutdown.start();
 	}
 }
 
 impl Shutdown {
-	pub fn new(await_explicit_shutdown: bool) -> Result<Self, AlreadyCreatedError> {
-		if (CREATED).swap(true, Ordering::SeqCst) {
-			tracing::error!("shutdown handler called twice");
-			return Err(AlreadyCreatedError);
-		}
-
+	/// Shut down on the given signals, giving running predictions up to `grace_period` to finish.
+	pub fn new(signals: Signals, grace_period: Duration) -> Self {
 		let (tx, _) = broadcast::channel(1);
-		let handle = register_handlers(await_explicit_shutdown);
+		let handle = register_handlers(signals);
+
+		let shutdown = Self {
+			sender: tx,
+			grace_period,
+			draining: Arc::new(watch::channel(false).0),
+			in_flight: Arc::new(watch::channel(0).0),
+			guards: Arc::new(watch::channel(0).0),
+		};
 
-		let tx_for_handle = tx.clone();
+		let drain = shutdown.clone();
 		tokio::spawn(async move {
 			tracing::debug!("Registered shutdown handlers");
-			handle.await;
-			tx_for_handle.send(()).ok();
+			let mut draining = drain.draining.subscribe();
+
+			tokio::select! {
+				() = handle => {
+					drain.draining.send_replace(true);
+				},
+				_ = draining.wait_for(|draining| *draining) => {},
+			}
+
+			drain.drain().await;
 		});
 
-		Ok(Self { sender: tx })
+		shutdown
 	}
 
 	pub fn start(&self) {
 		tracing::debug!("Manually requested shutdown.");
+		self.draining.send_replace(true);
+	}
+
+	/// Whether the server is shutting down, and shouldn't start any more predictions.
+	pub fn draining(&self) -> bool {
+		*self.draining.borrow()
+	}
+
+	/// Wait for the running predictions to finish (or the grace period to run out), then stop.
+	async fn drain(&self) {
+		let mut in_flight = self.in_flight.subscribe();
+
+		if *in_flight.borrow() > 0 {
+			tracing::info!(
+				"Waiting up to {:?} for running predictions to finish...",
+				self.grace_period
+			);
+		}
+
+		if tokio::time::timeout(self.grace_period, in_flight.wait_for(|count| *count == 0))
+			.await
+			.is_err()
+		{
+			tracing::warn!("Grace period ran out, canceling running predictions.");
+		}
+
 		self.sender.send(()).ok();
 	}
 
@@ -72,9 +138,28 @@ impl Shutdown {
 		}
 	}
 
+	/// Keep the shutdown from finishing until the returned guard is dropped.
+	pub fn guard(&self) -> Guard {
+		Guard::new(vec![self.guards.clone()])
+	}
+
+	/// Mark a prediction as running until the returned guard is dropped, so a drain waits for it (and the shutdown doesn't finish before it's done).
+	pub fn in_flight(&self) -> Guard {
+		Guard::new(vec![self.in_flight.clone(), self.guards.clone()])
+	}
+
+	/// Wait for every guard to be dropped.
+	pub async fn finished(&self) {
+		self.guards
+			.subscribe()
+			.wait_for(|guards| *guards == 0)
+			.await
+			.ok();
+	}
+
 	pub fn agent(&self) -> Agent {
 		Agent {
-			sender: self.sender.clone(),
+			shutdown: self.clone(),
 		}
 	}
 
@@ -83,7 +168,7 @@ impl Shutdown {
 	}
 }
 
-fn register_handlers(await_explicit_shutdown: bool) -> impl Future<Output = ()> {
+fn register_handlers(signals: Signals) -> impl Future<Output = ()> {
 	let ctrl_c = async {
 		signal::ctrl_c()
 			.await
@@ -102,8 +187,10 @@ fn register_handlers(await_explicit_shutdown: bool) -> impl Future<Output = ()>
 	let terminate = std::future::pending::<()>();
 
 	async move {
-		if await_explicit_shutdown {
-			return ctrl_c.await;
+		match signals {
+			Signals::None => return std::future::pending().await,
+			Signals::Interrupt => return ctrl_c.await,
+			Signals::All => {},
 		}
 
 		tokio::select! {
@@ -116,3 +203,115 @@ fn register_handlers(await_explicit_shutdown: bool) -> impl Future<Output = ()>
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		test_support::{call, set_up, Behavior},
+		testing::WebhookReceiver,
+	};
+	use axum::{
+		body::Body,
+		http::{Request, StatusCode},
+	};
+	use cog_core::http::Status;
+	use futures::StreamExt;
+	use std::sync::atomic::Ordering;
+	use tower::ServiceExt;
+
+	#[tokio::test]
+	async fn models_are_torn_down_on_shutdown() {
+		let (server, behavior) = Behavior::default().server();
+		let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+		let server = tokio::spawn(
+			server
+				.host([127, 0, 0, 1].into())
+				.port(0)
+				.shutdown_signal(async {
+					stopped.await.ok();
+				})
+				.serve(),
+		);
+
+		while !behavior.ready.load(Ordering::SeqCst) {
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+		stop.send(()).unwrap();
+
+		server.await.unwrap().unwrap();
+		assert!(behavior.torn_down.load(Ordering::SeqCst));
+	}
+
+	/// Start an asynchronous prediction (sleeping for `millis`), then shut the server down.
+	///
+	/// Returns the status of the prediction's completed webhook, and of a prediction requested after the shutdown started.
+	async fn drain(grace_period: Duration, millis: u64) -> (Status, StatusCode) {
+		let receiver = WebhookReceiver::new().start().unwrap();
+		let mut completed = receiver.completed();
+
+		let (server, _) = Behavior {
+			predict: |input| {
+				std::thread::sleep(Duration::from_millis(input.text.parse()?));
+				Ok(input.text)
+			},
+			..Behavior::default()
+		}
+		.server();
+		let router = server
+			.allow_private_egress(true)
+			.grace_period(grace_period)
+			.into_router()
+			.unwrap();
+		set_up(router.clone()).await;
+
+		let predict_async = |body: serde_json::Value| {
+			Request::post("/predictions")
+				.header("Content-Type", "application/json")
+				.header("Prefer", "respond-async")
+				.body(Body::from(body.to_string()))
+				.unwrap()
+		};
+
+		let response = router
+			.clone()
+			.oneshot(predict_async(serde_json::json!({
+				"input": { "text": millis.to_string() },
+				"webhook": receiver.url(),
+			})))
+			.await
+			.unwrap();
+		assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+		call(
+			router.clone(),
+			Request::post("/shutdown").body(Body::empty()).unwrap(),
+		)
+		.await;
+
+		let rejected = router
+			.oneshot(predict_async(
+				serde_json::json!({ "input": { "text": "0" } }),
+			))
+			.await
+			.unwrap();
+
+		(completed.next().await.unwrap().status, rejected.status())
+	}
+
+	#[tokio::test]
+	async fn running_predictions_are_drained_on_shutdown() {
+		let (status, rejected) = drain(Duration::from_secs(10), 200).await;
+
+		assert_eq!(status, Status::Succeeded);
+		assert_eq!(rejected, StatusCode::SERVICE_UNAVAILABLE);
+	}
+
+	#[tokio::test]
+	async fn predictions_are_canceled_after_the_grace_period() {
+		let (status, rejected) = drain(Duration::from_millis(10), 500).await;
+
+		assert_eq!(status, Status::Canceled);
+		assert_eq!(rejected, StatusCode::SERVICE_UNAVAILABLE);
+	}
+}
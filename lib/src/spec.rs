@@ -1,99 +1,124 @@
 use anyhow::Result;
 use core::fmt::Debug;
+use data_url::DataUrl;
 use mime_guess::Mime;
+use percent_encoding::percent_decode_str;
 use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
 use serde::Serialize;
-use std::{
-	env::{self, temp_dir},
-	fs::File,
-	path::PathBuf,
-	str::FromStr,
-};
+use std::{env::temp_dir, path::PathBuf, str::FromStr};
 use url::Url;
 use uuid::Uuid;
 
-use crate::helpers::{base64_decode, base64_encode, url_join};
+use crate::{
+	helpers::{dataurl, is_path_segment},
+	inputs::Inputs,
+	outputs::Outputs,
+};
 
 #[derive(Debug)]
-pub struct Path(PathBuf);
+pub struct Path {
+	path: PathBuf,
+	/// The directory created to hold an input file, removed along with it.
+	dir: Option<PathBuf>,
+}
 
 impl Path {
-	/// Create a new path from a url
+	/// Create a new path from a url, using the handler registered for its scheme
 	///
 	/// # Errors
 	///
-	/// Returns an error if the url cannot be downloaded or a temporary file cannot be created.
+	/// Returns an error if the url is not allowed by the egress policy, cannot be downloaded or a temporary file cannot be created.
 	pub(crate) fn new(url: &Url) -> Result<Self> {
+		Inputs::check(url)?;
+
 		if url.scheme() == "data" {
 			return Self::from_dataurl(url);
 		}
 
 		tracing::debug!("Downloading file from {url}");
-		let file_path = temp_dir().join(url.path().split('/').last().unwrap_or_else(|| url.path()));
-		let request = reqwest::blocking::get(url.as_str())?.bytes()?;
+		let file_name = url
+			.path()
+			.split('/')
+			.next_back()
+			.map(|name| percent_decode_str(name).decode_utf8_lossy().to_string());
+		let path = Self::input(file_name)?;
 
-		std::io::copy(&mut request.as_ref(), &mut File::create(&file_path)?)?;
-		tracing::debug!("Downloaded file to {}", file_path.display());
+		Inputs::fetch(url, &path.path)?;
+		tracing::debug!("Downloaded file to {}", path.path.display());
 
-		Ok(Self(file_path))
+		Ok(path)
 	}
 
-	/// Create a new path from a data url
-	///
-	/// # Errors
-	///
-	/// Returns an error if the url cannot be decoded or a temporary file cannot be created.
-	pub(crate) fn from_dataurl(url: &Url) -> Result<Self> {
-		let data = url.path().split(',').last().unwrap_or_else(|| url.path());
-
-		let file_bytes = base64_decode(data)?;
-		let mime_type = Mime::from_str(tree_magic_mini::from_u8(&file_bytes))
-			.unwrap_or(mime_guess::mime::APPLICATION_OCTET_STREAM);
-		let file_ext = mime_guess::get_mime_extensions(&mime_type)
-			.and_then(<[&str]>::last)
-			.map_or_else(String::new, |e| format!(".{e}"));
+	/// A path for an input file with the given name (if it's a valid file name), in a new directory so inputs with the same name never overwrite each other (or other files).
+	fn input(file_name: Option<String>) -> Result<Self> {
+		let dir = temp_dir().join(format!("cog-input-{}", Uuid::new_v4()));
+		std::fs::create_dir(&dir)?;
 
-		let file_path = temp_dir().join(format!("{}{file_ext}", Uuid::new_v4()));
+		let file_name = file_name
+			.filter(|name| is_path_segment(name))
+			.unwrap_or_else(|| Uuid::new_v4().to_string());
 
-		std::fs::write(&file_path, file_bytes)?;
-		Ok(Self(file_path))
+		Ok(Self {
+			path: dir.join(file_name),
+			dir: Some(dir),
+		})
 	}
 
-	/// PUT the file to the given endpoint and return the url
+	/// Create a new path from a data url ([RFC 2397](https://www.rfc-editor.org/rfc/rfc2397)), supporting both base64 and percent-encoded data.
 	///
-	/// # Errors
-	///
-	/// Returns an error if the file cannot be read or the upload fails.
+	/// The declared media type (if any) determines the file extension, and a `name` parameter is used as the file name.
 	///
-	/// # Panics
+	/// # Errors
 	///
-	/// Panics if the file name is not valid unicode.
-	pub(crate) fn upload_put(&self, upload_url: &Url) -> Result<String> {
-		let url = url_join(upload_url, self.0.file_name().unwrap().to_str().unwrap());
-		tracing::debug!("Uploading file to {url}");
-
-		let file_bytes = std::fs::read(&self.0)?;
-		let mime_type = tree_magic_mini::from_u8(&file_bytes);
-
-		let response = reqwest::blocking::Client::new()
-			.put(url.clone())
-			.header("Content-Type", mime_type)
-			.body(file_bytes)
-			.send()?;
-
-		if !response.status().is_success() {
-			anyhow::bail!(
-				"Failed to upload file to {url}: got {}. {}",
-				response.status(),
-				response.text().unwrap_or_default()
-			);
+	/// Returns an error if the url cannot be decoded or a temporary file cannot be created.
+	pub(crate) fn from_dataurl(url: &Url) -> Result<Self> {
+		let data_url = DataUrl::process(url.as_str())?;
+		let (file_bytes, _) = data_url
+			.decode_to_vec()
+			.map_err(|_| anyhow::anyhow!("Failed to decode data url: invalid base64"))?;
+
+		// Data urls without a media type default to `text/plain`, so we only trust it if it was explicitly declared.
+		let declared_mime = url
+			.path()
+			.split([',', ';'])
+			.next()
+			.is_some_and(|media_type| !media_type.trim().is_empty());
+		let mime_type = if declared_mime {
+			let mime_type = data_url.mime_type();
+			Mime::from_str(&format!("{}/{}", mime_type.type_, mime_type.subtype))
+		} else {
+			Mime::from_str(tree_magic_mini::from_u8(&file_bytes))
 		}
-
-		let mut url = response.url().clone();
-		url.set_query(None);
-
-		tracing::debug!("Uploaded file to {url}");
-		Ok(url.to_string())
+		.unwrap_or(mime_guess::mime::APPLICATION_OCTET_STREAM);
+
+		let file_name = data_url
+			.mime_type()
+			.get_parameter("name")
+			.and_then(|name| {
+				let name = percent_decode_str(name).decode_utf8_lossy();
+
+				std::path::Path::new(name.as_ref())
+					.file_name()
+					.map(|name| name.to_string_lossy().to_string())
+			})
+			.unwrap_or_else(|| {
+				// Prefer the extension matching the subtype (e.g. `.wav` over `.wave` for `audio/wav`).
+				let file_ext = mime_guess::get_mime_extensions(&mime_type)
+					.and_then(|extensions| {
+						extensions
+							.iter()
+							.find(|ext| **ext == mime_type.subtype().as_str())
+							.or_else(|| extensions.last())
+					})
+					.map_or_else(String::new, |e| format!(".{e}"));
+
+				format!("{}{file_ext}", Uuid::new_v4())
+			});
+
+		let path = Self::input(Some(file_name))?;
+
+		std::fs::write(&path.path, file_bytes)?;
+		Ok(path)
 	}
 
 	/// Convert the file to a data url
@@ -102,19 +127,13 @@ impl Path {
 	///
 	/// Returns an error if the file cannot be read.
 	pub(crate) fn to_dataurl(&self) -> Result<String> {
-		let file_bytes = std::fs::read(&self.0)?;
-		let mime_type = tree_magic_mini::from_u8(&file_bytes);
-
-		Ok(format!(
-			"data:{mime_type};base64,{base64}",
-			base64 = base64_encode(&file_bytes)
-		))
+		Ok(dataurl(&std::fs::read(&self.path)?))
 	}
 }
 
 impl AsRef<std::path::Path> for Path {
 	fn as_ref(&self) -> &std::path::Path {
-		self.0.as_ref()
+		self.path.as_ref()
 	}
 }
 
@@ -130,9 +149,20 @@ impl JsonSchema for Path {
 
 impl Drop for Path {
 	fn drop(&mut self) {
-		tracing::debug!("Removing temporary file at path {:?}", self.0);
+		tracing::debug!("Removing temporary file at path {:?}", self.path);
 
-		std::fs::remove_file(&self.0).unwrap();
+		if let Err(error) = std::fs::remove_file(&self.path) {
+			tracing::warn!(
+				"Failed to remove temporary file at {:?}: {error}",
+				self.path
+			);
+		}
+
+		if let Some(dir) = &self.dir {
+			if let Err(error) = std::fs::remove_dir_all(dir) {
+				tracing::warn!("Failed to remove temporary directory at {dir:?}: {error}");
+			}
+		}
 	}
 }
 
@@ -153,22 +183,18 @@ impl Serialize for Path {
 	where
 		S: serde::Serializer,
 	{
-		let url = env::var("UPLOAD_URL")
-			.map(|url| url.parse().ok())
-			.ok()
-			.flatten()
-			.map_or_else(
-				|| self.to_dataurl(),
-				|upload_url| self.upload_put(&upload_url),
-			);
+		// When serializing a prediction's response, files are collected and uploaded concurrently afterwards. Otherwise, they're inlined as data urls.
+		if let Some(placeholder) = Outputs::stage(&self.path) {
+			return serializer.serialize_str(&placeholder.map_err(serde::ser::Error::custom)?);
+		}
 
-		serializer.serialize_str(&url.map_err(serde::ser::Error::custom)?)
+		serializer.serialize_str(&self.to_dataurl().map_err(serde::ser::Error::custom)?)
 	}
 }
 
 impl From<PathBuf> for Path {
 	fn from(path: PathBuf) -> Self {
-		Self(path)
+		Self { path, dir: None }
 	}
 }
 
@@ -190,12 +216,12 @@ mod tests {
 		.unwrap();
 
 		let path = r#struct.file;
-		let underlying_path = path.0.clone();
+		let underlying_path = path.path.clone();
 
 		assert!(
 			underlying_path.exists(),
 			"File does not exist at path {:?}",
-			path.0
+			path.path
 		);
 		assert!(
 			underlying_path.metadata().unwrap().len() > 0,
@@ -222,4 +248,65 @@ mod tests {
 
 		assert!(dataurl.starts_with("data:image/png;base64,"));
 	}
+
+	#[test]
+	fn test_dataurl_deserialize_percent_encoded() {
+		let r#struct: StructWithPath = serde_json::from_value(json!({
+			"file": "data:,hello%20world"
+		}))
+		.unwrap();
+
+		assert_eq!(
+			std::fs::read_to_string(&r#struct.file).unwrap(),
+			"hello world"
+		);
+	}
+
+	#[test]
+	fn test_dataurl_deserialize_uses_declared_mime() {
+		let r#struct: StructWithPath = serde_json::from_value(json!({
+			"file": "data:audio/wav;base64,aGVsbG8gd29ybGQ="
+		}))
+		.unwrap();
+
+		assert_eq!(r#struct.file.path.extension().unwrap(), "wav");
+		assert_eq!(
+			std::fs::read_to_string(&r#struct.file).unwrap(),
+			"hello world"
+		);
+	}
+
+	#[test]
+	fn test_dataurl_deserialize_uses_name_parameter() {
+		let r#struct: StructWithPath = serde_json::from_value(json!({
+			"file": format!("data:text/plain;name=..%2F{}.txt;base64,aGVsbG8=", Uuid::new_v4())
+		}))
+		.unwrap();
+
+		assert_eq!(
+			r#struct.file.path.parent().unwrap().parent().unwrap(),
+			temp_dir()
+		);
+		assert_eq!(r#struct.file.path.extension().unwrap(), "txt");
+	}
+
+	#[test]
+	fn test_inputs_with_the_same_name_dont_collide() {
+		let [first, second] = ["aGVsbG8=", "d29ybGQ="].map(|data| {
+			serde_json::from_value::<StructWithPath>(json!({
+				"file": format!("data:text/plain;name=input.txt;base64,{data}")
+			}))
+			.unwrap()
+			.file
+		});
+
+		assert_ne!(first.path, second.path);
+		assert_eq!(std::fs::read_to_string(&first).unwrap(), "hello");
+		assert_eq!(std::fs::read_to_string(&second).unwrap(), "world");
+
+		let dir = first.dir.clone().unwrap();
+		std::fs::remove_file(&first.path).unwrap();
+		drop(first);
+		assert!(!dir.exists());
+	}
 }
@@ -0,0 +1,163 @@
+//! A configurable model and request helpers, shared by the tests of the server's modules.
+
+use anyhow::Result;
+use axum::{
+	body::Body,
+	http::{Method, Request, StatusCode},
+	Router,
+};
+use cog_core::{Cog, SetupContext};
+use std::{
+	collections::HashMap,
+	path::PathBuf,
+	sync::{
+		atomic::{AtomicBool, AtomicUsize, Ordering},
+		Arc, LazyLock, Mutex,
+	},
+	time::Duration,
+};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+use crate::ServerBuilder;
+
+#[derive(serde::Deserialize, schemars::JsonSchema)]
+pub struct Input {
+	pub text: String,
+}
+
+/// The behavior of the [`TestModel`] set up in each scratch directory.
+static BEHAVIORS: LazyLock<Mutex<HashMap<PathBuf, Arc<Behavior>>>> = LazyLock::new(Mutex::default);
+
+/// How a [`TestModel`] behaves. By default, it's set up right away, is healthy and echoes its input.
+pub struct Behavior {
+	/// How many setups fail before one succeeds.
+	pub failed_setups: usize,
+	/// How long each setup takes.
+	pub setup_delay: Duration,
+	/// Runs each prediction.
+	pub predict: fn(Input) -> Result<String>,
+	/// Makes the model report being unhealthy once set.
+	pub broken: AtomicBool,
+	/// Makes the model's health check panic once set.
+	pub panicking: AtomicBool,
+	/// Whether the model finished its setup.
+	pub ready: AtomicBool,
+	/// Whether the model was torn down.
+	pub torn_down: AtomicBool,
+	/// How many times the model's setup ran.
+	pub setups: AtomicUsize,
+}
+
+impl Default for Behavior {
+	fn default() -> Self {
+		Self {
+			failed_setups: 0,
+			setup_delay: Duration::ZERO,
+			predict: |input| Ok(input.text),
+			broken: AtomicBool::new(false),
+			panicking: AtomicBool::new(false),
+			ready: AtomicBool::new(false),
+			torn_down: AtomicBool::new(false),
+			setups: AtomicUsize::new(0),
+		}
+	}
+}
+
+impl Behavior {
+	/// A builder for a server whose model behaves like this, and a handle to check on (or change) the behavior.
+	pub fn server(self) -> (ServerBuilder<TestModel>, Arc<Self>) {
+		let behavior = Arc::new(self);
+		let scratch_dir = std::env::temp_dir().join(format!("cog-test-{}", Uuid::new_v4()));
+		BEHAVIORS
+			.lock()
+			.unwrap()
+			.insert(scratch_dir.clone(), behavior.clone());
+
+		(ServerBuilder::new().scratch_dir(scratch_dir), behavior)
+	}
+}
+
+/// A model configured with a [`Behavior`] (through its scratch directory).
+pub struct TestModel(Arc<Behavior>);
+
+impl Cog for TestModel {
+	type Request = Input;
+	type Response = String;
+
+	async fn setup(ctx: SetupContext) -> Result<Self> {
+		let behavior = BEHAVIORS
+			.lock()
+			.unwrap()
+			.get(ctx.scratch_dir())
+			.cloned()
+			.unwrap_or_default();
+
+		tokio::time::sleep(behavior.setup_delay).await;
+		if behavior.setups.fetch_add(1, Ordering::SeqCst) < behavior.failed_setups {
+			anyhow::bail!("Weights server unavailable");
+		}
+
+		behavior.ready.store(true, Ordering::SeqCst);
+		Ok(Self(behavior))
+	}
+
+	fn predict(&self, input: Self::Request) -> Result<Self::Response> {
+		(self.0.predict)(input)
+	}
+
+	async fn health_check(&mut self) -> cog_core::HealthReport {
+		assert!(
+			!self.0.panicking.load(Ordering::SeqCst),
+			"CUDA driver crashed"
+		);
+		if self.0.broken.load(Ordering::SeqCst) {
+			return cog_core::HealthReport::unhealthy()
+				.with_details(serde_json::json!({ "cuda": "context lost" }));
+		}
+
+		cog_core::HealthReport::healthy()
+	}
+
+	async fn teardown(&mut self) -> Result<()> {
+		self.0.torn_down.store(true, Ordering::SeqCst);
+		Ok(())
+	}
+}
+
+/// A `GET` request to the given path.
+pub fn get(path: &str) -> Request<Body> {
+	Request::get(path).body(Body::empty()).unwrap()
+}
+
+/// A request running a prediction with the given text as input (at `path`, usually `/predictions`).
+pub fn predict(method: Method, path: &str, text: &str) -> Request<Body> {
+	Request::builder()
+		.method(method)
+		.uri(path)
+		.header("Content-Type", "application/json")
+		.body(Body::from(
+			serde_json::json!({ "input": { "text": text } }).to_string(),
+		))
+		.unwrap()
+}
+
+/// Send the request, expecting a successful JSON response.
+pub async fn call(router: Router, request: Request<Body>) -> serde_json::Value {
+	let response = router.oneshot(request).await.unwrap();
+	assert_eq!(response.status(), StatusCode::OK);
+
+	serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap()).unwrap()
+}
+
+/// Poll the health check until the setup is done.
+pub async fn set_up(router: Router) -> serde_json::Value {
+	loop {
+		let health = call(router.clone(), get("/health-check")).await;
+
+		if health["status"] != "STARTING" {
+			return health;
+		}
+		tokio::time::sleep(Duration::from_millis(10)).await;
+	}
+}
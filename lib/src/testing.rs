@@ -0,0 +1,264 @@
+//! Run a model in-process from `cargo test`, without building an image or starting a server.
+//!
+//! Predictions go through the same validation, runner and response serialization as the HTTP server does, so tests cover the model's HTTP contract.
+//!
+//! ```no_run
+//! # use cog_rust::{Cog, testing::{Harness, WebhookReceiver}};
+//! # async fn test<MyModel: Cog + 'static>() -> anyhow::Result<()> {
+//! let harness = Harness::<MyModel>::new().await?;
+//! let response = harness.predict(serde_json::json!({ "text": "hello" })).await?;
+//!
+//! let webhooks = WebhookReceiver::new().start()?;
+//! let mut events = webhooks.events();
+//! harness
+//!     .submit(serde_json::json!({ "input": { "text": "hello" }, "webhook": webhooks.url() }))
+//!     .await?;
+//! assert!(futures::StreamExt::next(&mut events).await.is_some());
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::Result;
+use cog_core::{
+	http::{Request, Response, ValidationError},
+	HealthReport,
+};
+use std::{
+	marker::PhantomData,
+	sync::{atomic::Ordering, Arc},
+	time::Duration,
+};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::{
+	egress::EgressPolicy,
+	inputs::Inputs,
+	outputs::Destination,
+	prediction::{Error as PredictionError, Prediction, SyncGuard},
+	runner::{Health, Lifecycle},
+	shutdown::{Shutdown, Signals},
+	Cog, SetupContext,
+};
+
+/// Captures the webhooks sent for predictions. Start it with [`WebhookReceiver::start`] to listen on a local port.
+pub use cog_core::webhooks::WebhookReceiver;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("setup() failed")]
+	SetupFailed,
+
+	#[error("Invalid request: {0:?}")]
+	Validation(Vec<ValidationError>),
+
+	#[error(transparent)]
+	Other(#[from] anyhow::Error),
+}
+
+/// Runs a model's `setup()` and then submits predictions to it, like the server would.
+///
+/// Requests to private addresses are allowed (so inputs and webhooks can be served from the test itself), and output files are returned as data URLs.
+pub struct Harness<T> {
+	shutdown: Shutdown,
+	prediction: Arc<RwLock<Prediction>>,
+	model: PhantomData<fn() -> T>,
+}
+
+impl<T: Cog + 'static> Harness<T> {
+	/// Set up the model, waiting for `setup()` to finish.
+	///
+	/// The model gets no weights or configuration, and a new temporary scratch directory.
+	///
+	/// # Errors
+	///
+	/// Returns an error if `setup()` fails or times out.
+	pub async fn new() -> Result<Self, Error> {
+		Self::with_context(SetupContext::new(
+			std::env::temp_dir().join(format!("cog-scratch-{}", Uuid::new_v4())),
+		))
+		.await
+	}
+
+	/// Set up the model with the given context (to test loading specific weights or configuration), waiting for `setup()` to finish.
+	///
+	/// # Errors
+	///
+	/// Returns an error if `setup()` fails or times out.
+	pub async fn with_context(ctx: SetupContext) -> Result<Self, Error> {
+		let shutdown = Shutdown::new(Signals::None, Duration::ZERO);
+		let egress = Arc::new(EgressPolicy::new(vec![], vec![], true, None));
+		let prediction = Prediction::setup::<T>(
+			shutdown.clone(),
+			Destination::DataUrl,
+			Inputs::with_egress(egress.clone()),
+			egress,
+			ctx,
+			Lifecycle::default(),
+			Arc::default(),
+		);
+
+		let health = prediction.health();
+		loop {
+			match health.load(Ordering::SeqCst) {
+				Health::Starting => tokio::time::sleep(Duration::from_millis(10)).await,
+				Health::SetupFailed => return Err(Error::SetupFailed),
+				_ => break,
+			}
+		}
+
+		Ok(Self {
+			shutdown,
+			model: PhantomData,
+			prediction: Arc::new(RwLock::new(prediction)),
+		})
+	}
+
+	/// Run a prediction with the given input.
+	///
+	/// Failed predictions are returned as a [`Response`] with a `failed` status, as the server would.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the input doesn't match the model's schema.
+	pub async fn predict(&self, input: impl serde::Serialize) -> Result<Response, Error> {
+		self.submit(serde_json::json!({ "input": input })).await
+	}
+
+	/// Run a prediction from a full request body, as it would be sent to `POST /predictions` (including `webhook` and `webhook_event_filters`).
+	///
+	/// # Errors
+	///
+	/// Returns an error if the request is malformed or its input doesn't match the model's schema.
+	pub async fn submit(&self, request: impl serde::Serialize) -> Result<Response, Error> {
+		let request = serde_json::from_value::<Request>(
+			serde_json::to_value(request).map_err(anyhow::Error::from)?,
+		)
+		.map_err(anyhow::Error::from)?;
+
+		let result = SyncGuard::new(self.prediction.clone())
+			.run(Some(Uuid::new_v4().to_string()), request)
+			.await;
+
+		result.map_err(|error| match error {
+			PredictionError::Validation(errors) => Error::Validation(errors.errors),
+			error => Error::Other(error.into()),
+		})
+	}
+
+	/// Run the model's `health_check()`, as the server does while the model is idle.
+	pub async fn health_check(&self) -> Option<HealthReport> {
+		let checks = self.prediction.read().await.health_checks();
+
+		checks.refresh(Duration::from_secs(10)).await
+	}
+}
+
+impl<T> Drop for Harness<T> {
+	fn drop(&mut self) {
+		self.shutdown.start();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use cog_core::http::Status;
+	use futures::StreamExt;
+	use serde_json::json;
+
+	#[derive(serde::Deserialize, schemars::JsonSchema)]
+	struct Input {
+		text: String,
+	}
+
+	struct Echo;
+
+	impl Cog for Echo {
+		type Request = Input;
+		type Response = String;
+
+		async fn setup(_: SetupContext) -> Result<Self> {
+			Ok(Self)
+		}
+
+		fn predict(&self, input: Self::Request) -> Result<Self::Response> {
+			if input.text.is_empty() {
+				anyhow::bail!("Nothing to echo");
+			}
+
+			Ok(input.text)
+		}
+	}
+
+	struct Greeter {
+		greeting: String,
+	}
+
+	impl Cog for Greeter {
+		type Request = Input;
+		type Response = String;
+
+		async fn setup(ctx: SetupContext) -> Result<Self> {
+			assert!(ctx.scratch_dir().is_dir());
+			ctx.reporter().log("Loading greeting");
+
+			Ok(Self {
+				greeting: ctx.var("GREETING").unwrap_or("hello").to_string(),
+			})
+		}
+
+		fn predict(&self, input: Self::Request) -> Result<Self::Response> {
+			Ok(format!("{}, {}", self.greeting, input.text))
+		}
+	}
+
+	#[tokio::test]
+	async fn predictions_run_like_the_server() {
+		let harness = Harness::<Echo>::new().await.unwrap();
+
+		let response = harness.predict(json!({ "text": "hello" })).await.unwrap();
+		assert!(matches!(response.status, Status::Succeeded));
+		assert_eq!(response.output, Some(json!("hello")));
+
+		let response = harness.predict(json!({ "text": "" })).await.unwrap();
+		assert!(matches!(response.status, Status::Failed));
+		assert!(response.error.unwrap().contains("Nothing to echo"));
+
+		let Err(Error::Validation(errors)) = harness.predict(json!({ "text": 1 })).await else {
+			panic!("expected a validation error");
+		};
+		assert_eq!(errors[0].loc, ["body", "input", "text"]);
+	}
+
+	#[tokio::test]
+	async fn webhooks_are_captured() {
+		let harness = Harness::<Echo>::new().await.unwrap();
+		let webhooks = WebhookReceiver::new().start().unwrap();
+		let events = webhooks.events();
+
+		let response = harness
+			.submit(json!({ "input": { "text": "hello" }, "webhook": webhooks.url() }))
+			.await
+			.unwrap();
+		drop(webhooks);
+
+		let received = events.collect::<Vec<_>>().await;
+		assert_eq!(received.len(), 2);
+		assert!(matches!(received[0].status, Status::Processing));
+		assert_eq!(received[1].output, response.output);
+	}
+
+	#[tokio::test]
+	async fn models_are_set_up_with_the_given_context() {
+		let ctx = SetupContext::new(std::env::temp_dir().join(Uuid::new_v4().to_string()))
+			.with_env([("GREETING".to_string(), "hi".to_string())]);
+		let reporter = ctx.reporter().clone();
+
+		let harness = Harness::<Greeter>::with_context(ctx).await.unwrap();
+		let response = harness.predict(json!({ "text": "there" })).await.unwrap();
+
+		assert_eq!(response.output, Some(json!("hi, there")));
+		assert!(reporter.logs().contains("Loading greeting\n"));
+	}
+}
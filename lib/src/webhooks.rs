@@ -1,23 +1,42 @@
-use std::env;
+use std::{env, sync::Arc};
 
 use anyhow::Result;
-use axum::http::{HeaderMap, HeaderValue};
-use cog_core::http::WebhookEvent;
+use axum::http::{header::CONTENT_TYPE, HeaderMap, HeaderValue};
+use chrono::Utc;
+use cog_core::{
+	http::{Request, Response, WebhookEvent},
+	webhooks,
+};
 use reqwest::Client;
 use url::Url;
 
-use crate::prediction::{Prediction, ResponseHelpers};
+use crate::{egress::EgressPolicy, prediction::ResponseHelpers};
 
 pub struct WebhookSender {
 	client: Client,
+	egress: Arc<EgressPolicy>,
+	signing_secret: Option<String>,
 }
 
 impl WebhookSender {
-	pub fn new() -> Result<Self> {
+	/// Send webhooks with the bearer token in `WEBHOOK_AUTH_TOKEN`, signed with `WEBHOOK_SIGNING_SECRET` (when set).
+	pub fn new(egress: Arc<EgressPolicy>) -> Result<Self> {
+		Self::with_credentials(
+			egress,
+			env::var("WEBHOOK_AUTH_TOKEN").ok(),
+			env::var("WEBHOOK_SIGNING_SECRET").ok(),
+		)
+	}
+
+	pub fn with_credentials(
+		egress: Arc<EgressPolicy>,
+		token: Option<String>,
+		signing_secret: Option<String>,
+	) -> Result<Self> {
 		let mut headers = HeaderMap::new();
-		let client = Client::builder();
+		let client = egress.client_builder();
 
-		if let Ok(token) = env::var("WEBHOOK_AUTH_TOKEN") {
+		if let Some(token) = token {
 			let mut authorization = HeaderValue::from_str(&format!("Bearer {token}"))?;
 			authorization.set_sensitive(true);
 			headers.insert("Authorization", authorization);
@@ -28,31 +47,27 @@ impl WebhookSender {
 				.user_agent(format!("cog-worker/{}", env!("CARGO_PKG_VERSION")))
 				.default_headers(headers)
 				.build()?,
+			egress,
+			signing_secret,
 		})
 	}
 
-	pub async fn starting(&self, prediction: &Prediction) -> Result<()> {
-		let request = prediction.request.clone().unwrap();
-		if !Self::should_send(&request, WebhookEvent::Start) {
+	pub async fn starting(&self, id: Option<String>, request: &Request) -> Result<()> {
+		if !Self::should_send(request, WebhookEvent::Start) {
 			return Ok(());
 		}
 
 		self.send(
 			request.webhook.clone().unwrap(),
-			cog_core::http::Response::starting(prediction.id.clone(), request),
+			Response::starting(id, request.clone()),
 		)
 		.await?;
 
 		Ok(())
 	}
 
-	pub async fn finished(
-		&self,
-		prediction: &Prediction,
-		response: cog_core::http::Response,
-	) -> Result<()> {
-		let request = prediction.request.clone().unwrap();
-		if !Self::should_send(&request, WebhookEvent::Completed) {
+	pub async fn finished(&self, request: &Request, response: Response) -> Result<()> {
+		if !Self::should_send(request, WebhookEvent::Completed) {
 			return Ok(());
 		}
 
@@ -62,22 +77,92 @@ impl WebhookSender {
 		Ok(())
 	}
 
-	fn should_send(req: &cog_core::http::Request, event: WebhookEvent) -> bool {
+	fn should_send(req: &Request, event: WebhookEvent) -> bool {
 		req.webhook.is_some()
 			&& req
 				.webhook_event_filters
 				.as_ref()
-				.map_or(true, |filters| filters.contains(&event))
+				.is_none_or(|filters| filters.contains(&event))
 	}
 
-	async fn send(
-		&self,
-		url: Url,
-		res: cog_core::http::Response,
-	) -> Result<reqwest::Response, reqwest::Error> {
+	async fn send(&self, url: Url, res: Response) -> Result<reqwest::Response> {
+		self.egress.check(&url)?;
+
 		tracing::debug!("Sending webhook to {url}");
 		tracing::trace!("{res:?}");
 
-		self.client.post(url).json(&res).send().await
+		let body = serde_json::to_vec(&res)?;
+		let mut request = self
+			.client
+			.post(url)
+			.header(CONTENT_TYPE, "application/json");
+
+		if let Some(secret) = &self.signing_secret {
+			let timestamp = Utc::now().timestamp();
+			request = request
+				.header(webhooks::TIMESTAMP_HEADER, timestamp)
+				.header(
+					webhooks::SIGNATURE_HEADER,
+					webhooks::sign(secret.as_bytes(), timestamp, &body),
+				);
+		}
+
+		Ok(request.body(body).send().await?)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use axum::{Router, Server};
+	use cog_core::{http::Status, webhooks::WebhookReceiver};
+	use futures::StreamExt;
+	use serde_json::json;
+
+	#[tokio::test]
+	async fn webhooks_are_authenticated_and_signed() {
+		let receiver = WebhookReceiver::new()
+			.with_token("token")
+			.with_signing_secret("secret");
+		let (mut events, mut completed) = (receiver.events(), receiver.completed());
+
+		let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(
+			Router::new()
+				.route_service("/", receiver)
+				.into_make_service(),
+		);
+		let url: Url = format!("http://{}/", server.local_addr()).parse().unwrap();
+		tokio::spawn(server);
+
+		let sender = WebhookSender::with_credentials(
+			Arc::new(EgressPolicy::new(vec![], vec![], true, None)),
+			Some("token".to_string()),
+			Some("secret".to_string()),
+		)
+		.unwrap();
+		let request = Request {
+			webhook: Some(url),
+			..Request::new(json!({ "text": "hello" }))
+		};
+
+		sender
+			.starting(Some("abc".to_string()), &request)
+			.await
+			.unwrap();
+		sender
+			.finished(
+				&request,
+				Response {
+					id: Some("abc".to_string()),
+					status: Status::Succeeded,
+					..Response::default()
+				},
+			)
+			.await
+			.unwrap();
+
+		assert_eq!(events.next().await.unwrap().status, Status::Processing);
+		assert_eq!(events.next().await.unwrap().status, Status::Succeeded);
+		assert_eq!(completed.next().await.unwrap().id.as_deref(), Some("abc"));
 	}
 }
@@ -0,0 +1,476 @@
+//! Download a model's weights during `setup()`, instead of bundling them into the image.
+//!
+//! Weight files are declared with the URL to download them from and their SHA-256 digest. Missing files are downloaded in parallel (resuming any partial downloads), and every file is verified before the model gets to use it: corrupted files are downloaded again, and a download that doesn't match fails the setup instead of the predictions.
+//!
+//! Downloads follow the server's egress policy, like input files do.
+//!
+//! ```no_run
+//! # use cog_rust::{weights::Weights, SetupContext};
+//! # async fn setup(ctx: SetupContext) -> anyhow::Result<()> {
+//! let weights = Weights::new()
+//!     .file("model.safetensors", "https://example.com/model.safetensors".parse()?, "9f86d0…")
+//!     .fetch(&ctx)
+//!     .await?;
+//!
+//! let model = weights.join("model.safetensors");
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{Context, Result};
+use cog_core::{Reporter, SetupContext};
+use futures::future::try_join_all;
+use reqwest::{header::RANGE, StatusCode};
+use sha2::{Digest, Sha256};
+use std::{
+	fs::File,
+	io::Read,
+	path::{Component, Path, PathBuf},
+	sync::Mutex,
+};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Semaphore};
+use url::Url;
+
+use crate::egress::EgressPolicy;
+
+/// Environment variable (read from the [`SetupContext`]) with the directory to cache weights in.
+pub const CACHE_DIR_VAR: &str = "COG_WEIGHTS_CACHE_DIR";
+
+/// The weight files a model needs.
+#[derive(Debug, Clone)]
+pub struct Weights {
+	files: Vec<WeightFile>,
+	cache_dir: Option<PathBuf>,
+	concurrency: usize,
+}
+
+#[derive(Debug, Clone)]
+struct WeightFile {
+	name: PathBuf,
+	url: Url,
+	sha256: String,
+}
+
+impl Default for Weights {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Weights {
+	/// No weight files, downloading up to 4 at a time.
+	#[must_use]
+	pub const fn new() -> Self {
+		Self {
+			files: Vec::new(),
+			cache_dir: None,
+			concurrency: 4,
+		}
+	}
+
+	/// Require a file (at `name`, relative to the weights directory and without any `..`) with the given hex-encoded SHA-256 digest, downloading it from `url` if needed.
+	#[must_use]
+	pub fn file(mut self, name: impl Into<PathBuf>, url: Url, sha256: &str) -> Self {
+		self.files.push(WeightFile {
+			url,
+			name: name.into(),
+			sha256: sha256.to_lowercase(),
+		});
+		self
+	}
+
+	/// Keep the weights in this directory. Defaults to `COG_WEIGHTS_CACHE_DIR`, or a `weights` folder in the scratch directory.
+	#[must_use]
+	pub fn cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+		self.cache_dir = Some(dir.into());
+		self
+	}
+
+	/// Download at most this many files at a time.
+	#[must_use]
+	pub fn concurrency(mut self, concurrency: usize) -> Self {
+		self.concurrency = concurrency.max(1);
+		self
+	}
+
+	/// Download any missing (or partially downloaded) files and verify every file's digest, reporting progress to the context's reporter.
+	///
+	/// Returns the directory the files are in.
+	///
+	/// # Errors
+	///
+	/// Returns an error if a file's name is invalid, it can't be downloaded, or the digest of the downloaded file doesn't match.
+	pub async fn fetch(self, ctx: &SetupContext) -> Result<PathBuf> {
+		let egress = EgressPolicy::current();
+		for file in &self.files {
+			anyhow::ensure!(
+				file.name.components().next().is_some()
+					&& file
+						.name
+						.components()
+						.all(|component| matches!(component, Component::Normal(_))),
+				"Invalid weights file name {}: it must be a relative path inside the weights directory",
+				file.name.display()
+			);
+			egress.check(&file.url)?;
+		}
+
+		let dir = self.cache_dir.clone().unwrap_or_else(|| {
+			ctx.var(CACHE_DIR_VAR)
+				.map_or_else(|| ctx.scratch_dir().join("weights"), PathBuf::from)
+		});
+
+		let client = egress.client_builder().build()?;
+		let semaphore = Semaphore::new(self.concurrency);
+		let progress = Tracker::new(ctx.reporter().clone(), self.files.len());
+
+		try_join_all(self.files.iter().enumerate().map(|(i, file)| {
+			let (dir, client, semaphore, progress) = (&dir, &client, &semaphore, &progress);
+
+			async move {
+				let _permit = semaphore.acquire().await?;
+				file.fetch(dir, client, &|done, total| progress.update(i, done, total))
+					.await
+					.with_context(|| {
+						format!("Failed to fetch weights file {}", file.name.display())
+					})
+			}
+		}))
+		.await?;
+
+		Ok(dir)
+	}
+}
+
+impl WeightFile {
+	async fn fetch(
+		&self,
+		dir: &Path,
+		client: &reqwest::Client,
+		progress: &(dyn Fn(u64, Option<u64>) + Sync),
+	) -> Result<()> {
+		let path = dir.join(&self.name);
+		let partial = path.with_file_name(format!(
+			"{}.partial",
+			path.file_name().unwrap_or_default().to_string_lossy()
+		));
+
+		if path.exists() {
+			let size = tokio::fs::metadata(&path).await?.len();
+			progress(size, Some(size));
+
+			match self.verify(&path).await {
+				Ok(()) => return Ok(()),
+				Err(error) => {
+					tracing::warn!(
+						"Downloading {} again, since the cached file is corrupted: {error:#}",
+						self.name.display()
+					);
+					tokio::fs::remove_file(&path).await?;
+				},
+			}
+		}
+
+		tokio::fs::create_dir_all(path.parent().unwrap_or(dir)).await?;
+		let mut downloaded = tokio::fs::metadata(&partial)
+			.await
+			.map_or(0, |metadata| metadata.len());
+
+		let mut request = client.get(self.url.clone());
+		if downloaded > 0 {
+			request = request.header(RANGE, format!("bytes={downloaded}-"));
+		}
+		let mut response = request.send().await?;
+
+		// The partial download may be complete already, if we stopped right before moving it into place.
+		if response.status() == StatusCode::RANGE_NOT_SATISFIABLE && downloaded > 0 {
+			if self.verify(&partial).await.is_ok() {
+				progress(downloaded, Some(downloaded));
+				tokio::fs::rename(&partial, &path).await?;
+				return Ok(());
+			}
+
+			tracing::warn!(
+				"Downloading {} again, since the partial download can't be resumed",
+				self.name.display()
+			);
+			downloaded = 0;
+			response = client.get(self.url.clone()).send().await?;
+		}
+		let mut response = response.error_for_status()?;
+
+		// Servers that don't support ranges send the whole file again.
+		let resumed = response.status() == StatusCode::PARTIAL_CONTENT;
+		if !resumed {
+			downloaded = 0;
+		}
+		let total = response.content_length().map(|length| length + downloaded);
+
+		let mut out = OpenOptions::new()
+			.create(true)
+			.write(true)
+			.append(resumed)
+			.truncate(!resumed)
+			.open(&partial)
+			.await?;
+
+		progress(downloaded, total);
+		while let Some(chunk) = response.chunk().await? {
+			out.write_all(&chunk).await?;
+			downloaded += chunk.len() as u64;
+			progress(downloaded, total);
+		}
+		out.flush().await?;
+		drop(out);
+
+		if let Err(error) = self.verify(&partial).await {
+			tokio::fs::remove_file(&partial).await.ok();
+			return Err(error);
+		}
+
+		tokio::fs::rename(&partial, &path).await?;
+		Ok(())
+	}
+
+	async fn verify(&self, path: &Path) -> Result<()> {
+		let digest = sha256(path.to_path_buf()).await?;
+
+		anyhow::ensure!(
+			digest == self.sha256,
+			"Checksum mismatch: expected {}, got {digest}",
+			self.sha256
+		);
+		Ok(())
+	}
+}
+
+/// The hex-encoded SHA-256 digest of the file at `path`.
+async fn sha256(path: PathBuf) -> Result<String> {
+	tokio::task::spawn_blocking(move || {
+		let mut file = File::open(path)?;
+		let mut hasher = Sha256::new();
+		let mut buf = vec![0; 1024 * 1024];
+
+		loop {
+			let read = file.read(&mut buf)?;
+			if read == 0 {
+				break;
+			}
+			hasher.update(&buf[..read]);
+		}
+
+		Ok(hex::encode(hasher.finalize()))
+	})
+	.await?
+}
+
+/// Sums up the progress of every file, reporting the total once every file's size is known.
+struct Tracker {
+	reporter: Reporter,
+	files: Mutex<Vec<(u64, Option<u64>)>>,
+}
+
+impl Tracker {
+	fn new(reporter: Reporter, files: usize) -> Self {
+		Self {
+			reporter,
+			files: Mutex::new(vec![(0, None); files]),
+		}
+	}
+
+	fn update(&self, file: usize, done: u64, total: Option<u64>) {
+		let mut files = self.files.lock().unwrap();
+		files[file] = (done, total);
+
+		self.reporter.progress(
+			files.iter().map(|(done, _)| done).sum(),
+			files.iter().map(|(_, total)| *total).sum(),
+		);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use axum::{
+		extract::State,
+		http::{HeaderMap, StatusCode},
+		routing::get,
+		Router, Server,
+	};
+	use std::sync::Arc;
+	use uuid::Uuid;
+
+	const CONTENTS: &[u8] = b"some very large weights";
+
+	/// Fetch the weights with a policy that allows the local test server.
+	async fn fetch(weights: Weights, ctx: &SetupContext) -> Result<PathBuf> {
+		Arc::new(EgressPolicy::new(vec![], vec![], true, None))
+			.scope(weights.fetch(ctx))
+			.await
+	}
+
+	/// Serves `CONTENTS`, honoring `Range` requests, and records the ranges requested.
+	fn serve() -> (Url, Arc<Mutex<Vec<Option<String>>>>) {
+		let ranges = Arc::new(Mutex::new(Vec::new()));
+
+		let app = Router::new()
+			.route(
+				"/weights",
+				get(
+					|State(ranges): State<Arc<Mutex<Vec<Option<String>>>>>, headers: HeaderMap| async move {
+						let range = headers
+							.get(RANGE)
+							.and_then(|range| range.to_str().ok())
+							.map(ToString::to_string);
+						ranges.lock().unwrap().push(range.clone());
+
+						let start = range
+							.and_then(|range| {
+								range
+									.strip_prefix("bytes=")?
+									.strip_suffix('-')?
+									.parse()
+									.ok()
+							})
+							.unwrap_or(0);
+						if start >= CONTENTS.len() {
+							return (StatusCode::RANGE_NOT_SATISFIABLE, Vec::new());
+						}
+						let status = if start > 0 {
+							StatusCode::PARTIAL_CONTENT
+						} else {
+							StatusCode::OK
+						};
+
+						(status, CONTENTS[start..].to_vec())
+					},
+				),
+			)
+			.with_state(ranges.clone());
+
+		let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(app.into_make_service());
+		let url = format!("http://{}/weights", server.local_addr())
+			.parse()
+			.unwrap();
+		tokio::spawn(server);
+
+		(url, ranges)
+	}
+
+	#[tokio::test]
+	async fn weights_are_downloaded_resumed_and_verified() {
+		let (url, ranges) = serve();
+		let sha256 = hex::encode(Sha256::digest(CONTENTS));
+		let ctx = SetupContext::new(std::env::temp_dir().join(Uuid::new_v4().to_string()));
+
+		let weights = ctx.scratch_dir().join("weights");
+		std::fs::create_dir_all(weights.join("nested")).unwrap();
+		std::fs::write(weights.join("nested/b.bin.partial"), &CONTENTS[..4]).unwrap();
+
+		let dir = fetch(
+			Weights::new().file("a.bin", url.clone(), &sha256).file(
+				"nested/b.bin",
+				url.clone(),
+				&sha256,
+			),
+			&ctx,
+		)
+		.await
+		.unwrap();
+
+		assert_eq!(dir, weights);
+		assert_eq!(std::fs::read(dir.join("a.bin")).unwrap(), CONTENTS);
+		assert_eq!(std::fs::read(dir.join("nested/b.bin")).unwrap(), CONTENTS);
+		assert!(ranges
+			.lock()
+			.unwrap()
+			.contains(&Some("bytes=4-".to_string())));
+
+		let total = 2 * CONTENTS.len() as u64;
+		assert_eq!(
+			ctx.reporter().last_progress(),
+			Some(cog_core::Progress {
+				current: total,
+				total: Some(total)
+			})
+		);
+
+		// Cached files are verified, and only downloaded again if they're corrupted...
+		fetch(Weights::new().file("a.bin", url.clone(), &sha256), &ctx)
+			.await
+			.unwrap();
+		assert_eq!(ranges.lock().unwrap().len(), 2);
+
+		std::fs::write(dir.join("a.bin"), b"corrupted").unwrap();
+		fetch(Weights::new().file("a.bin", url.clone(), &sha256), &ctx)
+			.await
+			.unwrap();
+		assert_eq!(std::fs::read(dir.join("a.bin")).unwrap(), CONTENTS);
+		assert_eq!(ranges.lock().unwrap().len(), 3);
+
+		// ...while a download that doesn't match fails the setup.
+		let error = fetch(Weights::new().file("c.bin", url, &"0".repeat(64)), &ctx)
+			.await
+			.unwrap_err();
+		assert!(format!("{error:#}").contains("Checksum mismatch"));
+		assert!(!dir.join("c.bin").exists());
+	}
+
+	#[tokio::test]
+	async fn finished_or_oversized_partial_downloads_are_recovered() {
+		let (url, ranges) = serve();
+		let sha256 = hex::encode(Sha256::digest(CONTENTS));
+		let ctx = SetupContext::new(std::env::temp_dir().join(Uuid::new_v4().to_string()));
+
+		let weights = ctx.scratch_dir().join("weights");
+		std::fs::create_dir_all(&weights).unwrap();
+		std::fs::write(weights.join("a.bin.partial"), CONTENTS).unwrap();
+		std::fs::write(
+			weights.join("b.bin.partial"),
+			[CONTENTS, b"and then some"].concat(),
+		)
+		.unwrap();
+
+		let dir = fetch(
+			Weights::new()
+				.file("a.bin", url.clone(), &sha256)
+				.file("b.bin", url, &sha256),
+			&ctx,
+		)
+		.await
+		.unwrap();
+
+		for file in ["a.bin", "b.bin"] {
+			assert_eq!(std::fs::read(dir.join(file)).unwrap(), CONTENTS);
+			assert!(!dir.join(format!("{file}.partial")).exists());
+		}
+
+		// The finished download is only asked for once, while the oversized one is downloaded again.
+		let ranges = ranges.lock().unwrap().clone();
+		assert_eq!(ranges.len(), 3);
+		assert_eq!(ranges.iter().filter(|range| range.is_none()).count(), 1);
+	}
+
+	#[tokio::test]
+	async fn weights_follow_the_egress_policy_and_stay_in_their_directory() {
+		let (url, ranges) = serve();
+		let sha256 = hex::encode(Sha256::digest(CONTENTS));
+		let ctx = SetupContext::new(std::env::temp_dir().join(Uuid::new_v4().to_string()));
+
+		let error = Weights::new()
+			.file("a.bin", url.clone(), &sha256)
+			.fetch(&ctx)
+			.await
+			.unwrap_err();
+		assert!(format!("{error:#}").contains("not allowed"));
+
+		for name in ["../a.bin", "nested/../../a.bin", "/tmp/a.bin", ""] {
+			let error = fetch(Weights::new().file(name, url.clone(), &sha256), &ctx)
+				.await
+				.unwrap_err();
+			assert!(format!("{error:#}").contains("Invalid weights file name"));
+		}
+		assert!(ranges.lock().unwrap().is_empty());
+	}
+}